@@ -3,15 +3,22 @@ use clap::{Parser, Subcommand};
 use itertools::Itertools;
 use jwalk::WalkDir;
 use renkit::{
+    jdk::configure_java_home,
+    lock::{LockCheck, Lockfile, check_lock, find_lock_file},
     renconstruct::{
         config::{BuildOption, Config, CustomOptionValue, KnownBuildOption, TaskOptions},
+        image_cache::ImageCache,
+        plan::{
+            BuildStep, TaskGraph, build_commands, build_groups, check_builds, check_config,
+            enabled_builds, resize_conflicts, resize_problems, unknown_dependencies,
+        },
+        staging::{InstanceOverlay, OVERLAY_DIR, STAGING_DIR, Staging},
         tasks::{
-            Task, TaskContext, task_convert_images_pre, task_keystore_post, task_keystore_pre,
-            task_lint_pre, task_notarize_post,
+            Stage, Task, TaskContext, task_convert_images_pre, task_keystore_post,
+            task_keystore_pre, task_lint_pre, task_notarize_post, task_resize_images_pre,
         },
     },
-    renutil::{get_registry, install, launch},
-    version::Version,
+    renutil::{Instance, LaunchError, Local, Sandbox, get_registry, install, launch},
 };
 use rustpython::vm::{
     builtins::{PyList, PyStr},
@@ -20,14 +27,16 @@ use rustpython::vm::{
 };
 use rustpython_vm::{
     Interpreter, PyObjectRef, PyRef, Settings, VirtualMachine,
-    builtins::{PyDict, PyNone},
+    builtins::{PyBaseExceptionRef, PyDict, PyNone},
     import,
 };
 use std::{
     collections::{HashMap, HashSet},
     fs,
+    num::NonZero,
+    panic::{self, AssertUnwindSafe},
     path::{Path, PathBuf},
-    str::FromStr,
+    sync::mpsc,
     thread,
 };
 
@@ -83,6 +92,27 @@ enum Commands {
         /// The config file to use. [default: renconstruct.toml]
         #[arg(short = 'c', long = "config")]
         config_path: Option<PathBuf>,
+        /// The profile of the config file to apply.
+        #[arg(short = 'p', long)]
+        profile: Option<String>,
+        /// The maximum number of sandboxed tasks to run at once. [default: number of CPUs]
+        #[arg(short = 'j', long)]
+        jobs: Option<usize>,
+        /// Converts all images again instead of reusing them from the image cache.
+        #[arg(long)]
+        no_image_cache: bool,
+    },
+    /// Validates the configuration and prints what a build would do, without building.
+    #[command(alias = "validate")]
+    Plan {
+        input_dir: PathBuf,
+        output_dir: PathBuf,
+        /// The config file to use. [default: renconstruct.toml]
+        #[arg(short = 'c', long = "config")]
+        config_path: Option<PathBuf>,
+        /// The profile of the config file to apply.
+        #[arg(short = 'p', long)]
+        profile: Option<String>,
     },
 }
 
@@ -122,7 +152,7 @@ fn get_on_builds(
         .unwrap()
         .filter(|path| {
             let path = path.as_ref().unwrap();
-            !path.path().starts_with(".")
+            !path.file_name().to_string_lossy().starts_with('.')
         })
         .map(|path| path.unwrap().path())
         .sorted_by(|a, b| {
@@ -165,13 +195,254 @@ fn get_on_builds(
     on_builds
 }
 
+/// Prints a Python exception and flushes it, so it is not lost if renconstruct exits right after.
+fn print_exception(vm: &VirtualMachine, e: PyBaseExceptionRef) {
+    vm.print_exception(e);
+    if let Ok(stderr) = vm.sys_module.get_attr("stderr", vm) {
+        let _ = vm.call_method(&stderr, "flush", ());
+    }
+}
+
+/// Imports every Python file in the task directory and returns the task classes they define,
+/// keyed by their snake case name without the `Task` suffix.
+fn load_task_classes(vm: &VirtualMachine, task_dir: &Path) -> Result<Vec<(String, PyObjectRef)>> {
+    vm.insert_sys_path(vm.new_pyobj(task_dir.to_str())).unwrap();
+
+    let mut paths = vec![];
+
+    for entry in WalkDir::new(task_dir) {
+        match entry {
+            Ok(entry) => {
+                let path = entry.path();
+                if path.is_dir() {
+                    continue;
+                }
+                if let Some(ext) = path.extension() {
+                    if ext != "py" {
+                        continue;
+                    }
+                    paths.push(PyStr::from(path.to_string_lossy()).to_pyobject(vm));
+                }
+            }
+            Err(err) => println!("Error: {err}"),
+        }
+    }
+
+    let paths = PyList::from(paths).to_pyobject(vm);
+
+    let rc_dispatch = import::import_source(vm, "rc_dispatch", RC_DISPATCH_SRC).map_err(|e| {
+        print_exception(vm, e);
+        anyhow!("Unable to load the task dispatcher")
+    })?;
+
+    let dispatch = rc_dispatch.get_attr("dispatch", vm).unwrap();
+
+    let result = dispatch
+        .call_with_args(FuncArgs::from(vec![paths]), vm)
+        .map_err(|e| {
+            print_exception(vm, e);
+            anyhow!(
+                "Unable to load custom tasks from {}",
+                task_dir.to_string_lossy()
+            )
+        })?;
+    let result = result.to_sequence().list(vm).unwrap();
+
+    let classes = result
+        .borrow_vec()
+        .iter()
+        .map(|val| {
+            let name_slug = val
+                .get_item("name_slug", vm)
+                .unwrap()
+                .str(vm)
+                .unwrap()
+                .to_string();
+            (name_slug, val.get_item("class", vm).unwrap())
+        })
+        .collect();
+
+    Ok(classes)
+}
+
+/// Finds the class of a custom task by its `name`. Tasks without one fall back
+/// to the name of their section, which is deprecated.
+fn find_task_class(
+    classes: &[(String, PyObjectRef)],
+    name: &str,
+    task_name: Option<&str>,
+) -> Option<PyObjectRef> {
+    if let Some(task_name) = task_name {
+        return classes
+            .iter()
+            .rfind(|(slug, _)| slug == task_name)
+            .map(|(_, class)| class.clone());
+    }
+
+    println!("The task '{name}' does not specify a task name. Please update it like below:");
+    println!("  [tasks.{name}]\n  type = \"custom\"\n  name = \"{name}\" <== New property");
+    println!("You may then rename the section title to an arbitrary string.");
+
+    classes
+        .iter()
+        .rfind(|(slug, _)| slug == name)
+        .map(|(_, class)| class.clone())
+}
+
+/// Copies the crash reports of a failed Ren'Py launch into the output directory,
+/// so they are preserved as build artifacts.
+fn preserve_crash_artifacts<T>(result: Result<T>, output_dir: &Path) -> Result<T> {
+    if let Err(e) = &result
+        && let Some(launch_error) = e.downcast_ref::<LaunchError>()
+        && !launch_error.artifacts.is_empty()
+    {
+        let crash_dir = output_dir.join("crash");
+        fs::create_dir_all(&crash_dir)?;
+        for artifact in &launch_error.artifacts {
+            if let Some(name) = artifact.file_name() {
+                fs::copy(artifact, crash_dir.join(name))?;
+            }
+        }
+        println!("Copied crash artifacts to {}", crash_dir.to_string_lossy());
+    }
+    result
+}
+
+/// Calls the handler of a custom task for the given stage with the paths of its builds.
+fn call_handler(
+    vm: &VirtualMachine,
+    task: &Task,
+    stage: Stage,
+    on_builds: &HashMap<String, Option<String>>,
+) -> Result<()> {
+    let Some(handler) = task.handler(stage) else {
+        return Ok(());
+    };
+
+    let py_dict = PyDict::new_ref(&vm.ctx);
+    for (k, v) in on_builds {
+        match v {
+            Some(value) => {
+                py_dict
+                    .set_item(k, PyStr::from(value.clone()).to_pyobject(vm), vm)
+                    .unwrap();
+            }
+            None => {
+                py_dict.set_item(k, PyNone.to_pyobject(vm), vm).unwrap();
+            }
+        }
+    }
+
+    if let Err(e) = handler.call((py_dict.to_pyobject(vm),), vm) {
+        print_exception(vm, e);
+        return Err(anyhow!("Task failed: {}", task.name));
+    }
+
+    Ok(())
+}
+
+/// Runs a built-in task that does not need to launch Ren'Py, so it can run on any thread.
+fn run_builtin_task(task: &Task, stage: Stage, ctx: &TaskContext) -> Result<()> {
+    match (&task.kind.options, stage) {
+        (TaskOptions::Keystore(opts), Stage::PreBuild) => task_keystore_pre(ctx, opts),
+        (TaskOptions::Keystore(opts), Stage::PostBuild) => task_keystore_post(ctx, opts),
+        (TaskOptions::ConvertImages(opts), Stage::PreBuild) => task_convert_images_pre(ctx, opts),
+        (TaskOptions::ResizeImages(opts), Stage::PreBuild) => task_resize_images_pre(ctx, opts),
+        (TaskOptions::Notarize(opts), Stage::PostBuild) => task_notarize_post(ctx, opts),
+        _ => Ok(()),
+    }
+}
+
+/// Runs the tasks of a stage as soon as the tasks they depend on completed. Sandboxed tasks
+/// run on their own threads, up to `jobs` at once, all other tasks run one at a time.
+async fn run_stage(
+    vm: &VirtualMachine,
+    tasks: &'static [Task],
+    stage: Stage,
+    ctx: &TaskContext,
+    active_builds: &[String],
+    jobs: usize,
+) -> Result<()> {
+    let mut graph = TaskGraph::new(tasks, stage)?;
+    if graph.is_empty() {
+        return Ok(());
+    }
+
+    println!("Executing {stage} tasks");
+    let prefix = match stage {
+        Stage::PreBuild => "[Pre]",
+        Stage::PostBuild => "[Post]",
+    };
+
+    let (tx, rx) = mpsc::channel::<(usize, Result<()>)>();
+    let mut failure = None;
+
+    while !graph.is_done() {
+        while failure.is_none()
+            && let Some((i, task)) = graph.next(jobs)
+        {
+            println!("{prefix} Running task: {}", task.name);
+            let ctx = TaskContext {
+                on_builds: get_on_builds(active_builds, &task.kind.on_builds, &ctx.output_dir),
+                ..ctx.clone()
+            };
+
+            if !task.runs_in_parallel() {
+                let result = match &task.kind.options {
+                    TaskOptions::Lint(opts) => {
+                        preserve_crash_artifacts(task_lint_pre(&ctx, opts).await, &ctx.output_dir)
+                    }
+                    TaskOptions::Custom(_) => call_handler(vm, task, stage, &ctx.on_builds),
+                    _ => run_builtin_task(task, stage, &ctx),
+                };
+                graph.finish(i);
+                failure = result.err();
+                continue;
+            }
+
+            let tx = tx.clone();
+            if let TaskOptions::Custom(_) = &task.kind.options {
+                vm.start_thread(move |vm| {
+                    let _ = tx.send((i, call_handler(vm, task, stage, &ctx.on_builds)));
+                });
+            } else {
+                thread::spawn(move || {
+                    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                        run_builtin_task(task, stage, &ctx)
+                    }))
+                    .unwrap_or_else(|_| Err(anyhow!("Task panicked: {}", task.name)));
+                    let _ = tx.send((i, result));
+                });
+            }
+        }
+
+        if graph.running() == 0 {
+            break;
+        }
+
+        let (i, result) = rx.recv()?;
+        graph.finish(i);
+        if let Err(e) = result
+            && failure.is_none()
+        {
+            failure = Some(e);
+        }
+    }
+
+    failure.map_or(Ok(()), Err)
+}
+
 #[tokio::main]
+#[allow(clippy::too_many_arguments)]
 async fn build(
     vm: &VirtualMachine,
     input_dir: &Path,
-    output_dir: &PathBuf,
+    output_dir: &Path,
     config_path: Option<PathBuf>,
     cli_registry: Option<PathBuf>,
+    profile: Option<&str>,
+    jobs: Option<usize>,
+    no_image_cache: bool,
 ) -> Result<()> {
     let config_path = config_path.unwrap_or("renconstruct.toml".into());
 
@@ -179,58 +450,106 @@ async fn build(
         return Err(anyhow!("Config file does not exist"));
     }
 
-    let config_raw = fs::read_to_string(config_path)?;
-    let config: Config = toml::from_str(config_raw.as_str())?;
+    if let Some(profile) = profile {
+        println!("Using profile {profile}");
+    }
+    let cells = Config::read_matrix(&config_path, input_dir, profile)?;
 
     if !input_dir.exists() {
         return Err(anyhow!("Input directory does not exist"));
     }
 
-    if config.builds.is_empty() {
-        return Err(anyhow!("No build options enabled"));
+    let jobs = jobs.unwrap_or_else(|| thread::available_parallelism().map_or(1, NonZero::get));
+    let read_config = |table| -> Result<Config> {
+        let mut config: Config = toml::Value::Table(table).try_into()?;
+        config.options.image_cache &= !no_image_cache;
+        Ok(config)
+    };
+
+    let mut results = vec![];
+    for (version, table) in cells {
+        let Some(version) = version else {
+            let config = read_config(table)?;
+            return build_version(vm, input_dir, output_dir, config, cli_registry, jobs, false)
+                .await;
+        };
+
+        println!("Building with Ren'Py {version}");
+        let output_dir = output_dir.join(&version);
+        let result = match read_config(table) {
+            Ok(config) => {
+                build_version(
+                    vm,
+                    input_dir,
+                    &output_dir,
+                    config,
+                    cli_registry.clone(),
+                    jobs,
+                    true,
+                )
+                .await
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = &result {
+            println!("Building with Ren'Py {version} failed: {e:#}");
+        }
+        results.push((version, result));
     }
 
-    if *config
-        .builds
-        .get(&BuildOption::Known(KnownBuildOption::Web))
-        .unwrap_or(&false)
-        && config.renutil.version < Version::from_str("8.2.0").unwrap()
-    {
-        return Err(anyhow!(
-            "Web build support requires Ren'Py 8.2.0 or higher."
-        ));
+    println!("Build matrix:");
+    for (version, result) in &results {
+        match result {
+            Ok(()) => println!("  {version}: passed"),
+            Err(e) => println!("  {version}: failed ({e})"),
+        }
     }
 
-    if *config
-        .builds
-        .get(&BuildOption::Known(KnownBuildOption::AndroidAab))
-        .unwrap_or(&false)
-        && config.renutil.version < Version::from_str("7.5.0").unwrap()
-    {
+    let failed = results.iter().filter(|(_, r)| r.is_err()).count();
+    if failed > 0 {
         return Err(anyhow!(
-            "Android App Bundle build support requires Ren'Py 7.5.0 or higher."
+            "{failed} of {} matrix builds failed",
+            results.len()
         ));
     }
 
-    if *config
-        .builds
-        .get(&BuildOption::Known(KnownBuildOption::AndroidApk))
-        .unwrap_or(&false)
-        || *config
-            .builds
-            .get(&BuildOption::Known(KnownBuildOption::AndroidAab))
-            .unwrap_or(&false)
-    {
-        let has_keystore_task = config.tasks.iter().any(|(_, v)| match v.options {
-            TaskOptions::Keystore { .. } => v.enabled,
-            _ => false,
-        });
+    Ok(())
+}
 
-        if !has_keystore_task {
-            return Err(anyhow!(
-                "Android build support requires a keystore task to be active."
-            ));
-        }
+/// Whether the build writes to RAPT, which Android builds and keystore tasks do.
+/// These builds use an overlay of the instance, so the instance itself is never written to.
+fn writes_rapt(config: &Config) -> bool {
+    config.is_enabled(KnownBuildOption::AndroidApk)
+        || config.is_enabled(KnownBuildOption::AndroidAab)
+        || config
+            .tasks
+            .values()
+            .any(|task| task.enabled && matches!(task.options, TaskOptions::Keystore(_)))
+}
+
+/// Builds the project with a single version of Ren'Py. Builds of a matrix only check
+/// the project's `renkit.lock` if it locks the version they build.
+async fn build_version(
+    vm: &VirtualMachine,
+    input_dir: &Path,
+    output_dir: &Path,
+    config: Config,
+    cli_registry: Option<PathBuf>,
+    jobs: usize,
+    matrix: bool,
+) -> Result<()> {
+    check_builds(&config)?;
+
+    if let Some(problem) = unknown_dependencies(&config).into_iter().next() {
+        return Err(anyhow!(problem));
+    }
+
+    if let Some(problem) = resize_conflicts(&config)
+        .into_iter()
+        .chain(resize_problems(&config, input_dir))
+        .next()
+    {
+        return Err(anyhow!(problem));
     }
 
     if config.options.clear_output_dir {
@@ -242,13 +561,74 @@ async fn build(
 
     fs::create_dir_all(output_dir)?;
 
+    // Builds with images resized by different tasks are built one group after another, each
+    // from its own copy of the project.
+    let groups = build_groups(&config);
+    if groups.len() > 1 {
+        for (resized_by, builds) in groups {
+            println!(
+                "Building {} ({})",
+                builds.join(", "),
+                if resized_by.is_empty() {
+                    "images not resized".to_string()
+                } else {
+                    format!("images resized by {}", resized_by.join(", "))
+                }
+            );
+
+            let mut config = config.clone();
+            config.options.clear_output_dir = false;
+            for (build, enabled) in &mut config.builds {
+                let name = match build {
+                    BuildOption::Known(build) => build.to_string(),
+                    BuildOption::Custom(build) => build.clone(),
+                };
+                *enabled &= builds.contains(&name);
+            }
+
+            Box::pin(build_version(
+                vm,
+                input_dir,
+                output_dir,
+                config,
+                cli_registry.clone(),
+                jobs,
+                matrix,
+            ))
+            .await?;
+        }
+
+        return Ok(());
+    }
+
+    let instance_options = config.renutil.instance_options();
+
     let registry = if cli_registry.is_some() {
         get_registry(cli_registry)
     } else {
-        get_registry(config.renutil.registry)
+        get_registry(config.renutil.registry.clone())
     };
 
-    if !config.renutil.version.is_installed(&registry) {
+    let is_installed = config
+        .renutil
+        .version
+        .is_installed(&registry, &instance_options);
+
+    let builds_android = [KnownBuildOption::AndroidApk, KnownBuildOption::AndroidAab]
+        .into_iter()
+        .any(|build| {
+            *config
+                .builds
+                .get(&BuildOption::Known(build))
+                .unwrap_or(&false)
+        });
+
+    // Building for Android requires a matching JDK, installing Ren'Py configures it by itself.
+    if is_installed && builds_android {
+        configure_java_home(&config.renutil.version, &config.renutil.jdk_paths)?;
+    }
+
+    if !is_installed {
         println!("Installing Ren'Py {}", config.renutil.version);
 
         install(
@@ -256,187 +636,129 @@ async fn build(
             &config.renutil.version,
             false,
             false,
-            config.renutil.update_pickle,
+            &instance_options,
+            &config.renutil.jdk_paths,
         )
         .await?;
     }
 
-    let mut active_builds = {
-        let mut active_builds = HashSet::<String>::new();
+    let locks_version = !matrix
+        || find_lock_file(input_dir)
+            .and_then(|path| Lockfile::read(&path).ok())
+            .is_none_or(|lockfile| {
+                lockfile.version
+                    == Instance::<Local>::new(
+                        config.renutil.version.clone(),
+                        instance_options.clone(),
+                    )
+                    .name()
+            });
+    if locks_version {
+        check_lock(
+            &registry,
+            input_dir,
+            &config.renutil.version,
+            &instance_options,
+            config.renutil.locked,
+        )?;
+    }
 
-        for (build, enabled) in &config.builds {
-            if !enabled {
-                continue;
-            }
-            match build {
-                BuildOption::Known(KnownBuildOption::Pc) => active_builds.insert("pc".into()),
-                BuildOption::Known(KnownBuildOption::Win) => active_builds.insert("win".into()),
-                BuildOption::Known(KnownBuildOption::Linux) => active_builds.insert("linux".into()),
-                BuildOption::Known(KnownBuildOption::Mac) => active_builds.insert("mac".into()),
-                BuildOption::Known(KnownBuildOption::Web) => active_builds.insert("web".into()),
-                BuildOption::Known(KnownBuildOption::Steam) => active_builds.insert("steam".into()),
-                BuildOption::Known(KnownBuildOption::Market) => {
-                    active_builds.insert("market".into())
-                }
-                BuildOption::Known(KnownBuildOption::AndroidApk) => {
-                    active_builds.insert("android_apk".into())
-                }
-                BuildOption::Known(KnownBuildOption::AndroidAab) => {
-                    active_builds.insert("android_aab".into())
-                }
-                BuildOption::Custom(s) => active_builds.insert(s.into()),
-            };
-        }
+    let renpy_path = config
+        .renutil
+        .version
+        .to_local(&registry, &instance_options)?
+        .path(&registry);
 
-        active_builds
+    // Tasks and Ren'Py write to the project, so they run on a copy of it unless asked not to.
+    let staging = if config.options.build_in_place {
+        None
+    } else {
+        println!("Staging project");
+        Some(Staging::create(
+            input_dir,
+            output_dir,
+            &config.options.staging_ignore,
+        )?)
+    };
+    let input_dir = staging
+        .as_ref()
+        .map_or(input_dir, |staging| staging.project.as_path());
+
+    let overlay = if writes_rapt(&config) {
+        println!("Creating instance overlay");
+        Some(InstanceOverlay::create(
+            &renpy_path,
+            &Instance::<Local>::new(config.renutil.version.clone(), instance_options.clone())
+                .name(),
+            output_dir,
+        )?)
+    } else {
+        None
     };
+    let (registry, renpy_path) = match &overlay {
+        Some(overlay) => (overlay.registry.clone(), overlay.path.clone()),
+        None => (registry, renpy_path),
+    };
+
+    let all_active_builds = enabled_builds(&config);
+    let active_builds = all_active_builds.iter().cloned().collect::<HashSet<_>>();
 
-    let mut tasks = config.tasks;
+    let mut tasks = config.tasks.clone();
 
-    if let Some(task_dir) = config.options.task_dir {
+    if let Some(task_dir) = &config.options.task_dir {
         if !task_dir.exists() {
             return Err(anyhow!("Task directory does not exist"));
         }
 
         println!("Loading custom tasks from {}", task_dir.to_string_lossy());
 
-        vm.insert_sys_path(vm.new_pyobj(task_dir.to_str())).unwrap();
-
-        let mut paths = vec![];
-
-        for entry in WalkDir::new(&task_dir) {
-            match entry {
-                Ok(entry) => {
-                    let path = entry.path();
-                    if path.is_dir() {
-                        continue;
-                    }
-                    if let Some(ext) = path.extension() {
-                        if ext != "py" {
-                            continue;
-                        }
-                        paths.push(PyStr::from(path.to_string_lossy()).to_pyobject(vm));
-                    }
-                }
-                Err(err) => println!("Error: {err}"),
-            }
-        }
-
-        let paths = PyList::from(paths).to_pyobject(vm);
-
-        let rc_dispatch = match import::import_source(vm, "rc_dispatch", RC_DISPATCH_SRC) {
-            Ok(res) => res,
-            Err(e) => {
-                vm.print_exception(e);
-                panic!();
-            }
-        };
-
-        let dispatch = rc_dispatch.get_attr("dispatch", vm).unwrap();
-
-        let result = dispatch
-            .call_with_args(FuncArgs::from(vec![paths]), vm)
-            .unwrap();
-        let result = result.to_sequence().list(vm).unwrap();
+        let classes = load_task_classes(vm, task_dir)?;
 
         for (name, opts) in tasks
             .iter_mut()
             .filter(|(_, opts)| matches!(opts.options, TaskOptions::Custom(_)))
         {
-            let mut class = None;
-            for val in result.borrow_vec().iter() {
-                let name_slug = val
-                    .get_item("name_slug", vm)
-                    .unwrap()
-                    .str(vm)
-                    .unwrap()
-                    .to_string();
-
-                if opts.name != Some(name_slug) {
-                    continue;
-                }
+            let Some(class) = find_task_class(&classes, name, opts.name.as_deref()) else {
+                return Err(anyhow!("Custom task not found: {name}"));
+            };
 
-                class = Some(val.get_item("class", vm).unwrap());
-            }
+            println!("Loading custom task: {name}");
 
-            if class.is_none() && opts.name.is_none() {
-                println!(
-                    "The task '{name}' does not specify a task name. Please update it like below:"
-                );
-                println!(
-                    "  [tasks.{name}]\n  type = \"custom\"\n  name = \"{name}\" <== New property"
-                );
-                println!("You may then rename the section title to an arbitrary string.");
-                for val in result.borrow_vec().iter() {
-                    let name_slug = val
-                        .get_item("name_slug", vm)
-                        .unwrap()
-                        .str(vm)
-                        .unwrap()
-                        .to_string();
-
-                    if *name != name_slug {
-                        continue;
-                    }
+            let TaskOptions::Custom(custom) = &mut opts.options else {
+                unreachable!();
+            };
 
-                    class = Some(val.get_item("class", vm).unwrap());
-                }
+            let py_dict = PyDict::new_ref(&vm.ctx);
+            for (k, v) in &custom.options {
+                py_dict.set_item(k, to_pyobject(v, vm), vm).unwrap();
             }
 
-            if let Some(class) = class {
-                println!("Loading custom task: {name}");
-
-                let options = match &opts.options {
-                    TaskOptions::Custom(opts) => {
-                        let py_dict = PyDict::new_ref(&vm.ctx);
-                        for (k, v) in &opts.options {
-                            py_dict.set_item(k, to_pyobject(v, vm), vm).unwrap();
-                        }
-                        py_dict.to_pyobject(vm)
-                    }
-                    _ => panic!("Task type mismatch."),
-                };
-
-                let class_new = class.get_attr("__new__", vm).unwrap();
-                let instance = class_new.call((class,), vm).unwrap();
-                let instance_init = instance.get_attr("__init__", vm).unwrap();
-                let input_dir_py = PyStr::from(input_dir.to_string_lossy()).to_pyobject(vm);
-                let output_dir_py = PyStr::from(output_dir.to_string_lossy()).to_pyobject(vm);
-                let renpy_path_py = PyStr::from(
-                    registry
-                        .join(config.renutil.version.to_string())
-                        .to_string_lossy(),
-                );
-                let registry_py = PyStr::from(registry.to_string_lossy()).to_pyobject(vm);
-                if let Err(e) = instance_init.call(
-                    (
-                        options,
-                        input_dir_py,
-                        output_dir_py,
-                        renpy_path_py,
-                        registry_py,
-                    ),
-                    vm,
-                ) {
-                    vm.print_exception(e);
-                    panic!();
-                }
+            let class_new = class.get_attr("__new__", vm).unwrap();
+            let instance = class_new.call((class,), vm).unwrap();
+            let instance_init = instance.get_attr("__init__", vm).unwrap();
+            let input_dir_py = PyStr::from(input_dir.to_string_lossy()).to_pyobject(vm);
+            let output_dir_py = PyStr::from(output_dir.to_string_lossy()).to_pyobject(vm);
+            let renpy_path_py = PyStr::from(renpy_path.to_string_lossy());
+            let registry_py = PyStr::from(registry.to_string_lossy()).to_pyobject(vm);
+            if let Err(e) = instance_init.call(
+                (
+                    py_dict.to_pyobject(vm),
+                    input_dir_py,
+                    output_dir_py,
+                    renpy_path_py,
+                    registry_py,
+                ),
+                vm,
+            ) {
+                print_exception(vm, e);
+                return Err(anyhow!("Unable to initialize custom task: {name}"));
+            }
 
-                match &mut opts.options {
-                    TaskOptions::Custom(opts) => {
-                        if let Ok(true) = instance.has_attr("pre_build", vm) {
-                            opts.task_handle_pre =
-                                Some(instance.get_attr("pre_build", vm).unwrap());
-                        }
-                        if let Ok(true) = instance.has_attr("post_build", vm) {
-                            opts.task_handle_post =
-                                Some(instance.get_attr("post_build", vm).unwrap());
-                        }
-                    }
-                    _ => panic!("Task type mismatch."),
-                }
-            } else {
-                panic!("Custom task not found: {name}");
+            if let Ok(true) = instance.has_attr("pre_build", vm) {
+                custom.task_handle_pre = Some(instance.get_attr("pre_build", vm).unwrap());
+            }
+            if let Ok(true) = instance.has_attr("post_build", vm) {
+                custom.task_handle_post = Some(instance.get_attr("post_build", vm).unwrap());
             }
         }
     }
@@ -451,501 +773,330 @@ async fn build(
         })
         .collect::<Vec<_>>();
 
-    let all_active_builds: Vec<String> = config
-        .builds
-        .iter()
-        .filter(|(_, v)| **v)
-        .map(|(k, _)| match k {
-            BuildOption::Known(k) => k.to_string(),
-            BuildOption::Custom(c) => c.to_owned(),
-        })
-        .collect();
-
     let active_tasks = active_tasks.leak();
 
-    let grouped_pre_build_tasks = active_tasks
-        .iter()
-        .filter(|t| match t.kind.options {
-            TaskOptions::Custom(ref opts) => opts.task_handle_pre.is_some(),
-            _ => true,
-        })
-        .sorted_by(|a, b| {
-            a.kind
-                .priorities
-                .pre_build
-                .cmp(&b.kind.priorities.pre_build)
-        })
-        .chunk_by(|task| task.kind.priorities.pre_build);
-
-    for (priority, tasks) in &grouped_pre_build_tasks {
-        println!("Executing pre-build tasks at priority level {priority}");
-        let c = tasks.chunk_by(|task| task.kind.sandboxed);
-        for (sandboxed, tasks) in &c {
-            let tasks = tasks.collect::<Vec<_>>();
-            if sandboxed && tasks.len() > 1 {
-                println!(
-                    "Multiple sandboxed tasks at the same priority level detected, running in parallel."
-                );
-                let mut handles = vec![];
-                for task in tasks {
-                    let registry = registry.clone();
-
-                    let on_builds =
-                        get_on_builds(&all_active_builds, &task.kind.on_builds, output_dir);
-
-                    match &task.kind.options {
-                        TaskOptions::Notarize(_) => {}
-                        TaskOptions::Lint(_) => {
-                            panic!("Lint tasks can not be sandboxed.");
-                        }
-                        TaskOptions::Keystore(_) => {
-                            panic!("Keystore tasks can not be sandboxed.");
-                        }
-                        TaskOptions::ConvertImages(opts) => {
-                            println!("[Pre] Running task: {}", task.name);
-                            let ctx = TaskContext {
-                                version: config.renutil.version.clone(),
-                                input_dir: input_dir.to_path_buf(),
-                                output_dir: output_dir.clone(),
-                                renpy_path: registry.join(config.renutil.version.to_string()),
-                                registry,
-                                on_builds,
-                            };
-                            handles.push(thread::spawn(move || {
-                                task_convert_images_pre(&ctx, opts).unwrap();
-                            }));
-                        }
-                        TaskOptions::Custom(opts) => {
-                            println!("[Pre] Running task: {}", task.name);
-                            if let Some(handler) = &opts.task_handle_pre {
-                                let py_dict = PyDict::new_ref(&vm.ctx);
-                                for (k, v) in on_builds {
-                                    match v {
-                                        Some(value) => {
-                                            py_dict
-                                                .set_item(
-                                                    &k,
-                                                    PyStr::from(value).to_pyobject(vm),
-                                                    vm,
-                                                )
-                                                .unwrap();
-                                        }
-                                        None => {
-                                            py_dict
-                                                .set_item(&k, PyNone.to_pyobject(vm), vm)
-                                                .unwrap();
-                                        }
-                                    }
-                                }
-                                handles.push(vm.start_thread(|vm| {
-                                    handler.call((py_dict.to_pyobject(vm),), vm).unwrap();
-                                }));
-                            }
-                        }
-                    }
-                }
+    let ctx = TaskContext {
+        version: config.renutil.version.clone(),
+        instance_options: instance_options.clone(),
+        arch: config.renutil.arch.clone(),
+        input_dir: input_dir.to_path_buf(),
+        output_dir: output_dir.to_path_buf(),
+        renpy_path: renpy_path.clone(),
+        registry: registry.clone(),
+        jdk_paths: config.renutil.jdk_paths.clone(),
+        on_builds: HashMap::new(),
+        image_cache: config
+            .options
+            .image_cache
+            .then(|| ImageCache::open(config.options.image_cache_max_size_mb * 1024 * 1024)),
+    };
 
-                println!("Joining handles");
-                for handle in handles {
-                    handle.join().unwrap();
-                }
-            } else {
-                for task in tasks {
-                    let registry = registry.clone();
-
-                    let on_builds =
-                        get_on_builds(&all_active_builds, &task.kind.on_builds, output_dir);
-
-                    match &task.kind.options {
-                        TaskOptions::Notarize(_) => {}
-                        TaskOptions::Lint(opts) => {
-                            println!("[Pre] Running task: {}", task.name);
-                            let ctx = TaskContext {
-                                version: config.renutil.version.clone(),
-                                input_dir: input_dir.to_path_buf(),
-                                output_dir: output_dir.clone(),
-                                renpy_path: registry.join(config.renutil.version.to_string()),
-                                registry,
-                                on_builds,
-                            };
-                            task_lint_pre(&ctx, opts).await?;
-                        }
-                        TaskOptions::Keystore(opts) => {
-                            println!("[Pre] Running task: {}", task.name);
-                            let ctx = TaskContext {
-                                version: config.renutil.version.clone(),
-                                input_dir: input_dir.to_path_buf(),
-                                output_dir: output_dir.clone(),
-                                renpy_path: registry.join(config.renutil.version.to_string()),
-                                registry,
-                                on_builds,
-                            };
-                            task_keystore_pre(&ctx, opts)?;
-                        }
-                        TaskOptions::ConvertImages(opts) => {
-                            println!("[Pre] Running task: {}", task.name);
-                            let ctx = TaskContext {
-                                version: config.renutil.version.clone(),
-                                input_dir: input_dir.to_path_buf(),
-                                output_dir: output_dir.clone(),
-                                renpy_path: registry.join(config.renutil.version.to_string()),
-                                registry,
-                                on_builds,
-                            };
-                            task_convert_images_pre(&ctx, opts)?;
-                        }
-                        TaskOptions::Custom(opts) => {
-                            println!("[Pre] Running task: {}", task.name);
-                            if let Some(handler) = &opts.task_handle_pre {
-                                let py_dict = PyDict::new_ref(&vm.ctx);
-                                for (k, v) in on_builds {
-                                    match v {
-                                        Some(value) => {
-                                            py_dict
-                                                .set_item(
-                                                    &k,
-                                                    PyStr::from(value).to_pyobject(vm),
-                                                    vm,
-                                                )
-                                                .unwrap();
-                                        }
-                                        None => {
-                                            py_dict
-                                                .set_item(&k, PyNone.to_pyobject(vm), vm)
-                                                .unwrap();
-                                        }
-                                    }
-                                }
-                                handler.call((py_dict.to_pyobject(vm),), vm).unwrap();
-                            }
-                        }
-                    }
-                }
-            }
-        }
-    }
+    run_stage(
+        vm,
+        active_tasks,
+        Stage::PreBuild,
+        &ctx,
+        &all_active_builds,
+        jobs,
+    )
+    .await?;
 
-    if *config
-        .builds
-        .get(&BuildOption::Known(KnownBuildOption::AndroidApk))
-        .unwrap_or(&false)
-    {
-        println!("Building Android APK package.");
-        active_builds.remove("android_apk");
-        if config.renutil.version >= Version::from_str("7.5.0").unwrap() {
-            let args = vec![
-                "android_build".into(),
-                input_dir.to_string_lossy().to_string(),
-                "--dest".into(),
-                output_dir.to_string_lossy().to_string(),
-            ];
+    for (step, args) in build_commands(&config, input_dir, output_dir) {
+        println!("{}", step.description());
 
-            launch(
-                &registry,
-                Some(&config.renutil.version),
-                false,
-                false,
-                &args,
-                true,
-                false,
-            )
-            .await?;
-        } else {
-            let args = vec![
-                "android_build".into(),
-                input_dir.to_string_lossy().to_string(),
-                "assembleRelease".into(),
-                "--dest".into(),
-                output_dir.to_string_lossy().to_string(),
-            ];
+        // The web build clears the destination directory when it runs, which is undesirable
+        // As such, we contain it in a subfolder and move it out afterwards.
+        let web_dir = output_dir.join("web");
+        if step == BuildStep::Web {
+            fs::create_dir_all(&web_dir)?;
+        }
 
+        preserve_crash_artifacts(
             launch(
                 &registry,
                 Some(&config.renutil.version),
+                &instance_options,
+                config.renutil.arch.as_deref(),
+                Sandbox::Disabled,
+                None,
                 false,
                 false,
                 &args,
                 true,
                 false,
+                &config.renutil.jdk_paths,
+                LockCheck::Skip,
             )
-            .await?;
+            .await,
+            output_dir,
+        )?;
+
+        if step == BuildStep::Web {
+            fs::remove_dir_all(web_dir)?;
         }
     }
 
-    if *config
-        .builds
-        .get(&BuildOption::Known(KnownBuildOption::AndroidAab))
-        .unwrap_or(&false)
-    {
-        println!("Building Android App Bundle package.");
-        active_builds.remove("android_aab");
-        if config.renutil.version >= Version::from_str("7.5.0").unwrap() {
-            let args = vec![
-                "android_build".into(),
-                input_dir.to_string_lossy().to_string(),
-                "--bundle".into(),
-                "--dest".into(),
-                output_dir.to_string_lossy().to_string(),
-            ];
+    run_stage(
+        vm,
+        active_tasks,
+        Stage::PostBuild,
+        &ctx,
+        &all_active_builds,
+        jobs,
+    )
+    .await?;
 
-            launch(
-                &registry,
-                Some(&config.renutil.version),
-                false,
-                false,
-                &args,
-                true,
-                false,
-            )
-            .await?;
+    Ok(())
+}
+
+/// Describes the file a build writes to the output directory.
+fn describe_output(build: &str) -> String {
+    match build {
+        "android_apk" => "*.apk: Android APK package".into(),
+        "android_aab" => "*.aab: Android App Bundle package".into(),
+        "web" => "*-web.zip: Web package, staged in web/ while building".into(),
+        "linux" => "*-linux.tar.bz2: linux package".into(),
+        build => format!("*-{build}.zip: {build} package"),
+    }
+}
+
+/// Prints the tasks of a stage in the order they start in, tasks on the same line start together.
+fn print_stage(graph: &TaskGraph, stage: Stage) {
+    println!("Tasks ({stage}):");
+
+    let batches = graph.batches();
+    if batches.is_empty() {
+        println!("  none");
+    }
+
+    for (step, batch) in batches.iter().enumerate() {
+        let tasks = batch
+            .iter()
+            .map(|&i| {
+                let task = graph.task(i);
+                let mut details = vec![];
+                let dependencies = graph.dependencies(i);
+                if !dependencies.is_empty() {
+                    details.push(format!(
+                        "after {}",
+                        dependencies.iter().map(|task| &task.name).join(", ")
+                    ));
+                }
+                if !task.kind.on_builds.is_empty() {
+                    details.push(format!(
+                        "on {}",
+                        task.kind.on_builds.iter().sorted().join(", ")
+                    ));
+                }
+                if details.is_empty() {
+                    task.name.clone()
+                } else {
+                    format!("{} ({})", task.name, details.join("; "))
+                }
+            })
+            .collect::<Vec<_>>();
+        if tasks.len() > 1 {
+            println!("  {}. {} [in parallel]", step + 1, tasks.join(", "));
+        } else {
+            println!("  {}. {}", step + 1, tasks.join(", "));
         }
     }
+}
 
-    if *config
-        .builds
-        .get(&BuildOption::Known(KnownBuildOption::Web))
-        .unwrap_or(&false)
-    {
-        println!("Building Web package.");
-        active_builds.remove("web");
+/// Validates a config without building anything, then prints the tasks, Ren'Py commands
+/// and outputs of the build it describes.
+fn plan(
+    vm: &VirtualMachine,
+    input_dir: &Path,
+    output_dir: &Path,
+    config_path: Option<PathBuf>,
+    cli_registry: Option<PathBuf>,
+    profile: Option<&str>,
+) -> Result<()> {
+    let config_path = config_path.unwrap_or("renconstruct.toml".into());
 
-        // The web build clears the destination directory when it runs, which is undesirable
-        // As such, we contain it in a subfolder and move it out afterwards.
-        let web_dir = output_dir.join("web");
-        fs::create_dir_all(&web_dir)?;
+    if !config_path.exists() {
+        return Err(anyhow!("Config file does not exist"));
+    }
 
-        let args = vec![
-            "web_build".into(),
-            input_dir.to_string_lossy().to_string(),
-            "--dest".into(),
-            web_dir.to_string_lossy().to_string(),
-        ];
+    let mut problems = 0;
+    for (version, table) in Config::read_matrix(&config_path, input_dir, profile)? {
+        let output_dir = match &version {
+            Some(version) => {
+                println!("Build matrix cell for Ren'Py {version}:\n");
+                output_dir.join(version)
+            }
+            None => output_dir.to_path_buf(),
+        };
+        if let Err(count) = plan_version(vm, input_dir, &output_dir, table, cli_registry.clone()) {
+            problems += count;
+        }
+        println!();
+    }
 
-        launch(
-            &registry,
-            Some(&config.renutil.version),
-            false,
-            false,
-            &args,
-            true,
-            false,
-        )
-        .await?;
+    if problems > 0 {
+        return Err(anyhow!(
+            "Found {problems} problem(s) in {}",
+            config_path.to_string_lossy()
+        ));
+    }
 
-        fs::remove_dir_all(web_dir)?;
+    match profile {
+        Some(profile) => println!(
+            "{} is valid with profile {profile}.",
+            config_path.to_string_lossy()
+        ),
+        None => println!("{} is valid.", config_path.to_string_lossy()),
     }
 
-    if !active_builds.is_empty() {
-        println!("Building other packages.");
-        let mut args = vec![
-            "distribute".into(),
-            input_dir.to_string_lossy().to_string(),
-            "--destination".into(),
-            output_dir.to_string_lossy().to_string(),
-        ];
+    Ok(())
+}
 
-        for (build, enabled) in config.builds {
-            if !enabled {
-                continue;
-            }
-            match build {
-                BuildOption::Known(KnownBuildOption::Pc) => {
-                    args.push("--package".into());
-                    args.push("pc".into());
-                }
-                BuildOption::Known(KnownBuildOption::Win) => {
-                    args.push("--package".into());
-                    args.push("win".into());
-                }
-                BuildOption::Known(KnownBuildOption::Linux) => {
-                    args.push("--package".into());
-                    args.push("linux".into());
-                }
-                BuildOption::Known(KnownBuildOption::Mac) => {
-                    args.push("--package".into());
-                    args.push("mac".into());
-                }
-                BuildOption::Known(KnownBuildOption::Steam) => {
-                    args.push("--package".into());
-                    args.push("steam".into());
-                }
-                BuildOption::Known(KnownBuildOption::Market) => {
-                    args.push("--package".into());
-                    args.push("market".into());
-                }
-                BuildOption::Custom(s) => {
-                    args.push("--package".into());
-                    args.push(s);
+/// Validates the config of a single version of Ren'Py and prints its build.
+/// Returns the number of problems found otherwise.
+fn plan_version(
+    vm: &VirtualMachine,
+    input_dir: &Path,
+    output_dir: &Path,
+    table: toml::Table,
+    cli_registry: Option<PathBuf>,
+) -> Result<(), usize> {
+    let config: Config = match toml::Value::Table(table).try_into() {
+        Ok(config) => config,
+        Err(e) => {
+            println!("  - {}", e.to_string().trim_end().replace('\n', " "));
+            return Err(1);
+        }
+    };
+    let mut problems = check_config(&config, input_dir);
+
+    let mut tasks = config.tasks.clone();
+
+    if let Some(task_dir) = &config.options.task_dir
+        && task_dir.exists()
+    {
+        match load_task_classes(vm, task_dir) {
+            Ok(classes) => {
+                for (name, opts) in tasks.iter_mut().filter(|(_, opts)| opts.enabled) {
+                    let TaskOptions::Custom(custom) = &mut opts.options else {
+                        continue;
+                    };
+                    let Some(class) = find_task_class(&classes, name, opts.name.as_deref()) else {
+                        problems.push(format!("Custom task not found: {name}"));
+                        continue;
+                    };
+                    if let Ok(true) = class.has_attr("pre_build", vm) {
+                        custom.task_handle_pre = Some(class.get_attr("pre_build", vm).unwrap());
+                    }
+                    if let Ok(true) = class.has_attr("post_build", vm) {
+                        custom.task_handle_post = Some(class.get_attr("post_build", vm).unwrap());
+                    }
                 }
-                BuildOption::Known(
-                    KnownBuildOption::AndroidApk
-                    | KnownBuildOption::AndroidAab
-                    | KnownBuildOption::Web,
-                ) => {}
             }
+            Err(e) => problems.push(e.to_string()),
         }
-
-        launch(
-            &registry,
-            Some(&config.renutil.version),
-            false,
-            false,
-            &args,
-            true,
-            false,
-        )
-        .await?;
     }
 
-    let grouped_post_build_tasks = active_tasks
+    let builds = enabled_builds(&config);
+    let active_tasks = tasks
         .iter()
-        .filter(|t| match t.kind.options {
-            TaskOptions::Custom(ref opts) => opts.task_handle_post.is_some(),
-            _ => true,
-        })
-        .sorted_by(|a, b| {
-            a.kind
-                .priorities
-                .post_build
-                .cmp(&b.kind.priorities.post_build)
+        .filter(|(_, v)| v.enabled)
+        .filter(|(_, v)| v.on_builds.is_empty() || v.on_builds.iter().any(|b| builds.contains(b)))
+        .map(|(k, v)| Task {
+            name: k.clone(),
+            kind: v.clone(),
         })
-        .chunk_by(|task| task.kind.priorities.post_build);
-
-    for (priority, tasks) in &grouped_post_build_tasks {
-        println!("Executing post-build tasks at priority level {priority}");
-        let c = tasks.chunk_by(|task| task.kind.sandboxed);
-        for (sandboxed, tasks) in &c {
-            let tasks = tasks.collect::<Vec<_>>();
-            if sandboxed && tasks.len() > 1 {
-                println!(
-                    "Multiple sandboxed tasks at the same priority level detected, running in parallel."
-                );
-                let mut handles = vec![];
-                for task in tasks {
-                    let registry = registry.clone();
-
-                    let on_builds =
-                        get_on_builds(&all_active_builds, &task.kind.on_builds, output_dir);
-
-                    match &task.kind.options {
-                        TaskOptions::ConvertImages(_) => {}
-                        TaskOptions::Lint(_) => {
-                            panic!("Lint tasks can not be sandboxed.");
-                        }
-                        TaskOptions::Keystore(_) => {
-                            panic!("Keystore tasks can not be sandboxed.");
-                        }
-                        TaskOptions::Notarize(opts) => {
-                            println!("[Post] Running task: {}", task.name);
-                            let ctx = TaskContext {
-                                version: config.renutil.version.clone(),
-                                input_dir: input_dir.to_path_buf(),
-                                output_dir: output_dir.clone(),
-                                renpy_path: registry.join(config.renutil.version.to_string()),
-                                registry,
-                                on_builds,
-                            };
-                            handles.push(thread::spawn(move || {
-                                task_notarize_post(&ctx, opts).unwrap();
-                            }));
-                        }
-                        TaskOptions::Custom(opts) => {
-                            println!("[Post] Running task: {}", task.name);
-                            if let Some(handler) = &opts.task_handle_post {
-                                let py_dict = PyDict::new_ref(&vm.ctx);
-                                for (k, v) in on_builds {
-                                    match v {
-                                        Some(value) => {
-                                            py_dict
-                                                .set_item(
-                                                    &k,
-                                                    PyStr::from(value).to_pyobject(vm),
-                                                    vm,
-                                                )
-                                                .unwrap();
-                                        }
-                                        None => {
-                                            py_dict
-                                                .set_item(&k, PyNone.to_pyobject(vm), vm)
-                                                .unwrap();
-                                        }
-                                    }
-                                }
-                                handles.push(vm.start_thread(|vm| {
-                                    handler.call((py_dict.to_pyobject(vm),), vm).unwrap();
-                                }));
-                            }
-                        }
-                    }
-                }
+        .collect::<Vec<_>>();
 
-                println!("Joining handles");
-                for handle in handles {
-                    handle.join().unwrap();
-                }
+    let pre_build = TaskGraph::new(&active_tasks, Stage::PreBuild);
+    let post_build = TaskGraph::new(&active_tasks, Stage::PostBuild);
+    for graph in [&pre_build, &post_build] {
+        if let Err(e) = graph {
+            problems.push(e.to_string());
+        }
+    }
+
+    if !problems.is_empty() {
+        for problem in &problems {
+            println!("  - {problem}");
+        }
+        return Err(problems.len());
+    }
+    let (Ok(pre_build), Ok(post_build)) = (pre_build, post_build) else {
+        unreachable!("Invalid task graphs are reported as problems");
+    };
+
+    let instance_options = config.renutil.instance_options();
+    let registry = get_registry(cli_registry.or(config.renutil.registry.clone()));
+    let is_installed = config
+        .renutil
+        .version
+        .is_installed(&registry, &instance_options);
+    println!(
+        "Ren'Py {} ({})",
+        Instance::<Local>::new(config.renutil.version.clone(), instance_options).name(),
+        if is_installed {
+            "installed"
+        } else {
+            "will be installed"
+        }
+    );
+
+    if config.options.build_in_place {
+        println!("Project: built in place");
+    } else {
+        println!(
+            "Project: copied to {} while building",
+            output_dir.join(STAGING_DIR).to_string_lossy()
+        );
+    }
+    if writes_rapt(&config) {
+        println!(
+            "RAPT: copied to {} while building",
+            output_dir.join(OVERLAY_DIR).to_string_lossy()
+        );
+    }
+    println!("Builds: {}", builds.join(", "));
+    let groups = build_groups(&config);
+    if groups.len() > 1 {
+        println!("Built one after another, each from its own copy of the project:");
+        for (resized_by, builds) in &groups {
+            if resized_by.is_empty() {
+                println!("  {}", builds.join(", "));
             } else {
-                for task in tasks {
-                    let registry = registry.clone();
-
-                    let on_builds =
-                        get_on_builds(&all_active_builds, &task.kind.on_builds, output_dir);
-
-                    match &task.kind.options {
-                        TaskOptions::Lint(_) | TaskOptions::ConvertImages(_) => {}
-                        TaskOptions::Keystore(opts) => {
-                            println!("[Post] Running task: {}", task.name);
-                            let ctx = TaskContext {
-                                version: config.renutil.version.clone(),
-                                input_dir: input_dir.to_path_buf(),
-                                output_dir: output_dir.clone(),
-                                renpy_path: registry.join(config.renutil.version.to_string()),
-                                registry,
-                                on_builds,
-                            };
-                            task_keystore_post(&ctx, opts)?;
-                        }
-                        TaskOptions::Notarize(opts) => {
-                            println!("[Post] Running task: {}", task.name);
-                            let ctx = TaskContext {
-                                version: config.renutil.version.clone(),
-                                input_dir: input_dir.to_path_buf(),
-                                output_dir: output_dir.clone(),
-                                renpy_path: registry.join(config.renutil.version.to_string()),
-                                registry,
-                                on_builds,
-                            };
-                            task_notarize_post(&ctx, opts)?;
-                        }
-                        TaskOptions::Custom(opts) => {
-                            println!("[Post] Running task: {}", task.name);
-                            if let Some(handler) = &opts.task_handle_post {
-                                let py_dict = PyDict::new_ref(&vm.ctx);
-                                for (k, v) in on_builds {
-                                    match v {
-                                        Some(value) => {
-                                            py_dict
-                                                .set_item(
-                                                    &k,
-                                                    PyStr::from(value).to_pyobject(vm),
-                                                    vm,
-                                                )
-                                                .unwrap();
-                                        }
-                                        None => {
-                                            py_dict
-                                                .set_item(&k, PyNone.to_pyobject(vm), vm)
-                                                .unwrap();
-                                        }
-                                    }
-                                }
-                                handler.call((py_dict,), vm).unwrap();
-                            }
-                        }
-                    }
-                }
+                println!(
+                    "  {} (images resized by {})",
+                    builds.join(", "),
+                    resized_by.join(", ")
+                );
             }
         }
     }
+    println!();
+
+    print_stage(&pre_build, Stage::PreBuild);
+
+    println!("\nRen'Py commands:");
+    for (_, args) in build_commands(&config, input_dir, output_dir) {
+        println!("  renpy {}", args.join(" "));
+    }
+    println!();
+
+    print_stage(&post_build, Stage::PostBuild);
+
+    println!(
+        "\nOutput in {}{}:",
+        output_dir.to_string_lossy(),
+        if config.options.clear_output_dir {
+            " (cleared before building)"
+        } else {
+            ""
+        }
+    );
+    for build in &builds {
+        println!("  {}", describe_output(build));
+    }
+    println!("  crash/: crash reports of failed Ren'Py launches");
 
     Ok(())
 }
@@ -962,6 +1113,31 @@ fn main() -> Result<()> {
             input_dir,
             output_dir,
             config_path,
-        } => build(vm, input_dir, output_dir, config_path.clone(), cli.registry),
+            profile,
+            jobs,
+            no_image_cache,
+        } => build(
+            vm,
+            input_dir,
+            output_dir,
+            config_path.clone(),
+            cli.registry,
+            profile.as_deref(),
+            *jobs,
+            *no_image_cache,
+        ),
+        Commands::Plan {
+            input_dir,
+            output_dir,
+            config_path,
+            profile,
+        } => plan(
+            vm,
+            input_dir,
+            output_dir,
+            config_path.clone(),
+            cli.registry,
+            profile.as_deref(),
+        ),
     })
 }
@@ -0,0 +1,65 @@
+use anyhow::Result;
+use renkit::lock::LockCheck;
+use renkit::renutil::{
+    InstanceOptions, Sandbox, find_version_file, get_registry, launch, read_version_file,
+};
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+
+/// A shim that launches the Ren'Py version pinned by the closest `.renpy-version` file,
+/// searching upwards from the current directory. All arguments are passed to Ren'Py as-is,
+/// preceded by the project directory unless the first argument already is a directory.
+///
+/// The registry can be changed via the `RENUTIL_REGISTRY` environment variable.
+#[tokio::main]
+async fn main() -> Result<()> {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+
+    let registry = get_registry(env::var_os("RENUTIL_REGISTRY").map(PathBuf::from));
+
+    let current_dir = env::current_dir()?;
+    let version_file = find_version_file(&current_dir);
+    let pinned = match &version_file {
+        Some(version_file) => Some(read_version_file(version_file)?),
+        None => None,
+    };
+
+    // Ren'Py expects the project directory as its first argument, i.e. `renpy lint` runs `renpy <project> lint`.
+    if !args.first().is_some_and(|arg| Path::new(arg).is_dir()) {
+        let project = version_file
+            .as_deref()
+            .and_then(Path::parent)
+            .unwrap_or(&current_dir);
+        args.insert(0, project.to_string_lossy().to_string());
+    }
+
+    let (version, options) = match &pinned {
+        Some((version, options)) => (Some(version), options.clone()),
+        None => (None, InstanceOptions::default()),
+    };
+
+    let (status, _stdout, _stderr) = launch(
+        &registry,
+        version,
+        &options,
+        None,
+        Sandbox::Disabled,
+        None,
+        false,
+        true,
+        &args,
+        false,
+        true,
+        &[],
+        LockCheck::Warn,
+    )
+    .await?;
+
+    if !status.success() {
+        std::process::exit(status.code().unwrap_or(1));
+    }
+
+    Ok(())
+}
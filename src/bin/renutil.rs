@@ -1,25 +1,39 @@
 use anyhow::{Result, anyhow};
 use clap::{Parser, Subcommand};
 use renkit::{
-    renutil::{cleanup, get_registry, install, launch, list, show, uninstall},
+    cache::clean_cache,
+    dedupe::dedupe,
+    diff::diff,
+    export::{export, import},
+    lock::{LockCheck, lock},
+    renutil::{
+        Instance, InstanceOptions, Local, Sandbox, ScreenVariant, cleanup, default, get_registry,
+        install, launch, link, list, parse_instance_name, pin, show, uninstall, unlink,
+        validate_warp,
+    },
+    serve::serve,
+    tui::tui,
     version::Version,
 };
-use std::{path::PathBuf, str::FromStr};
+use std::path::{Path, PathBuf};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
-    /// The path to the registry directory to use. [default: ~/.renutil]
+    /// The path to the registry directory to install into. [default: the first writable registry in RENUTIL_REGISTRY_PATH, or ~/.renutil]
     #[arg(short = 'r', long)]
     registry: Option<PathBuf>,
+    /// Additional directories to search for JDKs in, before RENUTIL_JDK_PATHS and the common install locations.
+    #[arg(long = "jdk-path", global = true)]
+    jdk_paths: Vec<PathBuf>,
     #[command(subcommand)]
     command: Commands,
 }
 
-fn parse_version(version: &str) -> Result<Version> {
-    match Version::from_str(version) {
-        Ok(version) => Ok(version),
-        Err(e) => Err(anyhow!("Invalid version: {} - {}", version, e)),
+fn parse_instance(instance: &str) -> Result<(Version, InstanceOptions)> {
+    match parse_instance_name(instance) {
+        Ok(instance) => Ok(instance),
+        Err(e) => Err(anyhow!("Invalid version: {} - {}", instance, e)),
     }
 }
 
@@ -36,13 +50,13 @@ enum Commands {
     },
     /// Show information about a specific version of Ren'Py.
     Show {
-        #[clap(value_parser = clap::builder::ValueParser::new(parse_version))]
-        version: Version,
+        #[clap(value_parser = clap::builder::ValueParser::new(parse_instance))]
+        version: (Version, InstanceOptions),
     },
     /// Launch the given version of Ren'Py.
     Launch {
-        #[clap(short, long, value_parser = clap::builder::ValueParser::new(parse_version))]
-        version: Option<Version>,
+        #[clap(short, long, value_parser = clap::builder::ValueParser::new(parse_instance))]
+        version: Option<(Version, InstanceOptions)>,
         args: Vec<String>,
         #[arg(long)]
         headless: bool,
@@ -52,28 +66,140 @@ enum Commands {
         check_status: bool,
         #[arg(long)]
         no_auto_install: bool,
+        /// The architecture to launch with, i.e. 'py3-linux-aarch64'. [default: host architecture]
+        #[arg(long)]
+        arch: Option<String>,
+        /// Launches with a temporary home and save directory, which is removed afterwards.
+        #[arg(long)]
+        sandbox: bool,
+        /// Same as '--sandbox', but keeps the sandbox directory for inspection.
+        #[arg(long)]
+        keep_sandbox: bool,
+        /// Emulates the screen variant of another device. Requires direct mode.
+        #[arg(long, value_enum)]
+        variant: Option<ScreenVariant>,
+        /// Warps to the given script location after startup, i.e. 'script.rpy:42'. Requires direct mode.
+        #[arg(long)]
+        warp: Option<String>,
+        /// Fails instead of warning if the instance does not match the project's renkit.lock. Requires direct mode.
+        #[arg(long)]
+        locked: bool,
     },
     /// Install the given version of Ren'Py.
     Install {
-        #[clap(value_parser = clap::builder::ValueParser::new(parse_version))]
-        version: Version,
+        #[clap(value_parser = clap::builder::ValueParser::new(parse_instance))]
+        version: (Version, InstanceOptions),
+        /// Keeps a copy of the downloaded archives in the registry, in addition to the archive cache.
         #[arg(short = 'n', long)]
         no_cleanup: bool,
         #[arg(short = 'f', long)]
         force: bool,
         #[arg(short = 'u', long)]
         update_pickle: bool,
+        /// Deduplicates files across all instances once the installation is done.
+        #[arg(long)]
+        dedupe: bool,
     },
     /// Cleans up temporary directories for the given version of Ren'Py.
     Clean {
-        #[clap(value_parser = clap::builder::ValueParser::new(parse_version))]
-        version: Version,
+        #[clap(value_parser = clap::builder::ValueParser::new(parse_instance))]
+        version: (Version, InstanceOptions),
+    },
+    /// Manages the cache of downloaded archives, which is shared by all registries.
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommands,
+    },
+    /// Sets the default version of Ren'Py, used when no version is given or pinned.
+    /// Shows the current default if no version is given.
+    #[command(alias = "use")]
+    Default {
+        #[clap(value_parser = clap::builder::ValueParser::new(parse_instance))]
+        version: Option<(Version, InstanceOptions)>,
+    },
+    /// Pins the version of Ren'Py a project should be launched with by writing its `.renpy-version` file.
+    Pin {
+        #[clap(value_parser = clap::builder::ValueParser::new(parse_instance))]
+        version: (Version, InstanceOptions),
+        /// The project directory to pin. [default: current directory]
+        #[arg(short = 'p', long)]
+        path: Option<PathBuf>,
+    },
+    /// Records the exact engine the pinned version resolves to in a `renkit.lock` next to `.renpy-version`.
+    Lock {
+        /// The project directory to lock. [default: current directory]
+        #[arg(short = 'p', long)]
+        path: Option<PathBuf>,
+    },
+    /// Registers an existing Ren'Py SDK directory in the registry under the given name.
+    Link {
+        path: PathBuf,
+        /// The name to register the SDK as, i.e. '8.3.4+studio'.
+        #[clap(long = "as", value_parser = clap::builder::ValueParser::new(parse_instance))]
+        name: (Version, InstanceOptions),
+    },
+    /// Removes a linked Ren'Py SDK from the registry without deleting the SDK itself.
+    Unlink {
+        #[clap(value_parser = clap::builder::ValueParser::new(parse_instance))]
+        version: (Version, InstanceOptions),
+    },
+    /// Compares the engine, launcher, RAPT and web runtime of two instances or SDK archives.
+    Diff {
+        /// An installed version, i.e. '8.3.4', or the path to an SDK archive.
+        a: String,
+        /// An installed version, i.e. '8.4.0', or the path to an SDK archive.
+        b: String,
+        /// Only compares paths starting with the given prefix, i.e. 'renpy/display'. Can be given multiple times.
+        #[arg(short = 'p', long = "path")]
+        paths: Vec<String>,
+        /// Only lists added, removed and changed files without showing their diffs.
+        #[arg(long)]
+        name_only: bool,
+    },
+    /// Replaces identical files across all installed instances with reflinks or hardlinks to save disk space.
+    Dedupe {
+        /// Only reports how much space would be saved.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Exports an installed instance into a portable archive.
+    Export {
+        #[clap(value_parser = clap::builder::ValueParser::new(parse_instance))]
+        version: (Version, InstanceOptions),
+        /// The archive to write. [default: <version>.tar.zst]
+        #[arg(short = 'o', long)]
+        output: Option<PathBuf>,
+    },
+    /// Imports an instance from an archive created via 'renutil export'.
+    Import {
+        archive: PathBuf,
+        /// Replaces the instance if it is already installed.
+        #[arg(short = 'f', long)]
+        force: bool,
     },
+    /// Serves the registry over a local HTTP/JSON interface for editor integrations.
+    Serve {
+        /// The port to listen on, only connections from localhost are accepted.
+        #[arg(short = 'p', long, default_value_t = 8780)]
+        port: u16,
+    },
+    /// Opens an interactive terminal interface to manage installed and available versions.
+    Tui,
     #[command(alias = "remove")]
     /// Uninstalls the given version of Ren'Py.
     Uninstall {
-        #[clap(value_parser = clap::builder::ValueParser::new(parse_version))]
-        version: Version,
+        #[clap(value_parser = clap::builder::ValueParser::new(parse_instance))]
+        version: (Version, InstanceOptions),
+    },
+}
+
+#[derive(Subcommand)]
+enum CacheCommands {
+    /// Removes cached archives, the least recently used first.
+    Clean {
+        /// Only removes archives until the cache is at most this large, in MiB. [default: remove all archives]
+        #[arg(long, default_value_t = 0, hide_default_value = true)]
+        max_size_mb: u64,
     },
 }
 
@@ -89,7 +215,9 @@ async fn main() -> Result<()> {
             num,
             nightly,
         } => list(&registry, *online, *num, *nightly).await?,
-        Commands::Show { version } => show(&registry, version).await?,
+        Commands::Show {
+            version: (version, options),
+        } => show(&registry, version, options, &cli.jdk_paths).await?,
         Commands::Launch {
             version,
             headless,
@@ -97,15 +225,66 @@ async fn main() -> Result<()> {
             args,
             check_status,
             no_auto_install,
+            arch,
+            sandbox,
+            keep_sandbox,
+            variant,
+            warp,
+            locked,
         } => {
+            let mut args = args.clone();
+
+            if variant.is_some() || warp.is_some() {
+                if !direct || args.is_empty() {
+                    anyhow::bail!(
+                        "'--variant' and '--warp' require direct mode with the project directory as the first argument."
+                    );
+                }
+
+                let project = PathBuf::from(&args[0]);
+
+                if variant.is_some() && !project.join("game").is_dir() {
+                    anyhow::bail!(
+                        "{} is not a Ren'Py project, the game directory is missing.",
+                        project.to_string_lossy()
+                    );
+                }
+
+                if let Some(warp) = warp {
+                    validate_warp(&project, warp)?;
+                    args.splice(1..1, ["--warp".to_string(), warp.clone()]);
+                }
+            }
+
+            let sandbox = if *keep_sandbox {
+                Sandbox::Keep
+            } else if *sandbox {
+                Sandbox::Enabled
+            } else {
+                Sandbox::Disabled
+            };
+            let (version, options) = match version {
+                Some((version, options)) => (Some(version), options.clone()),
+                None => (None, InstanceOptions::default()),
+            };
             let (status, _stdout, _stderr) = launch(
                 &registry,
-                version.as_ref(),
+                version,
+                &options,
+                arch.as_deref(),
+                sandbox,
+                *variant,
                 *headless,
                 *direct,
-                args,
+                &args,
                 *check_status,
                 !no_auto_install,
+                &cli.jdk_paths,
+                if *locked {
+                    LockCheck::Enforce
+                } else {
+                    LockCheck::Warn
+                },
             )
             .await?;
             if !status.success() {
@@ -114,13 +293,76 @@ async fn main() -> Result<()> {
             return Ok(());
         }
         Commands::Install {
-            version,
+            version: (version, options),
             no_cleanup,
             force,
             update_pickle,
-        } => install(&registry, version, *no_cleanup, *force, *update_pickle).await?,
-        Commands::Clean { version } => cleanup(&registry, version)?,
-        Commands::Uninstall { version } => uninstall(&registry, version)?,
+            dedupe: dedupe_after,
+        } => {
+            let options = InstanceOptions {
+                update_pickle: options.update_pickle || *update_pickle,
+            };
+            install(
+                &registry,
+                version,
+                *no_cleanup,
+                *force,
+                &options,
+                &cli.jdk_paths,
+            )
+            .await?;
+            if *dedupe_after {
+                dedupe(&registry, false)?;
+            }
+        }
+        Commands::Clean {
+            version: (version, options),
+        } => cleanup(&registry, version, options)?,
+        Commands::Cache {
+            command: CacheCommands::Clean { max_size_mb },
+        } => clean_cache(*max_size_mb)?,
+        Commands::Default { version } => match version {
+            Some((version, options)) => default(&registry, Some(version), options)?,
+            None => default(&registry, None, &InstanceOptions::default())?,
+        },
+        Commands::Pin {
+            version: (version, options),
+            path,
+        } => pin(path.as_deref().unwrap_or(Path::new(".")), version, options)?,
+        Commands::Lock { path } => lock(&registry, path.as_deref().unwrap_or(Path::new(".")))?,
+        Commands::Link {
+            path,
+            name: (version, options),
+        } => link(&registry, path, version, options)?,
+        Commands::Unlink {
+            version: (version, options),
+        } => unlink(&registry, version, options)?,
+        Commands::Uninstall {
+            version: (version, options),
+        } => uninstall(&registry, version, options)?,
+        Commands::Diff {
+            a,
+            b,
+            paths,
+            name_only,
+        } => diff(&registry, a, b, paths, *name_only)?,
+        Commands::Dedupe { dry_run } => dedupe(&registry, *dry_run)?,
+        Commands::Export {
+            version: (version, options),
+            output,
+        } => {
+            let output = match output {
+                Some(output) => output.clone(),
+                None => PathBuf::from(format!(
+                    "{}.tar.zst",
+                    Instance::<Local>::new(version.clone(), options.clone()).name()
+                )),
+            };
+            export(&registry, version, options, &output)?;
+        }
+        Commands::Import { archive, force } => import(&registry, archive, *force)?,
+        Commands::Serve { port } => serve(registry, cli.jdk_paths.clone(), *port).await?,
+        Commands::Tui => tui(registry, cli.jdk_paths.clone())?,
     }
 
     Ok(())
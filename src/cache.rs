@@ -0,0 +1,284 @@
+use anyhow::Result;
+use indicatif::HumanBytes;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    env, fs,
+    io::{self, BufReader, Read},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+/// Records which archive a URL resolved to when it was downloaded.
+/// URLs without a hash were not available when they were last requested.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    url: String,
+    sha256: Option<String>,
+}
+
+/// A content-addressed cache of downloaded archives, shared by all registries.
+/// Archives are stored by their SHA-256 hash under `blobs`, `urls` maps download URLs to hashes.
+#[derive(Debug, Clone)]
+pub struct ArchiveCache {
+    root: PathBuf,
+}
+
+#[must_use]
+pub fn sha256_hex(data: impl AsRef<[u8]>) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+pub fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut reader = BufReader::new(fs::File::open(path)?);
+    let mut hasher = Sha256::new();
+    let mut buffer = [0; 16 * 1024];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+impl ArchiveCache {
+    #[must_use]
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    /// Opens the cache at `RENUTIL_CACHE_DIR`, or at `~/.cache/renkit/archives` by default.
+    #[must_use]
+    pub fn open() -> Self {
+        let root = env::var_os("RENUTIL_CACHE_DIR")
+            .filter(|p| !p.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| home::home_dir().map(|home| home.join(".cache/renkit/archives")))
+            .expect("Unable to detect home directory.");
+        Self::new(root)
+    }
+
+    fn entry_path(&self, url: &Url) -> PathBuf {
+        self.root
+            .join("urls")
+            .join(format!("{}.json", sha256_hex(url.as_str())))
+    }
+
+    fn blob_path(&self, sha256: &str, file_name: &str) -> PathBuf {
+        self.root.join("blobs").join(sha256).join(file_name)
+    }
+
+    fn read_entry(&self, url: &Url) -> Option<CacheEntry> {
+        let entry = fs::read_to_string(self.entry_path(url)).ok()?;
+        serde_json::from_str::<CacheEntry>(&entry)
+            .ok()
+            .filter(|entry| entry.url == url.as_str())
+    }
+
+    /// Whether an archive was cached for the URL, without verifying it.
+    #[must_use]
+    pub fn contains(&self, url: &Url) -> bool {
+        self.read_entry(url)
+            .is_some_and(|entry| entry.sha256.is_some())
+    }
+
+    /// Whether the URL was not available when it was last requested.
+    #[must_use]
+    pub fn is_unavailable(&self, url: &Url) -> bool {
+        self.read_entry(url)
+            .is_some_and(|entry| entry.sha256.is_none())
+    }
+
+    /// Returns the cached archive for the URL and its hash. Archives that fail verification
+    /// are evicted, so they are downloaded again.
+    pub fn get(&self, url: &Url, file_name: &str) -> Result<Option<(PathBuf, String)>> {
+        let Some(sha256) = self.read_entry(url).and_then(|entry| entry.sha256) else {
+            return Ok(None);
+        };
+
+        let path = self.blob_path(&sha256, file_name);
+        if path.exists() && sha256_file(&path)? == sha256 {
+            // Marks the archive as recently used, so `clean` evicts it last.
+            let _ = fs::File::options()
+                .write(true)
+                .open(&path)
+                .and_then(|file| file.set_modified(SystemTime::now()));
+            return Ok(Some((path, sha256)));
+        }
+
+        if let Some(parent) = path.parent() {
+            let _ = fs::remove_dir_all(parent);
+        }
+        let _ = fs::remove_file(self.entry_path(url));
+
+        Ok(None)
+    }
+
+    /// A fresh path to download into before the archive is added via `insert`.
+    pub fn partial_path(&self) -> Result<PathBuf> {
+        let tmp = self.root.join("tmp");
+        fs::create_dir_all(&tmp)?;
+        Ok(tmp.join(format!("{}.part", rand::random::<u32>())))
+    }
+
+    /// Moves a downloaded archive with the given hash into the cache and returns its new path.
+    pub fn insert(
+        &self,
+        url: &Url,
+        file_name: &str,
+        partial: &Path,
+        sha256: &str,
+    ) -> Result<PathBuf> {
+        let path = self.blob_path(sha256, file_name);
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::rename(partial, &path)?;
+
+        self.write_entry(&CacheEntry {
+            url: url.to_string(),
+            sha256: Some(sha256.to_string()),
+        })?;
+
+        Ok(path)
+    }
+
+    /// Remembers that the URL is not available, so installing offline can skip it.
+    pub fn insert_unavailable(&self, url: &Url) -> Result<()> {
+        self.write_entry(&CacheEntry {
+            url: url.to_string(),
+            sha256: None,
+        })
+    }
+
+    /// Evicts the least recently used archives until the cache fits into `max_size` bytes,
+    /// along with downloads that were interrupted more than a day ago.
+    /// Returns the number of bytes freed.
+    pub fn clean(&self, max_size: u64) -> Result<u64> {
+        let mut freed = 0;
+
+        let blobs = self.root.join("blobs");
+        let mut archives = vec![];
+        if blobs.exists() {
+            for dir in fs::read_dir(&blobs)? {
+                let dir = dir?.path();
+                let (mut used, mut size) = (SystemTime::UNIX_EPOCH, 0);
+                for file in fs::read_dir(&dir)? {
+                    let metadata = file?.metadata()?;
+                    used = used.max(metadata.modified()?);
+                    size += metadata.len();
+                }
+                archives.push((used, size, dir));
+            }
+        }
+
+        let mut size = archives.iter().map(|(_, size, _)| size).sum::<u64>();
+        archives.sort();
+        for (_, len, dir) in archives {
+            if size <= max_size {
+                break;
+            }
+            fs::remove_dir_all(dir)?;
+            size -= len;
+            freed += len;
+        }
+
+        let urls = self.root.join("urls");
+        if urls.exists() {
+            for entry in fs::read_dir(&urls)? {
+                let path = entry?.path();
+                let evicted = fs::read_to_string(&path)
+                    .ok()
+                    .and_then(|entry| serde_json::from_str::<CacheEntry>(&entry).ok())
+                    .and_then(|entry| entry.sha256)
+                    .is_some_and(|sha256| !blobs.join(sha256).exists());
+                if evicted {
+                    fs::remove_file(path)?;
+                }
+            }
+        }
+
+        // Downloads of installations that are still running are left alone.
+        let tmp = self.root.join("tmp");
+        if tmp.exists() {
+            for entry in fs::read_dir(&tmp)? {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                if metadata.modified()?.elapsed().unwrap_or_default() > Duration::from_hours(24) {
+                    fs::remove_file(entry.path())?;
+                    freed += metadata.len();
+                }
+            }
+        }
+
+        Ok(freed)
+    }
+
+    fn write_entry(&self, entry: &CacheEntry) -> Result<()> {
+        let entry_path = self.entry_path(&entry.url.parse()?);
+        fs::create_dir_all(entry_path.parent().unwrap())?;
+        let temp = entry_path.with_extension(format!("{}.tmp", rand::random::<u32>()));
+        fs::write(&temp, serde_json::to_vec_pretty(entry)?)?;
+        fs::rename(&temp, &entry_path)?;
+        Ok(())
+    }
+}
+
+/// Removes cached archives, the least recently used first, until the cache fits into
+/// `max_size_mb` MiB.
+pub fn clean_cache(max_size_mb: u64) -> Result<()> {
+    let freed = ArchiveCache::open().clean(max_size_mb * 1024 * 1024)?;
+    println!("Freed {} in the archive cache.", HumanBytes(freed));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ArchiveCache, sha256_hex};
+    use std::{env, fs};
+
+    #[test]
+    fn cached_archives() {
+        let root = env::temp_dir().join(format!("renkit-cache-{}", rand::random::<u32>()));
+        let cache = ArchiveCache::new(root.clone());
+        let url = "https://www.renpy.org/dl/8.3.4/renpy-8.3.4-sdk.tar.bz2"
+            .parse()
+            .unwrap();
+        let file_name = "renpy-8.3.4-sdk.tar.bz2";
+
+        assert!(cache.get(&url, file_name).unwrap().is_none());
+
+        let partial = cache.partial_path().unwrap();
+        fs::write(&partial, "sdk").unwrap();
+        let path = cache
+            .insert(&url, file_name, &partial, &sha256_hex("sdk"))
+            .unwrap();
+        assert!(cache.contains(&url));
+        assert_eq!(
+            cache.get(&url, file_name).unwrap(),
+            Some((path.clone(), sha256_hex("sdk")))
+        );
+
+        fs::write(&path, "corrupted").unwrap();
+        assert!(cache.get(&url, file_name).unwrap().is_none());
+        assert!(!cache.contains(&url));
+
+        cache.insert_unavailable(&url).unwrap();
+        assert!(cache.is_unavailable(&url));
+        assert!(cache.get(&url, file_name).unwrap().is_none());
+
+        let partial = cache.partial_path().unwrap();
+        fs::write(&partial, "sdk").unwrap();
+        cache
+            .insert(&url, file_name, &partial, &sha256_hex("sdk"))
+            .unwrap();
+        assert_eq!(cache.clean(3).unwrap(), 0);
+        assert!(cache.contains(&url));
+        assert_eq!(cache.clean(0).unwrap(), 3);
+        assert!(!cache.contains(&url));
+        assert!(!cache.is_unavailable(&url));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
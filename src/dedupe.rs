@@ -0,0 +1,259 @@
+use crate::common::canonicalize_normalized;
+use crate::renutil::get_installed_instances;
+use anyhow::Result;
+use indicatif::HumanBytes;
+#[cfg(target_family = "unix")]
+use std::os::unix::fs::MetadataExt;
+use std::{
+    collections::HashMap,
+    fs,
+    hash::{DefaultHasher, Hasher},
+    io::{self, BufReader, Read},
+    path::{Path, PathBuf},
+};
+
+/// Paths within an instance that hold engine files which are only read while building.
+/// Everything else may be written to in place by Ren'Py or renconstruct tasks, so sharing it
+/// could leak changes into other instances.
+const SHARED_PATHS: [&str; 5] = [
+    "renpy",
+    "lib",
+    "launcher",
+    "rapt/Sdk/build-tools",
+    "rapt/Sdk/platforms",
+];
+
+/// Files that are regenerated in place, i.e. compiled scripts.
+const EXCLUDED_EXTENSIONS: [&str; 5] = ["pyc", "pyo", "rpyc", "rpymc", "rpyb"];
+
+fn is_shareable(relative: &Path) -> bool {
+    SHARED_PATHS
+        .iter()
+        .any(|shared| relative.starts_with(shared))
+        && !relative
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| EXCLUDED_EXTENSIONS.contains(&ext))
+}
+
+/// Files can only share storage if they are on the same device and have the same permissions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct FileKey {
+    size: u64,
+    device: u64,
+    mode: u32,
+}
+
+struct Candidate {
+    path: PathBuf,
+    key: FileKey,
+    /// Identifies files that are already hardlinked to each other.
+    inode: Option<u64>,
+}
+
+#[cfg(target_family = "unix")]
+fn candidate(path: PathBuf, metadata: &fs::Metadata) -> Candidate {
+    Candidate {
+        path,
+        key: FileKey {
+            size: metadata.len(),
+            device: metadata.dev(),
+            mode: metadata.mode(),
+        },
+        inode: Some(metadata.ino()),
+    }
+}
+
+#[cfg(not(target_family = "unix"))]
+fn candidate(path: PathBuf, metadata: &fs::Metadata) -> Candidate {
+    Candidate {
+        path,
+        key: FileKey {
+            size: metadata.len(),
+            device: 0,
+            mode: u32::from(metadata.permissions().readonly()),
+        },
+        inode: None,
+    }
+}
+
+fn hash_file(path: &Path) -> io::Result<u64> {
+    let mut reader = BufReader::new(fs::File::open(path)?);
+    let mut hasher = DefaultHasher::new();
+    let mut buffer = [0; 16 * 1024];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.write(&buffer[..read]);
+    }
+    Ok(hasher.finish())
+}
+
+fn files_equal(a: &Path, b: &Path) -> io::Result<bool> {
+    let mut a = BufReader::new(fs::File::open(a)?);
+    let mut b = BufReader::new(fs::File::open(b)?);
+    let mut buffer_a = [0; 16 * 1024];
+    let mut buffer_b = [0; 16 * 1024];
+    loop {
+        let read = a.read(&mut buffer_a)?;
+        if read == 0 {
+            return Ok(b.read(&mut buffer_b)? == 0);
+        }
+        b.read_exact(&mut buffer_b[..read])?;
+        if buffer_a[..read] != buffer_b[..read] {
+            return Ok(false);
+        }
+    }
+}
+
+/// Replaces `duplicate` with a reflink of `original` if the file system supports it,
+/// otherwise with a hardlink. The replacement is atomic, so `duplicate` is never missing.
+fn share(original: &Path, duplicate: &Path) -> io::Result<()> {
+    let temp = temp_path(duplicate);
+    if reflink_copy::reflink(original, &temp).is_err() {
+        let _ = fs::remove_file(&temp);
+        fs::hard_link(original, &temp)?;
+    }
+    if let Err(e) = fs::rename(&temp, duplicate) {
+        let _ = fs::remove_file(&temp);
+        return Err(e);
+    }
+    Ok(())
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    path.with_file_name(format!(".{name}.renutil-{}", std::process::id()))
+}
+
+/// Writes a file by replacing it instead of modifying it in place,
+/// so other files sharing its storage after `dedupe` stay untouched.
+pub fn write_unshared(path: &Path, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    let temp = temp_path(path);
+    fs::write(&temp, contents)?;
+    fs::rename(&temp, path)
+}
+
+/// Copies a file by replacing the destination instead of modifying it in place,
+/// so other files sharing its storage after `dedupe` stay untouched.
+pub fn copy_unshared(from: &Path, to: &Path) -> io::Result<()> {
+    let temp = temp_path(to);
+    fs::copy(from, &temp)?;
+    fs::rename(&temp, to)
+}
+
+/// Replaces identical files across all instances in the registry with reflinks or hardlinks.
+/// Linked instances and instances of shared registries are left alone.
+pub fn dedupe(registry: &PathBuf, dry_run: bool) -> Result<()> {
+    let registry_root = canonicalize_normalized(registry)?;
+
+    let mut roots = vec![];
+    for instance in get_installed_instances(registry)? {
+        let path = instance.path(registry);
+        if path.starts_with(&registry_root) && instance.link_target(registry).is_none() {
+            roots.push(path);
+        }
+    }
+
+    println!("Scanning {} instances", roots.len());
+
+    let mut groups: HashMap<FileKey, Vec<Candidate>> = HashMap::new();
+    for root in &roots {
+        for entry in jwalk::WalkDir::new(root) {
+            let entry = entry?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let path = entry.path();
+            if !is_shareable(path.strip_prefix(root)?) {
+                continue;
+            }
+            let metadata = fs::metadata(&path)?;
+            if metadata.len() == 0 {
+                continue;
+            }
+            let candidate = candidate(path, &metadata);
+            groups.entry(candidate.key).or_default().push(candidate);
+        }
+    }
+
+    let mut num_shared = 0;
+    let mut saved = 0;
+
+    for (key, mut candidates) in groups {
+        if candidates.len() < 2 {
+            continue;
+        }
+        candidates.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let mut by_hash: HashMap<u64, Vec<Candidate>> = HashMap::new();
+        for candidate in candidates {
+            by_hash
+                .entry(hash_file(&candidate.path)?)
+                .or_default()
+                .push(candidate);
+        }
+
+        for candidates in by_hash.into_values() {
+            let Some((original, duplicates)) = candidates.split_first() else {
+                continue;
+            };
+            for duplicate in duplicates {
+                if duplicate.inode.is_some() && duplicate.inode == original.inode {
+                    continue;
+                }
+                if !files_equal(&original.path, &duplicate.path)? {
+                    continue;
+                }
+                if !dry_run {
+                    share(&original.path, &duplicate.path)?;
+                }
+                num_shared += 1;
+                saved += key.size;
+            }
+        }
+    }
+
+    if dry_run {
+        println!(
+            "Would deduplicate {num_shared} files, saving {}.",
+            HumanBytes(saved)
+        );
+    } else {
+        println!(
+            "Deduplicated {num_shared} files, saving {}.",
+            HumanBytes(saved)
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_shareable;
+    use std::path::Path;
+
+    #[test]
+    fn shareable_paths() {
+        assert!(is_shareable(Path::new("lib/python3.9/os.py")));
+        assert!(is_shareable(Path::new("renpy/common/00start.rpy")));
+        assert!(is_shareable(Path::new(
+            "rapt/Sdk/platforms/android-34/android.jar"
+        )));
+        assert!(!is_shareable(Path::new("launcher/game/gui.rpyc")));
+        assert!(!is_shareable(Path::new("rapt/project/local.properties")));
+        assert!(!is_shareable(Path::new("rapt/android.keystore")));
+        assert!(!is_shareable(Path::new(
+            "rapt/Sdk/licenses/android-sdk-license"
+        )));
+        assert!(!is_shareable(Path::new("renpyfoo/file")));
+        assert!(!is_shareable(Path::new("tmp/build/file")));
+        assert!(!is_shareable(Path::new(".renutil.json")));
+    }
+}
@@ -0,0 +1,349 @@
+use crate::renutil::parse_instance_name;
+use anyhow::{Result, anyhow};
+use bzip2::read::BzDecoder;
+use similar::TextDiff;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    hash::{DefaultHasher, Hash, Hasher},
+    io::Read,
+    path::{Path, PathBuf},
+};
+use tar::Archive;
+
+/// The parts of an SDK that are compared, everything else is bundled third-party code.
+pub const SECTIONS: [&str; 4] = ["renpy", "launcher", "rapt", "web"];
+
+/// The sections SDK archives contain, RAPT and the web runtime are downloaded separately.
+const ARCHIVE_SECTIONS: [&str; 2] = ["renpy", "launcher"];
+
+/// Paths renutil generates while installing an instance, which are not part of any archive:
+/// the Android SDK and project, keystores and the link RAPT imports the engine through.
+const GENERATED_PATHS: [&str; 5] = [
+    "rapt/Sdk",
+    "rapt/project",
+    "rapt/renpy",
+    "rapt/android.keystore",
+    "rapt/bundle.keystore",
+];
+
+/// Files renutil patches while installing an instance. They are still compared, since Ren'Py
+/// changes them as well, but are marked in the output as they may differ due to the patches alone.
+const PATCHED_PATHS: [&str; 4] = [
+    "rapt/android.py",
+    "rapt/buildlib/rapt/interface.py",
+    "rapt/buildlib/rapt/plat.py",
+    "renpy/compat/pickle.py",
+];
+
+/// Compiled files are regenerated by every installation, so they always differ.
+const IGNORED_EXTENSIONS: [&str; 5] = ["pyc", "pyo", "rpyc", "rpymc", "rpyb"];
+
+/// Files whose changes are shown as unified diffs.
+const TEXT_EXTENSIONS: [&str; 2] = ["py", "rpy"];
+
+#[derive(Debug, PartialEq, Eq)]
+struct FileEntry {
+    hash: u64,
+    /// Only kept for files that are diffed.
+    text: Option<String>,
+}
+
+impl FileEntry {
+    fn new(path: &str, content: &[u8]) -> Self {
+        let mut hasher = DefaultHasher::new();
+        content.hash(&mut hasher);
+
+        let text = Path::new(path)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .filter(|ext| TEXT_EXTENSIONS.contains(ext))
+            .map(|_| String::from_utf8_lossy(content).into_owned());
+
+        Self {
+            hash: hasher.finish(),
+            text,
+        }
+    }
+}
+
+type Tree = BTreeMap<String, FileEntry>;
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Comparison {
+    added: Vec<String>,
+    removed: Vec<String>,
+    changed: Vec<String>,
+}
+
+/// Whether a path relative to the SDK root is part of the comparison of the given sections.
+fn is_relevant(path: &str, sections: &[&str], filters: &[String]) -> bool {
+    let Some((section, _)) = path.split_once('/') else {
+        return false;
+    };
+    if !sections.contains(&section) || path.split('/').any(|c| c == "__pycache__") {
+        return false;
+    }
+    if GENERATED_PATHS
+        .iter()
+        .any(|generated| Path::new(path).starts_with(generated))
+    {
+        return false;
+    }
+    if Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| IGNORED_EXTENSIONS.contains(&ext))
+    {
+        return false;
+    }
+    filters.is_empty()
+        || filters
+            .iter()
+            .any(|filter| Path::new(path).starts_with(filter))
+}
+
+fn read_instance(path: &Path, sections: &[&str], filters: &[String]) -> Result<Tree> {
+    let mut tree = Tree::new();
+
+    for section in sections {
+        let section_path = path.join(section);
+        if !section_path.is_dir() {
+            continue;
+        }
+        for entry in jwalk::WalkDir::new(&section_path) {
+            let entry = entry?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let file_path = entry.path();
+            let relative = file_path
+                .strip_prefix(path)?
+                .to_string_lossy()
+                .replace('\\', "/");
+            if is_relevant(&relative, sections, filters) {
+                let entry = FileEntry::new(&relative, &fs::read(&file_path)?);
+                tree.insert(relative, entry);
+            }
+        }
+    }
+
+    Ok(tree)
+}
+
+fn read_archive(path: &Path, sections: &[&str], filters: &[String]) -> Result<Tree> {
+    let mut tree = Tree::new();
+
+    let name = path.to_string_lossy();
+    if name.ends_with(".zip") {
+        let mut zip = zip::ZipArchive::new(fs::File::open(path)?)?;
+        for i in 0..zip.len() {
+            let mut file = zip.by_index(i)?;
+            if !file.is_file() {
+                continue;
+            }
+            let Some(file_path) = file.enclosed_name() else {
+                continue;
+            };
+            // SDK archives contain a single root directory named after the version.
+            let relative = file_path
+                .components()
+                .skip(1)
+                .collect::<PathBuf>()
+                .to_string_lossy()
+                .replace('\\', "/");
+            if is_relevant(&relative, sections, filters) {
+                let mut content = vec![];
+                file.read_to_end(&mut content)?;
+                tree.insert(relative.clone(), FileEntry::new(&relative, &content));
+            }
+        }
+    } else if name.ends_with(".tar.bz2") {
+        let mut archive = Archive::new(BzDecoder::new(fs::File::open(path)?));
+        for file in archive.entries()? {
+            let mut file = file?;
+            if !file.header().entry_type().is_file() {
+                continue;
+            }
+            let relative = file
+                .path()?
+                .components()
+                .skip(1)
+                .collect::<PathBuf>()
+                .to_string_lossy()
+                .replace('\\', "/");
+            if is_relevant(&relative, sections, filters) {
+                let mut content = vec![];
+                file.read_to_end(&mut content)?;
+                tree.insert(relative.clone(), FileEntry::new(&relative, &content));
+            }
+        }
+    } else {
+        anyhow::bail!(
+            "Unsupported archive {name}, expected a Ren'Py SDK archive ending in .zip or .tar.bz2."
+        );
+    }
+
+    Ok(tree)
+}
+
+/// Reads either an installed instance, given by its name, or an SDK archive, given by its path.
+fn read_tree(registry: &Path, source: &str, sections: &[&str], filters: &[String]) -> Result<Tree> {
+    let path = Path::new(source);
+    if path.is_file() {
+        return read_archive(path, sections, filters);
+    }
+
+    let (version, options) = parse_instance_name(source)
+        .map_err(|e| anyhow!("{source} is neither an SDK archive nor a valid version: {e}"))?;
+    let instance = version.to_local(registry, &options)?;
+
+    read_instance(&instance.path(registry), sections, filters)
+}
+
+fn compare(a: &Tree, b: &Tree) -> Comparison {
+    let paths = a.keys().chain(b.keys()).collect::<BTreeSet<_>>();
+
+    let mut comparison = Comparison::default();
+    for path in paths {
+        match (a.get(path), b.get(path)) {
+            (None, Some(_)) => comparison.added.push(path.clone()),
+            (Some(_), None) => comparison.removed.push(path.clone()),
+            (Some(a), Some(b)) if a.hash != b.hash => comparison.changed.push(path.clone()),
+            _ => {}
+        }
+    }
+
+    comparison
+}
+
+/// Compares the engine, launcher, RAPT and web runtime of two instances or SDK archives.
+/// Only paths starting with one of the given filters are compared, if any are given.
+pub fn diff(registry: &Path, a: &str, b: &str, filters: &[String], name_only: bool) -> Result<()> {
+    let sections = if Path::new(a).is_file() || Path::new(b).is_file() {
+        println!(
+            "SDK archives do not contain RAPT and the web runtime, only comparing the engine and the launcher."
+        );
+        &ARCHIVE_SECTIONS[..]
+    } else {
+        &SECTIONS[..]
+    };
+    let tree_a = read_tree(registry, a, sections, filters)?;
+    let tree_b = read_tree(registry, b, sections, filters)?;
+
+    let comparison = compare(&tree_a, &tree_b);
+
+    for path in &comparison.added {
+        println!("A {path}");
+    }
+    for path in &comparison.removed {
+        println!("D {path}");
+    }
+    let mut patched = false;
+    for path in &comparison.changed {
+        if PATCHED_PATHS.contains(&path.as_str()) {
+            patched = true;
+            println!("M {path} (patched while installing)");
+        } else {
+            println!("M {path}");
+        }
+    }
+
+    if !name_only {
+        for path in &comparison.changed {
+            if let (Some(Some(old)), Some(Some(new))) = (
+                tree_a.get(path).map(|e| e.text.as_deref()),
+                tree_b.get(path).map(|e| e.text.as_deref()),
+            ) {
+                println!();
+                print!(
+                    "{}",
+                    TextDiff::from_lines(old, new)
+                        .unified_diff()
+                        .header(&format!("{a}/{path}"), &format!("{b}/{path}"))
+                );
+            }
+        }
+    }
+
+    println!();
+    println!(
+        "{} added, {} removed, {} changed",
+        comparison.added.len(),
+        comparison.removed.len(),
+        comparison.changed.len()
+    );
+    if patched {
+        println!(
+            "Files patched while installing may differ because of the patches, i.e. when comparing an instance with an SDK archive."
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ARCHIVE_SECTIONS, Comparison, FileEntry, SECTIONS, Tree, compare, is_relevant};
+
+    #[test]
+    fn tree_comparison() {
+        assert!(is_relevant("renpy/display/core.py", &SECTIONS, &[]));
+        assert!(!is_relevant("renpy/display/core.pyc", &SECTIONS, &[]));
+        assert!(!is_relevant("lib/python3.9/os.py", &SECTIONS, &[]));
+        assert!(!is_relevant("renpy.py", &SECTIONS, &[]));
+        assert!(!is_relevant(
+            "rapt/Sdk/platforms/android.jar",
+            &SECTIONS,
+            &[]
+        ));
+        assert!(is_relevant("rapt/android.py", &SECTIONS, &[]));
+        assert!(is_relevant("renpy/compat/pickle.py", &SECTIONS, &[]));
+        assert!(!is_relevant("rapt/renpy/compat/pickle.py", &SECTIONS, &[]));
+        assert!(!is_relevant("rapt/android.keystore", &SECTIONS, &[]));
+        assert!(is_relevant("rapt/buildlib/rapt/build.py", &SECTIONS, &[]));
+        assert!(!is_relevant(
+            "rapt/buildlib/rapt/build.py",
+            &ARCHIVE_SECTIONS,
+            &[]
+        ));
+        assert!(is_relevant(
+            "renpy/display/core.py",
+            &SECTIONS,
+            &["renpy/display/".into()]
+        ));
+        assert!(!is_relevant(
+            "renpy/displayable/core.py",
+            &SECTIONS,
+            &["renpy/display".into()]
+        ));
+        assert!(!is_relevant(
+            "launcher/game/gui.rpy",
+            &SECTIONS,
+            &["renpy/display".into()]
+        ));
+
+        let entry = |path: &str, content: &str| {
+            (path.to_string(), FileEntry::new(path, content.as_bytes()))
+        };
+        let a = Tree::from([
+            entry("renpy/a.py", "a"),
+            entry("renpy/b.py", "b"),
+            entry("renpy/c.py", "c"),
+        ]);
+        let b = Tree::from([
+            entry("renpy/b.py", "b"),
+            entry("renpy/c.py", "changed"),
+            entry("renpy/d.py", "d"),
+        ]);
+
+        assert_eq!(
+            compare(&a, &b),
+            Comparison {
+                added: vec!["renpy/d.py".into()],
+                removed: vec!["renpy/a.py".into()],
+                changed: vec!["renpy/c.py".into()],
+            }
+        );
+    }
+}
@@ -0,0 +1,250 @@
+use crate::common::canonicalize_normalized;
+use crate::renutil::{
+    Instance, InstanceManifest, InstanceOptions, Local, parse_instance_name, uninstall,
+};
+use crate::version::Version;
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+use tar::{Archive, Builder, EntryType, Header};
+
+/// Describes the instance contained in an exported archive, stored as its first entry.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportManifest {
+    pub name: String,
+    pub version: String,
+    pub options: InstanceOptions,
+    pub renkit_version: String,
+}
+
+impl ExportManifest {
+    pub const FILE_NAME: &'static str = "renutil-export.json";
+}
+
+/// Rewrites a symlink target that points into the instance to be relative to the link,
+/// so the link keeps working once the instance is imported into a different registry.
+fn relative_link_target(root: &Path, link: &Path, target: &Path) -> PathBuf {
+    let Ok(inner) = target.strip_prefix(root) else {
+        return target.to_path_buf();
+    };
+
+    let depth = link
+        .parent()
+        .map_or(0, |parent| parent.components().count());
+
+    let mut relative = PathBuf::new();
+    for _ in 0..depth {
+        relative.push("..");
+    }
+    relative.push(inner);
+    relative
+}
+
+fn append_bytes<W: std::io::Write>(
+    builder: &mut Builder<W>,
+    path: &Path,
+    data: &[u8],
+) -> Result<()> {
+    let mut header = Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder.append_data(&mut header, path, data)?;
+    Ok(())
+}
+
+/// Packs an installed instance into a zstd-compressed tarball, preserving permissions and symlinks.
+pub fn export(
+    registry: &Path,
+    version: &Version,
+    options: &InstanceOptions,
+    output: &Path,
+) -> Result<()> {
+    let instance = version.to_local(registry, options)?;
+    let name = instance.name();
+
+    // Linked instances are exported with the contents of the SDK they point to.
+    let root = canonicalize_normalized(instance.path(registry))?;
+
+    println!("Exporting {name} to {}", output.to_string_lossy());
+
+    let file = fs::File::create(output)?;
+    let mut encoder = zstd::Encoder::new(file, 0)?;
+    encoder.include_checksum(true)?;
+
+    let mut builder = Builder::new(encoder);
+    builder.follow_symlinks(false);
+
+    let manifest = ExportManifest {
+        name: name.clone(),
+        version: version.to_string(),
+        options: options.clone(),
+        renkit_version: env!("CARGO_PKG_VERSION").to_string(),
+    };
+    append_bytes(
+        &mut builder,
+        Path::new(ExportManifest::FILE_NAME),
+        &serde_json::to_vec_pretty(&manifest)?,
+    )?;
+
+    // Instances installed before manifests were introduced get one, so they can be verified on import.
+    if InstanceManifest::read(&root)?.is_none() {
+        let instance_manifest = InstanceManifest {
+            version: version.to_string(),
+            options: options.clone(),
+            archives: vec![],
+            patches: vec![],
+            android_build_tools: None,
+        };
+        append_bytes(
+            &mut builder,
+            &Path::new(&name).join(InstanceManifest::FILE_NAME),
+            &serde_json::to_vec_pretty(&instance_manifest)?,
+        )?;
+    }
+
+    let mut num_files = 0;
+    for entry in jwalk::WalkDir::new(&root).sort(true) {
+        let entry = entry?;
+        let path = entry.path();
+        let relative = path.strip_prefix(&root)?;
+        let archive_path = Path::new(&name).join(relative);
+
+        let file_type = entry.file_type();
+        if file_type.is_symlink() {
+            let target = relative_link_target(&root, relative, &fs::read_link(&path)?);
+            let mut header = Header::new_gnu();
+            header.set_entry_type(EntryType::Symlink);
+            header.set_size(0);
+            header.set_mode(0o777);
+            builder.append_link(&mut header, &archive_path, target)?;
+        } else if file_type.is_dir() {
+            builder.append_dir(&archive_path, &path)?;
+        } else {
+            builder.append_path_with_name(&path, &archive_path)?;
+            num_files += 1;
+        }
+    }
+
+    builder.into_inner()?.finish()?;
+
+    println!("Exported {num_files} files.");
+
+    Ok(())
+}
+
+/// Unpacks an archive created by `export` and registers the contained instance.
+/// The archive is unpacked into a staging directory first, so a broken archive leaves no traces.
+pub fn import(registry: &Path, archive: &Path, force: bool) -> Result<()> {
+    let staging = registry.join(format!(".import-{}", rand::random::<u32>()));
+    fs::create_dir_all(&staging)?;
+
+    let result = import_staged(registry, archive, &staging, force);
+
+    if let Err(e) = fs::remove_dir_all(&staging) {
+        println!(
+            "Unable to remove staging directory {}: {e}",
+            staging.to_string_lossy()
+        );
+    }
+
+    result
+}
+
+fn import_staged(registry: &Path, archive: &Path, staging: &Path, force: bool) -> Result<()> {
+    println!("Unpacking {}", archive.to_string_lossy());
+
+    let decoder = zstd::Decoder::new(fs::File::open(archive)?)?;
+    let mut tar = Archive::new(decoder);
+    tar.set_preserve_permissions(true);
+    tar.set_preserve_mtime(true);
+    tar.unpack(staging)
+        .map_err(|e| anyhow!("Unable to unpack {}: {e}", archive.to_string_lossy()))?;
+
+    // The tarball may end before the compressed stream does, i.e. if the archive was truncated.
+    // Reading up to the end of the stream makes sure its checksum is verified.
+    io::copy(&mut tar.into_inner(), &mut io::sink())
+        .map_err(|e| anyhow!("{} is corrupted: {e}", archive.to_string_lossy()))?;
+
+    let manifest_path = staging.join(ExportManifest::FILE_NAME);
+    if !manifest_path.exists() {
+        anyhow::bail!(
+            "{} is not an instance exported by renutil.",
+            archive.to_string_lossy()
+        );
+    }
+    let manifest: ExportManifest = serde_json::from_str(&fs::read_to_string(manifest_path)?)?;
+
+    let (version, options) = parse_instance_name(&manifest.name)?;
+    if options != manifest.options {
+        anyhow::bail!(
+            "The archive contains {} with mismatching options ({:?}).",
+            manifest.name,
+            manifest.options
+        );
+    }
+
+    let name = Instance::<Local>::new(version.clone(), options.clone()).name();
+    let extracted = staging.join(&name);
+    if !extracted.join("renpy.py").exists() {
+        anyhow::bail!("The archive does not contain a Ren'Py SDK for {name}.");
+    }
+    match InstanceManifest::read(&extracted)? {
+        Some(instance_manifest) if instance_manifest.options == options => {}
+        _ => anyhow::bail!("The archive does not contain a valid instance manifest for {name}."),
+    }
+
+    if version.is_installed(registry, &options) {
+        if force {
+            println!("Forcing uninstallation of existing version {name}.");
+            uninstall(&registry.to_path_buf(), &version, &options)?;
+        } else {
+            anyhow::bail!("Version {name} is already installed.");
+        }
+    }
+
+    fs::rename(&extracted, registry.join(&name))?;
+
+    println!("Imported {name}.");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::relative_link_target;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn link_targets() {
+        let root = Path::new("/registry/8.3.4");
+
+        assert_eq!(
+            relative_link_target(
+                root,
+                Path::new("rapt/renpy"),
+                Path::new("/registry/8.3.4/renpy")
+            ),
+            PathBuf::from("../renpy")
+        );
+        assert_eq!(
+            relative_link_target(
+                root,
+                Path::new("renpy.sh"),
+                Path::new("/registry/8.3.4/renpy.py")
+            ),
+            PathBuf::from("renpy.py")
+        );
+        assert_eq!(
+            relative_link_target(root, Path::new("lib/python"), Path::new("/usr/bin/python3")),
+            PathBuf::from("/usr/bin/python3")
+        );
+        assert_eq!(
+            relative_link_target(root, Path::new("lib/x/python"), Path::new("python3.9")),
+            PathBuf::from("python3.9")
+        );
+    }
+}
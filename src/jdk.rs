@@ -0,0 +1,183 @@
+use crate::version::Version;
+use anyhow::Result;
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+#[derive(Debug, Clone)]
+pub struct Jdk {
+    pub path: PathBuf,
+    pub version: String,
+    pub major: u32,
+}
+
+impl Jdk {
+    /// Reads the `release` file of the JDK installed at the given path.
+    #[must_use]
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let content = fs::read_to_string(path.join("release")).ok()?;
+        let (version, major) = parse_release(&content)?;
+        Some(Self {
+            path: path.to_path_buf(),
+            version,
+            major,
+        })
+    }
+}
+
+/// Extracts the full and major Java version from the contents of a JDK `release` file.
+/// Versions before 9 use the legacy `1.x` scheme, i.e. `1.8.0_392` is major version 8.
+#[must_use]
+pub fn parse_release(content: &str) -> Option<(String, u32)> {
+    let version = content
+        .lines()
+        .find_map(|line| line.strip_prefix("JAVA_VERSION="))?
+        .trim()
+        .trim_matches('"')
+        .to_string();
+
+    let mut parts = version.split(['.', '_', '-', '+']);
+    let major = match parts.next()?.parse::<u32>().ok()? {
+        1 => parts.next()?.parse::<u32>().ok()?,
+        major => major,
+    };
+
+    Some((version, major))
+}
+
+/// The major JDK version required to build Android packages with the given version of Ren'Py.
+#[must_use]
+pub fn required_major(version: &Version) -> u32 {
+    if version >= &Version::from_str("8.2.0").unwrap() {
+        21
+    } else {
+        8
+    }
+}
+
+/// Directories which contain JDK installations, in the order they are searched in.
+/// Paths given explicitly and via `RENUTIL_JDK_PATHS` take precedence over the common locations.
+#[must_use]
+pub fn search_paths(extra_paths: &[PathBuf]) -> Vec<PathBuf> {
+    let mut paths = extra_paths.to_vec();
+
+    if let Some(env_paths) = env::var_os("RENUTIL_JDK_PATHS") {
+        paths.extend(env::split_paths(&env_paths));
+    }
+
+    if let Some(home) = home::home_dir() {
+        paths.push(home.join(".sdkman/candidates/java"));
+        paths.push(home.join(".jdks"));
+        paths.push(home.join("Library/Java/JavaVirtualMachines"));
+    }
+
+    paths.push(PathBuf::from("/usr/lib/jvm"));
+    paths.push(PathBuf::from("/Library/Java/JavaVirtualMachines"));
+    paths.push(PathBuf::from(r"C:\Program Files\Java"));
+    paths.push(PathBuf::from(r"C:\Program Files\Eclipse Adoptium"));
+
+    paths
+}
+
+/// Finds all JDKs in the given search paths. Each search path may either be a JDK itself
+/// or contain JDKs as its immediate subdirectories, including macOS-style bundles.
+#[must_use]
+pub fn discover(extra_paths: &[PathBuf]) -> Vec<Jdk> {
+    let mut jdks: Vec<Jdk> = vec![];
+
+    for root in search_paths(extra_paths) {
+        let mut candidates = vec![root.clone()];
+        if let Ok(entries) = fs::read_dir(&root) {
+            let mut children: Vec<PathBuf> = entries.flatten().map(|e| e.path()).collect();
+            children.sort();
+            for child in children {
+                candidates.push(child.join("Contents/Home"));
+                candidates.push(child);
+            }
+        }
+
+        for candidate in candidates {
+            if let Some(jdk) = Jdk::from_path(&candidate)
+                && !jdks.iter().any(|j| j.path == jdk.path)
+            {
+                jdks.push(jdk);
+            }
+        }
+    }
+
+    jdks
+}
+
+/// Finds a JDK matching the given version of Ren'Py.
+/// `JAVA_HOME` is preferred if it points to a JDK of the required major version,
+/// otherwise the first matching JDK from the search paths is picked.
+#[must_use]
+pub fn find_jdk(version: &Version, extra_paths: &[PathBuf]) -> Option<Jdk> {
+    let required = required_major(version);
+
+    if let Some(jdk) = env::var_os("JAVA_HOME").and_then(|path| Jdk::from_path(Path::new(&path)))
+        && jdk.major == required
+    {
+        return Some(jdk);
+    }
+
+    discover(extra_paths)
+        .into_iter()
+        .find(|jdk| jdk.major == required)
+}
+
+/// Points `JAVA_HOME` at the JDK matching the given version of Ren'Py for this process and its children.
+/// Falls back to the existing value of `JAVA_HOME` with a warning if no matching JDK could be found.
+pub fn configure_java_home(version: &Version, extra_paths: &[PathBuf]) -> Result<PathBuf> {
+    let required = required_major(version);
+
+    let java_home = if let Some(jdk) = find_jdk(version, extra_paths) {
+        println!(
+            "Using OpenJDK {} at {}",
+            jdk.version,
+            jdk.path.to_string_lossy()
+        );
+        jdk.path
+    } else if let Some(path) = env::var_os("JAVA_HOME").map(PathBuf::from) {
+        match Jdk::from_path(&path) {
+            Some(jdk) => println!(
+                "Warning: JAVA_HOME points to OpenJDK {}, but Ren'Py {version} requires OpenJDK {required}.",
+                jdk.version
+            ),
+            None => println!(
+                "Warning: Unable to determine the JDK version at {}, Ren'Py {version} requires OpenJDK {required}.",
+                path.to_string_lossy()
+            ),
+        }
+        path
+    } else {
+        anyhow::bail!(
+            "JAVA_HOME is not set and no matching JDK was found. Please check if you need to install OpenJDK {required}"
+        );
+    };
+
+    unsafe { env::set_var("JAVA_HOME", &java_home) };
+
+    Ok(java_home)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_release;
+
+    #[test]
+    fn release_parsing() {
+        let release = "IMPLEMENTOR=\"Eclipse Adoptium\"\nJAVA_VERSION=\"21.0.2\"\nJAVA_VERSION_DATE=\"2024-01-16\"";
+        assert_eq!(parse_release(release), Some(("21.0.2".into(), 21)));
+
+        let release = "JAVA_VERSION=\"1.8.0_392\"\nOS_NAME=\"Linux\"";
+        assert_eq!(parse_release(release), Some(("1.8.0_392".into(), 8)));
+
+        let release = "JAVA_VERSION=\"17\"";
+        assert_eq!(parse_release(release), Some(("17".into(), 17)));
+
+        assert_eq!(parse_release("OS_NAME=\"Linux\""), None);
+    }
+}
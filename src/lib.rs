@@ -3,10 +3,19 @@
     clippy::missing_panics_doc,
     clippy::too_many_lines,
     clippy::missing_errors_doc,
-    clippy::fn_params_excessive_bools
+    clippy::fn_params_excessive_bools,
+    clippy::too_many_arguments
 )]
+pub mod cache;
 pub mod common;
+pub mod dedupe;
+pub mod diff;
+pub mod export;
+pub mod jdk;
+pub mod lock;
 pub mod renconstruct;
 pub mod renotize;
 pub mod renutil;
+pub mod serve;
+pub mod tui;
 pub mod version;
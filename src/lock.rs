@@ -0,0 +1,238 @@
+use crate::common::canonicalize_normalized;
+use crate::renutil::{
+    ArchiveRecord, Instance, InstanceManifest, InstanceOptions, Local, VERSION_FILE_NAME,
+    find_version_file, read_version_file,
+};
+use crate::version::Version;
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// The file next to `.renpy-version` that records the exact engine a project is built with.
+pub const LOCK_FILE_NAME: &str = "renkit.lock";
+
+/// How a launch treats a `renkit.lock` of the launched project.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LockCheck {
+    #[default]
+    Skip,
+    /// Print a warning if the instance does not match the lock.
+    Warn,
+    /// Fail if there is no lock or the instance does not match it.
+    Enforce,
+}
+
+/// The provenance of an installed instance, as recorded by `renutil lock`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Lockfile {
+    pub version: String,
+    pub renkit_version: String,
+    pub android_build_tools: Option<String>,
+    #[serde(default)]
+    pub patches: Vec<String>,
+    #[serde(default)]
+    pub archives: Vec<ArchiveRecord>,
+}
+
+impl Lockfile {
+    /// Describes the installed instance of the given version.
+    pub fn from_instance(
+        registry: &Path,
+        version: &Version,
+        options: &InstanceOptions,
+    ) -> Result<Self> {
+        let instance = version.to_local(registry, options)?;
+        let name = instance.name();
+
+        let manifest = InstanceManifest::read(&instance.path(registry))?
+            .filter(|manifest| !manifest.archives.is_empty())
+            .ok_or(anyhow!(
+                "{name} does not record the archives it was installed from, reinstall it via 'renutil install --force {name}' to lock it."
+            ))?;
+
+        Ok(Self {
+            version: name,
+            renkit_version: env!("CARGO_PKG_VERSION").to_string(),
+            android_build_tools: manifest.android_build_tools,
+            patches: manifest.patches,
+            archives: manifest.archives,
+        })
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        toml::from_str(&fs::read_to_string(path)?)
+            .map_err(|e| anyhow!("Invalid lock file {}: {e}", path.to_string_lossy()))
+    }
+
+    /// Lists every difference to the given lock that changes the engine.
+    /// The renkit version is only recorded, it does not change the engine by itself.
+    #[must_use]
+    pub fn mismatches(&self, installed: &Self) -> Vec<String> {
+        let mut mismatches = vec![];
+
+        if self.version != installed.version {
+            mismatches.push(format!(
+                "version is {}, locked to {}",
+                installed.version, self.version
+            ));
+        }
+        if self.android_build_tools != installed.android_build_tools {
+            mismatches.push(format!(
+                "Android build tools are {}, locked to {}",
+                installed
+                    .android_build_tools
+                    .as_deref()
+                    .unwrap_or("unknown"),
+                self.android_build_tools.as_deref().unwrap_or("unknown")
+            ));
+        }
+        if self.patches != installed.patches {
+            mismatches.push(format!(
+                "patches are [{}], locked to [{}]",
+                installed.patches.join(", "),
+                self.patches.join(", ")
+            ));
+        }
+        for locked in &self.archives {
+            match installed
+                .archives
+                .iter()
+                .find(|a| a.component == locked.component)
+            {
+                Some(archive) if archive == locked => {}
+                Some(archive) => mismatches.push(format!(
+                    "{} archive is {} ({}), locked to {} ({})",
+                    locked.component, archive.url, archive.sha256, locked.url, locked.sha256
+                )),
+                None => mismatches.push(format!("{} is not installed", locked.component)),
+            }
+        }
+        for archive in &installed.archives {
+            if !self
+                .archives
+                .iter()
+                .any(|a| a.component == archive.component)
+            {
+                mismatches.push(format!(
+                    "{} is installed, but not locked",
+                    archive.component
+                ));
+            }
+        }
+
+        mismatches
+    }
+}
+
+/// Walks up from `start` through all of its parent directories
+/// and returns the path to the closest `renkit.lock` file, if any.
+#[must_use]
+pub fn find_lock_file(start: &Path) -> Option<PathBuf> {
+    let start = canonicalize_normalized(start).ok()?;
+    start
+        .ancestors()
+        .map(|dir| dir.join(LOCK_FILE_NAME))
+        .find(|path| path.is_file())
+}
+
+/// Writes a `renkit.lock` next to the `.renpy-version` file of the project,
+/// recording the provenance of the pinned instance.
+pub fn lock(registry: &Path, project: &Path) -> Result<()> {
+    let Some(version_file) = find_version_file(project) else {
+        anyhow::bail!(
+            "No {VERSION_FILE_NAME} found for {}, pin a version via 'renutil pin <version>' first.",
+            project.to_string_lossy()
+        );
+    };
+    let (version, options) = read_version_file(&version_file)?;
+
+    if !version.is_installed(registry, &options) {
+        anyhow::bail!(
+            "{} is not installed.",
+            Instance::<Local>::new(version, options).name()
+        );
+    }
+
+    let lockfile = Lockfile::from_instance(registry, &version, &options)?;
+    let path = version_file.with_file_name(LOCK_FILE_NAME);
+    fs::write(&path, toml::to_string_pretty(&lockfile)?)?;
+
+    println!("Locked {} to {}.", lockfile.version, path.to_string_lossy());
+
+    Ok(())
+}
+
+/// Compares the instance of the given version against the closest `renkit.lock` of the project.
+/// Mismatches are errors if `locked` is set, otherwise they are printed as warnings.
+pub fn check_lock(
+    registry: &Path,
+    project: &Path,
+    version: &Version,
+    options: &InstanceOptions,
+    locked: bool,
+) -> Result<()> {
+    let Some(path) = find_lock_file(project) else {
+        if locked {
+            anyhow::bail!(
+                "No {LOCK_FILE_NAME} found for {}, create one via 'renutil lock'.",
+                project.to_string_lossy()
+            );
+        }
+        return Ok(());
+    };
+
+    let lockfile = Lockfile::read(&path)?;
+    let mismatches = match Lockfile::from_instance(registry, version, options) {
+        Ok(installed) => lockfile.mismatches(&installed),
+        Err(e) => vec![e.to_string()],
+    };
+
+    if mismatches.is_empty() {
+        return Ok(());
+    }
+
+    let message = format!(
+        "The installed instance does not match {}:\n  {}",
+        path.to_string_lossy(),
+        mismatches.join("\n  ")
+    );
+    if locked {
+        anyhow::bail!(message);
+    }
+    println!("Warning: {message}");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Lockfile;
+    use crate::renutil::ArchiveRecord;
+
+    #[test]
+    fn lock_mismatches() {
+        let archive = |component: &str, sha256: &str| ArchiveRecord {
+            component: component.into(),
+            url: format!("https://www.renpy.org/dl/8.3.4/{component}.zip"),
+            sha256: sha256.into(),
+        };
+        let locked = Lockfile {
+            version: "8.3.4".into(),
+            renkit_version: "6.1.0".into(),
+            android_build_tools: Some("29.0.2".into()),
+            patches: vec!["gradle-xmx8g".into()],
+            archives: vec![archive("SDK", "a"), archive("RAPT", "b")],
+        };
+
+        let mut installed = locked.clone();
+        installed.renkit_version = "6.2.0".into();
+        assert!(locked.mismatches(&installed).is_empty());
+
+        installed.archives = vec![archive("SDK", "c"), archive("Web", "d")];
+        installed.patches.push("pickle-protocol-5".into());
+        assert_eq!(locked.mismatches(&installed).len(), 4);
+    }
+}
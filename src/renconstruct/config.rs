@@ -1,10 +1,15 @@
-use crate::version::Version;
+use super::{
+    matrix::expand_versions,
+    profiles::{apply_profile, read_with_includes},
+    vars::interpolate,
+};
+use crate::{renutil::InstanceOptions, version::Version};
 use rustpython_vm::PyObjectRef;
 use serde::{Deserialize, Deserializer, Serialize};
 use std::{
     collections::{HashMap, HashSet},
     fmt::Display,
-    path::PathBuf,
+    path::{Path, PathBuf},
     str::FromStr,
 };
 
@@ -29,10 +34,18 @@ fn default_webp_quality() -> f32 {
     90.0
 }
 
+fn default_jpeg_quality() -> u8 {
+    90
+}
+
 fn default_as_true() -> bool {
     true
 }
 
+fn default_image_cache_max_size_mb() -> u64 {
+    2048
+}
+
 fn default_convert_images_extensions() -> Vec<String> {
     vec!["png".into(), "jpg".into(), "jpeg".into()]
 }
@@ -51,6 +64,7 @@ pub enum ImageFormat {
 }
 
 #[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct ConvertImagesPathConfig {
     #[serde(default = "default_as_true")]
     pub recursive: bool,
@@ -60,7 +74,37 @@ pub struct ConvertImagesPathConfig {
     pub extensions: Vec<String>,
 }
 
+/// The filter images are resampled with when resizing them.
+#[derive(Debug, Clone, Copy, Deserialize, Default)]
+pub enum ResizeFilter {
+    #[serde(alias = "nearest")]
+    Nearest,
+    #[serde(alias = "triangle")]
+    Triangle,
+    #[serde(alias = "catmull_rom")]
+    CatmullRom,
+    #[serde(alias = "gaussian")]
+    Gaussian,
+    #[default]
+    #[serde(alias = "lanczos3")]
+    Lanczos3,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ResizeImagesPathConfig {
+    #[serde(default = "default_as_true")]
+    pub recursive: bool,
+    #[serde(default = "default_convert_images_extensions")]
+    pub extensions: Vec<String>,
+    /// The factor to scale images by, i.e. `0.5` for half resolution.
+    pub scale: Option<f32>,
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+}
+
 #[derive(Debug, Clone, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
 pub struct PriorityOptions {
     #[serde(default)]
     pub pre_build: usize,
@@ -68,10 +112,39 @@ pub struct PriorityOptions {
     pub post_build: usize,
 }
 
+#[derive(Debug, Clone, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct StageDependencies {
+    #[serde(default)]
+    pub pre_build: Vec<String>,
+    #[serde(default)]
+    pub post_build: Vec<String>,
+}
+
+/// The tasks a task waits for, either in every stage both of them run in or per stage.
 #[derive(Debug, Clone, Deserialize)]
-pub struct LintOptions {}
+#[serde(untagged)]
+pub enum DependsOn {
+    All(Vec<String>),
+    Stages(StageDependencies),
+}
+
+impl Default for DependsOn {
+    fn default() -> Self {
+        DependsOn::All(vec![])
+    }
+}
 
 #[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LintOptions {
+    /// Lints with a throwaway home and save directory, so local persistent data can not leak in.
+    #[serde(default = "default_as_true")]
+    pub sandbox: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct KeystoreOptions {
     pub keystore_apk: String,
     pub keystore_aab: String,
@@ -80,6 +153,7 @@ pub struct KeystoreOptions {
 }
 
 #[derive(Debug, Clone, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
 pub struct ConvertImagesOptions {
     pub format: ImageFormat,
     #[serde(default = "default_avif_quality")]
@@ -89,7 +163,20 @@ pub struct ConvertImagesOptions {
     pub paths: HashMap<String, ConvertImagesPathConfig>,
 }
 
+#[derive(Debug, Clone, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ResizeImagesOptions {
+    #[serde(default)]
+    pub filter: ResizeFilter,
+    #[serde(default = "default_jpeg_quality")]
+    pub jpeg_quality: u8,
+    #[serde(default = "default_webp_quality")]
+    pub webp_quality: f32,
+    pub paths: HashMap<String, ResizeImagesPathConfig>,
+}
+
 #[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct NotarizeOptions {
     pub bundle_id: String,
     pub key_file: PathBuf,
@@ -131,6 +218,8 @@ pub enum TaskOptions {
     Keystore(KeystoreOptions),
     #[serde(rename = "convert_images")]
     ConvertImages(ConvertImagesOptions),
+    #[serde(rename = "resize_images")]
+    ResizeImages(ResizeImagesOptions),
     #[serde(rename = "notarize")]
     Notarize(NotarizeOptions),
     #[serde(rename = "custom")]
@@ -146,20 +235,22 @@ pub struct GeneralTaskOptions {
     pub on_builds: HashSet<String>,
     #[serde(default)]
     pub priorities: PriorityOptions,
+    #[serde(default)]
+    pub depends_on: DependsOn,
     #[serde(flatten)]
     pub options: TaskOptions,
     #[serde(default)]
     pub sandboxed: bool,
 }
 
-#[derive(Debug, Deserialize, Hash, PartialEq, Eq)]
+#[derive(Debug, Clone, Deserialize, Hash, PartialEq, Eq)]
 #[serde(untagged)]
 pub enum BuildOption {
     Known(KnownBuildOption),
     Custom(String),
 }
 
-#[derive(Debug, Deserialize, Hash, PartialEq, Eq)]
+#[derive(Debug, Clone, Deserialize, Hash, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
 pub enum KnownBuildOption {
     Pc,
@@ -189,7 +280,8 @@ impl Display for KnownBuildOption {
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
     #[serde(alias = "build")]
     pub builds: HashMap<BuildOption, bool>,
@@ -197,20 +289,106 @@ pub struct Config {
     pub options: RenconstructOptions,
     pub renutil: RenutilOptions,
     pub tasks: HashMap<String, GeneralTaskOptions>,
+    /// The variables the config was interpolated with.
+    #[serde(default)]
+    pub vars: toml::Table,
+}
+
+impl Config {
+    /// Reads a config file along with the files it includes, applies the given profile
+    /// and resolves its variables. Returns the tables `Config` is deserialized from,
+    /// one per version for build matrices, along with the version they build.
+    pub fn read_matrix(
+        path: &Path,
+        input_dir: &Path,
+        profile: Option<&str>,
+    ) -> anyhow::Result<Vec<(Option<String>, toml::Table)>> {
+        let mut config = read_with_includes(path)?;
+        apply_profile(&mut config, profile)?;
+
+        let cells = match expand_versions(&config)? {
+            Some(cells) => cells
+                .into_iter()
+                .map(|(version, config)| (Some(version), config))
+                .collect(),
+            None => vec![(None, config)],
+        };
+
+        cells
+            .into_iter()
+            .map(|(version, mut config)| {
+                interpolate(&mut config, input_dir)?;
+                Ok((version, config))
+            })
+            .collect()
+    }
+
+    #[must_use]
+    pub fn is_enabled(&self, build: KnownBuildOption) -> bool {
+        *self
+            .builds
+            .get(&BuildOption::Known(build))
+            .unwrap_or(&false)
+    }
 }
 
-#[derive(Debug, Deserialize, Default)]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct RenconstructOptions {
     pub task_dir: Option<PathBuf>,
     #[serde(default)]
     pub clear_output_dir: bool,
+    /// Builds in the project directory instead of a staging copy of it.
+    #[serde(default)]
+    pub build_in_place: bool,
+    /// Paths to leave out of the staging copy, in addition to the default ones.
+    #[serde(default)]
+    pub staging_ignore: Vec<String>,
+    /// Reuses images converted by earlier builds instead of converting them again.
+    #[serde(default = "default_as_true")]
+    pub image_cache: bool,
+    /// The size the image cache is pruned to after converting images, in MiB.
+    #[serde(default = "default_image_cache_max_size_mb")]
+    pub image_cache_max_size_mb: u64,
+}
+
+impl Default for RenconstructOptions {
+    fn default() -> Self {
+        Self {
+            task_dir: None,
+            clear_output_dir: false,
+            build_in_place: false,
+            staging_ignore: vec![],
+            image_cache: true,
+            image_cache_max_size_mb: default_image_cache_max_size_mb(),
+        }
+    }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct RenutilOptions {
     #[serde(deserialize_with = "deserialize_version")]
     pub version: Version,
     pub registry: Option<PathBuf>,
     #[serde(default)]
     pub update_pickle: bool,
+    /// The architecture to launch Ren'Py with, defaults to the host architecture.
+    pub arch: Option<String>,
+    /// Additional directories to search for JDKs in.
+    #[serde(default)]
+    pub jdk_paths: Vec<PathBuf>,
+    /// Fails the build instead of warning if the instance does not match the project's `renkit.lock`.
+    #[serde(default)]
+    pub locked: bool,
+}
+
+impl RenutilOptions {
+    /// The install options an instance has to match to be used for this project.
+    #[must_use]
+    pub fn instance_options(&self) -> InstanceOptions {
+        InstanceOptions {
+            update_pickle: self.update_pickle,
+        }
+    }
 }
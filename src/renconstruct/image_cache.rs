@@ -0,0 +1,150 @@
+use crate::cache::{sha256_file, sha256_hex};
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// Changes whenever the encoders change their output for the same settings,
+/// so images converted by older versions are not reused.
+const CACHE_VERSION: u32 = 1;
+
+/// A content-addressed cache of converted images, shared by all projects. Entries are keyed by
+/// the hash of the source image and the encoder settings, the least recently used ones are
+/// evicted once the cache grows beyond its maximum size.
+#[derive(Debug, Clone)]
+pub struct ImageCache {
+    root: PathBuf,
+    max_size: u64,
+}
+
+impl ImageCache {
+    #[must_use]
+    pub fn new(root: PathBuf, max_size: u64) -> Self {
+        Self { root, max_size }
+    }
+
+    /// Opens the cache at `RENCONSTRUCT_IMAGE_CACHE_DIR`, or at `~/.cache/renkit/images` by default.
+    #[must_use]
+    pub fn open(max_size: u64) -> Self {
+        let root = env::var_os("RENCONSTRUCT_IMAGE_CACHE_DIR")
+            .filter(|p| !p.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| home::home_dir().map(|home| home.join(".cache/renkit/images")))
+            .expect("Unable to detect home directory.");
+        Self::new(root, max_size)
+    }
+
+    /// The key of a source image converted with the given encoder settings.
+    pub fn key(source: &Path, settings: &str) -> io::Result<String> {
+        Ok(sha256_hex(format!(
+            "{CACHE_VERSION}\n{settings}\n{}",
+            sha256_file(source)?
+        )))
+    }
+
+    fn entry_path(&self, key: &str) -> PathBuf {
+        self.root.join(&key[..2]).join(key)
+    }
+
+    /// Returns the cached image for the key and marks it as recently used.
+    #[must_use]
+    pub fn get(&self, key: &str) -> Option<PathBuf> {
+        let path = self.entry_path(key);
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .and_then(|file| file.set_modified(SystemTime::now()))
+            .ok()?;
+        Some(path)
+    }
+
+    /// Adds a converted image to the cache.
+    pub fn insert(&self, key: &str, image: &Path) -> io::Result<()> {
+        let path = self.entry_path(key);
+        fs::create_dir_all(path.parent().unwrap())?;
+        let temp = path.with_extension(format!("{}.tmp", rand::random::<u32>()));
+        if let Err(e) = fs::copy(image, &temp).and_then(|_| fs::rename(&temp, &path)) {
+            let _ = fs::remove_file(&temp);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Evicts the least recently used images until the cache fits its maximum size
+    /// and returns the number of bytes freed.
+    pub fn prune(&self) -> io::Result<u64> {
+        if !self.root.exists() {
+            return Ok(0);
+        }
+
+        let mut entries = vec![];
+        for dir in fs::read_dir(&self.root)? {
+            let dir = dir?;
+            if !dir.file_type()?.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(dir.path())? {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                entries.push((metadata.modified()?, metadata.len(), entry.path()));
+            }
+        }
+
+        let mut size = entries.iter().map(|(_, len, _)| len).sum::<u64>();
+        let mut freed = 0;
+        entries.sort();
+        for (_, len, path) in entries {
+            if size <= self.max_size {
+                break;
+            }
+            fs::remove_file(path)?;
+            size -= len;
+            freed += len;
+        }
+
+        Ok(freed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ImageCache;
+    use std::{
+        env, fs,
+        time::{Duration, SystemTime},
+    };
+
+    #[test]
+    fn cached_images() {
+        let root = env::temp_dir().join(format!("renkit-images-{}", rand::random::<u32>()));
+        let cache = ImageCache::new(root.join("cache"), 8);
+        fs::create_dir_all(&root).unwrap();
+        let source = root.join("bg.png");
+        fs::write(&source, "png").unwrap();
+
+        let lossless = ImageCache::key(&source, "webp lossless").unwrap();
+        let lossy = ImageCache::key(&source, "webp quality=90").unwrap();
+        assert_ne!(lossless, lossy);
+        assert!(cache.get(&lossless).is_none());
+
+        fs::write(root.join("bg.webp"), "webp").unwrap();
+        cache.insert(&lossless, &root.join("bg.webp")).unwrap();
+        let entry = cache.get(&lossless).unwrap();
+        assert_eq!(fs::read_to_string(&entry).unwrap(), "webp");
+        fs::File::options()
+            .write(true)
+            .open(&entry)
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_mins(1))
+            .unwrap();
+
+        fs::write(root.join("bg.avif"), "avif!").unwrap();
+        cache.insert(&lossy, &root.join("bg.avif")).unwrap();
+        assert_eq!(cache.prune().unwrap(), 4);
+        assert!(cache.get(&lossless).is_none());
+        assert!(cache.get(&lossy).is_some());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
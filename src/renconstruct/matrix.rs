@@ -0,0 +1,73 @@
+use anyhow::{Result, bail};
+use toml::{Table, Value};
+
+/// Splits a config with `renutil.versions` into one config per version, in the order they
+/// are listed. Configs with a single `renutil.version` are not a matrix and return `None`.
+pub fn expand_versions(config: &Table) -> Result<Option<Vec<(String, Table)>>> {
+    let Some(renutil) = config.get("renutil").and_then(|v| v.as_table()) else {
+        return Ok(None);
+    };
+    let Some(versions) = renutil.get("versions") else {
+        return Ok(None);
+    };
+
+    if renutil.contains_key("version") {
+        bail!("renutil.version and renutil.versions can not be used together");
+    }
+    let Some(versions) = versions.as_array() else {
+        bail!("renutil.versions must be a list of versions");
+    };
+    if versions.is_empty() {
+        bail!("renutil.versions must list at least one version");
+    }
+
+    let mut cells = vec![];
+    for version in versions {
+        let Some(version) = version.as_str() else {
+            bail!("renutil.versions must be a list of versions");
+        };
+        if cells.iter().any(|(v, _)| v == version) {
+            bail!("renutil.versions lists {version} more than once");
+        }
+
+        let mut cell = config.clone();
+        let renutil = cell["renutil"].as_table_mut().unwrap();
+        renutil.remove("versions");
+        renutil.insert("version".into(), Value::String(version.into()));
+        cells.push((version.to_string(), cell));
+    }
+
+    Ok(Some(cells))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::expand_versions;
+
+    #[test]
+    fn version_matrix() {
+        let config: toml::Table = toml::from_str(
+            r#"
+            [renutil]
+            versions = ["7.8.4", "8.3.4"]
+            update_pickle = false
+            "#,
+        )
+        .unwrap();
+
+        let cells = expand_versions(&config).unwrap().unwrap();
+        assert_eq!(
+            cells.iter().map(|(v, _)| v.as_str()).collect::<Vec<_>>(),
+            vec!["7.8.4", "8.3.4"]
+        );
+        assert_eq!(cells[0].1["renutil"]["version"].as_str(), Some("7.8.4"));
+        assert!(cells[1].1["renutil"].get("versions").is_none());
+
+        let single: toml::Table = toml::from_str("[renutil]\nversion = \"8.3.4\"").unwrap();
+        assert!(expand_versions(&single).unwrap().is_none());
+
+        let both: toml::Table =
+            toml::from_str("[renutil]\nversion = \"8.3.4\"\nversions = [\"8.3.4\"]").unwrap();
+        assert!(expand_versions(&both).is_err());
+    }
+}
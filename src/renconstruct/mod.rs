@@ -1,2 +1,8 @@
 pub mod config;
+pub mod image_cache;
+pub mod matrix;
+pub mod plan;
+pub mod profiles;
+pub mod staging;
 pub mod tasks;
+pub mod vars;
@@ -0,0 +1,845 @@
+use super::config::{
+    BuildOption, Config, DependsOn, KnownBuildOption, ResizeImagesOptions, TaskOptions,
+};
+use super::tasks::{Stage, Task};
+use crate::renotize::validate_signing_files;
+use crate::version::Version;
+use anyhow::{Result, anyhow};
+use base64::prelude::*;
+use itertools::Itertools;
+use std::{env, path::Path, str::FromStr};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TaskState {
+    Pending,
+    Running,
+    Done,
+}
+
+/// The tasks of a stage and the tasks each of them waits for. Tasks are started as soon as their
+/// dependencies completed, lower priorities first and otherwise by name.
+#[derive(Debug, Clone)]
+pub struct TaskGraph<'a> {
+    tasks: Vec<&'a Task>,
+    priorities: Vec<usize>,
+    dependencies: Vec<Vec<usize>>,
+    states: Vec<TaskState>,
+}
+
+impl<'a> TaskGraph<'a> {
+    /// Builds the graph of the tasks that run in the given stage, including the tasks they
+    /// always wait for. Dependencies on tasks that do not run in the stage are ignored,
+    /// circular dependencies are an error.
+    ///
+    /// If no task of the stage declares `depends_on`, every priority level waits for the level
+    /// before it, like in configs written before `depends_on` existed.
+    pub fn new(tasks: &'a [Task], stage: Stage) -> Result<Self> {
+        let tasks = tasks
+            .iter()
+            .filter(|task| task.runs_in(stage))
+            .sorted_by_key(|task| (task.priority(stage), &task.name))
+            .collect::<Vec<_>>();
+        let priorities = tasks
+            .iter()
+            .map(|task| task.priority(stage))
+            .collect::<Vec<_>>();
+        let levels = tasks.iter().all(|task| task.dependencies(stage).is_empty());
+
+        let dependencies = tasks
+            .iter()
+            .enumerate()
+            .map(|(i, task)| {
+                let previous_level = priorities
+                    .iter()
+                    .filter(|&&p| levels && p < priorities[i])
+                    .max();
+                task.dependencies(stage)
+                    .iter()
+                    .filter_map(|name| tasks.iter().position(|t| &t.name == name))
+                    .chain((0..tasks.len()).filter(|&d| task.waits_for(tasks[d])))
+                    // Resizing before converting images takes precedence over the priority levels.
+                    .chain((0..tasks.len()).filter(|&d| {
+                        Some(&priorities[d]) == previous_level && !tasks[d].waits_for(task)
+                    }))
+                    .unique()
+                    .collect()
+            })
+            .collect();
+
+        let graph = Self {
+            states: vec![TaskState::Pending; tasks.len()],
+            tasks,
+            priorities,
+            dependencies,
+        };
+
+        if let Some(cycle) = graph.find_cycle() {
+            return Err(anyhow!(
+                "Tasks depend on each other in the {stage} stage: {}",
+                cycle.iter().map(|&i| &graph.tasks[i].name).join(" -> ")
+            ));
+        }
+
+        Ok(graph)
+    }
+
+    /// Removes tasks without pending dependencies until none are left. Every remaining task
+    /// waits for another remaining one, so following them from any of them leads into a cycle.
+    fn find_cycle(&self) -> Option<Vec<usize>> {
+        let mut removed = vec![false; self.tasks.len()];
+        while let Some(i) = (0..self.tasks.len())
+            .find(|&i| !removed[i] && self.dependencies[i].iter().all(|&d| removed[d]))
+        {
+            removed[i] = true;
+        }
+
+        let mut path = vec![(0..self.tasks.len()).find(|&i| !removed[i])?];
+        loop {
+            let last = *path.last().unwrap();
+            let next = *self.dependencies[last]
+                .iter()
+                .find(|&&d| !removed[d])
+                .unwrap();
+            if let Some(start) = path.iter().position(|&i| i == next) {
+                let mut cycle = path.split_off(start);
+                cycle.push(next);
+                return Some(cycle);
+            }
+            path.push(next);
+        }
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    #[must_use]
+    pub fn is_done(&self) -> bool {
+        self.states.iter().all(|state| *state == TaskState::Done)
+    }
+
+    #[must_use]
+    pub fn running(&self) -> usize {
+        self.states
+            .iter()
+            .filter(|state| **state == TaskState::Running)
+            .count()
+    }
+
+    #[must_use]
+    pub fn dependencies(&self, i: usize) -> Vec<&'a Task> {
+        self.dependencies[i]
+            .iter()
+            .map(|&d| self.tasks[d])
+            .collect()
+    }
+
+    /// Starts the next task whose dependencies completed, if any can start while `jobs`
+    /// tasks may run at once. Tasks that do not run in parallel only start once no other
+    /// task is running and block all others until they are finished. Until then, only tasks
+    /// of the same or a lower priority that do run in parallel may start ahead of them.
+    pub fn next(&mut self, jobs: usize) -> Option<(usize, &'a Task)> {
+        let running = self.running();
+        if running >= jobs.max(1)
+            || (0..self.tasks.len())
+                .any(|i| self.states[i] == TaskState::Running && !self.tasks[i].runs_in_parallel())
+        {
+            return None;
+        }
+
+        let mut waiting = None;
+        let i = (0..self.tasks.len()).find(|&i| {
+            let ready = self.states[i] == TaskState::Pending
+                && waiting.is_none_or(|priority| self.priorities[i] <= priority)
+                && self.dependencies[i]
+                    .iter()
+                    .all(|&d| self.states[d] == TaskState::Done);
+            if ready && running > 0 && !self.tasks[i].runs_in_parallel() {
+                waiting = Some(self.priorities[i]);
+                return false;
+            }
+            ready
+        })?;
+
+        self.states[i] = TaskState::Running;
+        Some((i, self.tasks[i]))
+    }
+
+    pub fn finish(&mut self, i: usize) {
+        self.states[i] = TaskState::Done;
+    }
+
+    /// The order tasks run in without a limit on jobs, as the indices of the tasks
+    /// that are started together.
+    #[must_use]
+    pub fn batches(&self) -> Vec<Vec<usize>> {
+        let mut graph = self.clone();
+        let mut batches = vec![];
+        loop {
+            let mut batch = vec![];
+            while let Some((i, _)) = graph.next(usize::MAX) {
+                batch.push(i);
+            }
+            if batch.is_empty() {
+                return batches;
+            }
+            for &i in &batch {
+                graph.finish(i);
+            }
+            batches.push(batch);
+        }
+    }
+
+    #[must_use]
+    pub fn task(&self, i: usize) -> &'a Task {
+        self.tasks[i]
+    }
+}
+
+/// The Ren'Py invocations that build the enabled distributions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildStep {
+    AndroidApk,
+    AndroidAab,
+    Web,
+    Distribute,
+}
+
+impl BuildStep {
+    #[must_use]
+    pub fn description(&self) -> &'static str {
+        match self {
+            BuildStep::AndroidApk => "Building Android APK package.",
+            BuildStep::AndroidAab => "Building Android App Bundle package.",
+            BuildStep::Web => "Building Web package.",
+            BuildStep::Distribute => "Building other packages.",
+        }
+    }
+}
+
+/// The name of every enabled build, as used by `on_builds`.
+#[must_use]
+pub fn enabled_builds(config: &Config) -> Vec<String> {
+    config
+        .builds
+        .iter()
+        .filter(|(_, enabled)| **enabled)
+        .map(|(build, _)| match build {
+            BuildOption::Known(build) => build.to_string(),
+            BuildOption::Custom(build) => build.clone(),
+        })
+        .sorted()
+        .collect()
+}
+
+/// The Ren'Py commands that build the enabled distributions, in the order they run in.
+#[must_use]
+pub fn build_commands(
+    config: &Config,
+    input_dir: &Path,
+    output_dir: &Path,
+) -> Vec<(BuildStep, Vec<String>)> {
+    let input_dir = input_dir.to_string_lossy().to_string();
+    let output_dir_str = output_dir.to_string_lossy().to_string();
+    let supports_bundles = config.renutil.version >= Version::from_str("7.5.0").unwrap();
+
+    let mut commands = vec![];
+
+    if config.is_enabled(KnownBuildOption::AndroidApk) {
+        let args = if supports_bundles {
+            vec![
+                "android_build".into(),
+                input_dir.clone(),
+                "--dest".into(),
+                output_dir_str.clone(),
+            ]
+        } else {
+            vec![
+                "android_build".into(),
+                input_dir.clone(),
+                "assembleRelease".into(),
+                "--dest".into(),
+                output_dir_str.clone(),
+            ]
+        };
+        commands.push((BuildStep::AndroidApk, args));
+    }
+
+    if config.is_enabled(KnownBuildOption::AndroidAab) && supports_bundles {
+        commands.push((
+            BuildStep::AndroidAab,
+            vec![
+                "android_build".into(),
+                input_dir.clone(),
+                "--bundle".into(),
+                "--dest".into(),
+                output_dir_str.clone(),
+            ],
+        ));
+    }
+
+    if config.is_enabled(KnownBuildOption::Web) {
+        commands.push((
+            BuildStep::Web,
+            vec![
+                "web_build".into(),
+                input_dir.clone(),
+                "--dest".into(),
+                output_dir.join("web").to_string_lossy().to_string(),
+            ],
+        ));
+    }
+
+    let packages = enabled_builds(config)
+        .into_iter()
+        .filter(|build| !["android_apk", "android_aab", "web"].contains(&build.as_str()))
+        .collect::<Vec<_>>();
+    if !packages.is_empty() {
+        let mut args = vec![
+            "distribute".into(),
+            input_dir,
+            "--destination".into(),
+            output_dir_str,
+        ];
+        for package in packages {
+            args.push("--package".into());
+            args.push(package);
+        }
+        commands.push((BuildStep::Distribute, args));
+    }
+
+    commands
+}
+
+/// Checks that the enabled builds are supported by the configured version of Ren'Py.
+pub fn check_builds(config: &Config) -> Result<()> {
+    if config.builds.is_empty() {
+        return Err(anyhow!("No build options enabled"));
+    }
+
+    if config.is_enabled(KnownBuildOption::Web)
+        && config.renutil.version < Version::from_str("8.2.0").unwrap()
+    {
+        return Err(anyhow!(
+            "Web build support requires Ren'Py 8.2.0 or higher."
+        ));
+    }
+
+    if config.is_enabled(KnownBuildOption::AndroidAab)
+        && config.renutil.version < Version::from_str("7.5.0").unwrap()
+    {
+        return Err(anyhow!(
+            "Android App Bundle build support requires Ren'Py 7.5.0 or higher."
+        ));
+    }
+
+    if config.is_enabled(KnownBuildOption::AndroidApk)
+        || config.is_enabled(KnownBuildOption::AndroidAab)
+    {
+        let has_keystore_task = config.tasks.iter().any(|(_, v)| match v.options {
+            TaskOptions::Keystore { .. } => v.enabled,
+            _ => false,
+        });
+
+        if !has_keystore_task {
+            return Err(anyhow!(
+                "Android build support requires a keystore task to be active."
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Lists every dependency of an enabled task on a task that is not configured at all.
+#[must_use]
+pub fn unknown_dependencies(config: &Config) -> Vec<String> {
+    let mut problems = vec![];
+
+    for (name, task) in config
+        .tasks
+        .iter()
+        .filter(|(_, task)| task.enabled)
+        .sorted_by_key(|(name, _)| *name)
+    {
+        let dependencies = match &task.depends_on {
+            DependsOn::All(tasks) => tasks.iter().collect::<Vec<_>>(),
+            DependsOn::Stages(stages) => {
+                stages.pre_build.iter().chain(&stages.post_build).collect()
+            }
+        };
+        for dependency in dependencies.into_iter().unique() {
+            if !config.tasks.contains_key(dependency) {
+                problems.push(format!(
+                    "Task '{name}' depends on '{dependency}', which does not exist."
+                ));
+            }
+        }
+    }
+
+    problems
+}
+
+/// Splits the enabled builds into groups that have their images resized by the same tasks,
+/// along with the names of those tasks. Every group is built from its own copy of the project,
+/// so the other builds never use images resized for some builds only.
+#[must_use]
+pub fn build_groups(config: &Config) -> Vec<(Vec<String>, Vec<String>)> {
+    let resize_tasks = config
+        .tasks
+        .iter()
+        .filter(|(_, task)| task.enabled && matches!(task.options, TaskOptions::ResizeImages(_)))
+        .sorted_by_key(|(name, _)| *name)
+        .collect::<Vec<_>>();
+
+    enabled_builds(config)
+        .into_iter()
+        .into_group_map_by(|build| {
+            resize_tasks
+                .iter()
+                .filter(|(_, task)| task.on_builds.is_empty() || task.on_builds.contains(build))
+                .map(|(name, _)| (*name).clone())
+                .collect::<Vec<_>>()
+        })
+        .into_iter()
+        .sorted_by(|(_, a), (_, b)| a.cmp(b))
+        .collect()
+}
+
+/// Lists problems with building the groups of [`build_groups`] separately. Building in place
+/// leaves no room for a copy of the project per group.
+#[must_use]
+pub fn resize_conflicts(config: &Config) -> Vec<String> {
+    let groups = build_groups(config);
+    if !config.options.build_in_place || groups.len() < 2 {
+        return vec![];
+    }
+
+    vec![format!(
+        "Images are resized for some builds only, which builds {} from separate copies of the project, but build_in_place is set.",
+        groups
+            .iter()
+            .map(|(_, builds)| builds.join(", "))
+            .join(" and ")
+    )]
+}
+
+/// Checks the paths and targets of a `resize_images` task before any image is resized.
+fn check_resize(name: &str, opts: &ResizeImagesOptions, input_dir: &Path) -> Vec<String> {
+    let mut problems = vec![];
+
+    for (path, target) in opts.paths.iter().sorted_by_key(|(path, _)| *path) {
+        if !input_dir.join(path).exists() {
+            problems.push(format!(
+                "Task '{name}' resizes images in {path}, which does not exist."
+            ));
+        }
+        if target.scale.is_none() && target.max_width.is_none() && target.max_height.is_none() {
+            problems.push(format!(
+                "Task '{name}' resizes images in {path}, but sets neither scale, max_width nor max_height."
+            ));
+        }
+        if target.extensions.iter().any(|ext| ext == "avif") {
+            problems.push(format!(
+                "Task '{name}' resizes images in {path}, but AVIF images can not be resized."
+            ));
+        }
+        if target
+            .scale
+            .is_some_and(|scale| scale <= 0.0 || scale > 1.0)
+        {
+            problems.push(format!(
+                "Task '{name}' resizes images in {path} with a scale outside of (0, 1]."
+            ));
+        }
+    }
+
+    problems
+}
+
+/// Lists the problems of all enabled `resize_images` tasks, see [`check_config`].
+#[must_use]
+pub fn resize_problems(config: &Config, input_dir: &Path) -> Vec<String> {
+    config
+        .tasks
+        .iter()
+        .filter(|(_, task)| task.enabled)
+        .sorted_by_key(|(name, _)| *name)
+        .flat_map(|(name, task)| match &task.options {
+            TaskOptions::ResizeImages(opts) => check_resize(name, opts, input_dir),
+            _ => vec![],
+        })
+        .collect()
+}
+
+/// Checks everything that would otherwise only fail partway through a build:
+/// build support, the builds tasks are restricted to and the files tasks reference.
+#[must_use]
+pub fn check_config(config: &Config, input_dir: &Path) -> Vec<String> {
+    let mut problems = vec![];
+
+    if !input_dir.exists() {
+        problems.push("Input directory does not exist".to_string());
+    }
+
+    if let Err(e) = check_builds(config) {
+        problems.push(e.to_string());
+    }
+    problems.extend(resize_conflicts(config));
+
+    if let Some(task_dir) = &config.options.task_dir
+        && !task_dir.exists()
+    {
+        problems.push("Task directory does not exist".to_string());
+    }
+
+    problems.extend(unknown_dependencies(config));
+
+    let builds = enabled_builds(config);
+
+    for (name, task) in config
+        .tasks
+        .iter()
+        .filter(|(_, task)| task.enabled)
+        .sorted_by_key(|(name, _)| *name)
+    {
+        for build in task.on_builds.iter().sorted() {
+            if !builds.contains(build) {
+                problems.push(format!(
+                    "Task '{name}' runs on build '{build}', which is not enabled."
+                ));
+            }
+        }
+
+        match &task.options {
+            TaskOptions::Lint(_) | TaskOptions::Keystore(_) if task.sandboxed => {
+                problems.push(format!("Task '{name}' can not be sandboxed."));
+            }
+            _ => {}
+        }
+
+        match &task.options {
+            TaskOptions::Keystore(opts) => {
+                for (var, value) in [
+                    ("RC_KEYSTORE_APK", &opts.keystore_apk),
+                    ("RC_KEYSTORE_AAB", &opts.keystore_aab),
+                ] {
+                    let value = env::var(var).unwrap_or_else(|_| value.clone());
+                    if let Err(e) = BASE64_STANDARD.decode(value) {
+                        problems.push(format!(
+                            "Task '{name}' has a keystore that is not valid base64 ({var}): {e}"
+                        ));
+                    }
+                }
+            }
+            TaskOptions::Notarize(opts) => {
+                for path in [&opts.key_file, &opts.cert_file, &opts.app_store_key_file] {
+                    if !path.exists() {
+                        problems.push(format!(
+                            "Task '{name}' references {}, which does not exist.",
+                            path.to_string_lossy()
+                        ));
+                    }
+                }
+                if [&opts.key_file, &opts.cert_file, &opts.app_store_key_file]
+                    .iter()
+                    .all(|path| path.exists())
+                    && let Err(e) = validate_signing_files(
+                        &opts.key_file,
+                        &opts.cert_file,
+                        &opts.app_store_key_file,
+                    )
+                {
+                    problems.push(format!(
+                        "Task '{name}' references invalid signing files: {e}"
+                    ));
+                }
+            }
+            TaskOptions::ConvertImages(opts) => {
+                for path in opts.paths.keys().sorted() {
+                    if !input_dir.join(path).exists() {
+                        problems.push(format!(
+                            "Task '{name}' converts images in {path}, which does not exist."
+                        ));
+                    }
+                }
+            }
+            TaskOptions::ResizeImages(opts) => {
+                problems.extend(check_resize(name, opts, input_dir));
+            }
+            TaskOptions::Custom(_) if config.options.task_dir.is_none() => {
+                problems.push(format!(
+                    "Task '{name}' is a custom task, but no task_dir is configured."
+                ));
+            }
+            TaskOptions::Lint(_) | TaskOptions::Custom(_) => {}
+        }
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TaskGraph, build_groups, resize_conflicts, resize_problems};
+    use crate::renconstruct::{
+        config::{Config, GeneralTaskOptions},
+        tasks::{Stage, Task},
+    };
+    use std::{collections::HashMap, path::Path};
+
+    #[test]
+    fn task_graph() {
+        let config = r#"
+            [lint]
+            type = "lint"
+            enabled = true
+
+            [keystore]
+            type = "keystore"
+            enabled = true
+            keystore_apk = ""
+            keystore_aab = ""
+            priorities = { pre_build = 1 }
+
+            [images]
+            type = "convert_images"
+            enabled = true
+            sandboxed = true
+            format = "webp"
+            paths = {}
+            depends_on = ["lint"]
+
+            [audio]
+            type = "convert_images"
+            enabled = true
+            sandboxed = true
+            format = "webp"
+            paths = {}
+            depends_on = { pre_build = ["lint"] }
+            "#;
+        let tasks = |config: &str| {
+            toml::from_str::<HashMap<String, GeneralTaskOptions>>(config)
+                .unwrap()
+                .into_iter()
+                .map(|(name, kind)| Task { name, kind })
+                .collect::<Vec<_>>()
+        };
+
+        let valid = tasks(config);
+        let graph = TaskGraph::new(&valid, Stage::PreBuild).unwrap();
+        let batches = graph
+            .batches()
+            .into_iter()
+            .map(|batch| batch.into_iter().map(|i| &graph.task(i).name).collect())
+            .collect::<Vec<Vec<_>>>();
+        assert_eq!(
+            batches,
+            vec![vec!["lint"], vec!["audio", "images"], vec!["keystore"]]
+        );
+
+        let mut graph = TaskGraph::new(&valid, Stage::PreBuild).unwrap();
+        let (lint, _) = graph.next(2).unwrap();
+        assert!(graph.next(2).is_none());
+        graph.finish(lint);
+        let (audio, _) = graph.next(1).unwrap();
+        assert!(graph.next(1).is_none());
+        graph.finish(audio);
+        let (images, _) = graph.next(2).unwrap();
+        assert!(graph.next(2).is_none());
+        graph.finish(images);
+        assert_eq!(graph.next(2).unwrap().1.name, "keystore");
+
+        let mixed = tasks(
+            r#"
+            [early]
+            type = "convert_images"
+            enabled = true
+            sandboxed = true
+            format = "webp"
+            paths = {}
+
+            [other]
+            type = "convert_images"
+            enabled = true
+            sandboxed = true
+            format = "webp"
+            paths = {}
+
+            [lint]
+            type = "lint"
+            enabled = true
+            priorities = { pre_build = 1 }
+
+            [late]
+            type = "convert_images"
+            enabled = true
+            sandboxed = true
+            format = "webp"
+            paths = {}
+            priorities = { pre_build = 2 }
+            depends_on = ["early"]
+            "#,
+        );
+        let mut graph = TaskGraph::new(&mixed, Stage::PreBuild).unwrap();
+        let (early, _) = graph.next(4).unwrap();
+        let (other, _) = graph.next(4).unwrap();
+        assert!(graph.next(4).is_none());
+        graph.finish(early);
+        // Late is ready, but lint comes first and waits for other to finish.
+        assert!(graph.next(4).is_none());
+        graph.finish(other);
+        let (lint, task) = graph.next(4).unwrap();
+        assert_eq!(task.name, "lint");
+        assert!(graph.next(4).is_none());
+        graph.finish(lint);
+        assert_eq!(graph.next(4).unwrap().1.name, "late");
+
+        // Without depends_on, every priority level waits for the one before it.
+        let levels = tasks(
+            r#"
+            [first]
+            type = "convert_images"
+            enabled = true
+            sandboxed = true
+            format = "webp"
+            paths = {}
+
+            [second]
+            type = "convert_images"
+            enabled = true
+            sandboxed = true
+            format = "webp"
+            paths = {}
+            priorities = { pre_build = 1 }
+            "#,
+        );
+        let mut graph = TaskGraph::new(&levels, Stage::PreBuild).unwrap();
+        let (first, task) = graph.next(2).unwrap();
+        assert_eq!(task.name, "first");
+        assert!(graph.next(2).is_none());
+        graph.finish(first);
+        assert_eq!(graph.next(2).unwrap().1.name, "second");
+
+        let circular = tasks(&config.replace("[lint]", "[lint]\ndepends_on = [\"images\"]"));
+        assert_eq!(
+            TaskGraph::new(&circular, Stage::PreBuild)
+                .unwrap_err()
+                .to_string(),
+            "Tasks depend on each other in the pre-build stage: lint -> images -> lint"
+        );
+    }
+
+    #[test]
+    fn unknown_config_keys() {
+        let config = r#"
+            [builds]
+            pc = true
+
+            [renutil]
+            version = "8.3.4"
+
+            [tasks.lint]
+            type = "lint"
+            enabled = true
+            sandbox = true
+            priorities = { pre_build = 1 }
+
+            [tasks.images]
+            type = "convert_images"
+            enabled = true
+            format = "webp"
+            paths = { "game/images" = { recursive = true, lossless = true } }
+
+            [tasks.custom]
+            type = "custom"
+            enabled = true
+            anything = 1
+            "#;
+        let parse = |config: &str| {
+            toml::Value::Table(toml::from_str(config).unwrap())
+                .try_into::<Config>()
+                .map_err(|e| e.to_string())
+        };
+
+        assert!(parse(config).is_ok());
+        for (key, typo, error) in [
+            ("version =", "verison =", "unknown field `verison`"),
+            ("lossless", "lossles", "unknown field `lossles`"),
+            ("pre_build = 1", "prebuild = 1", "unknown field `prebuild`"),
+            (
+                "sandbox",
+                "on_build = []\nsandbox",
+                "unknown field `on_build`",
+            ),
+        ] {
+            assert!(
+                parse(&config.replacen(key, typo, 1))
+                    .unwrap_err()
+                    .starts_with(error)
+            );
+        }
+    }
+
+    #[test]
+    fn resized_build_groups() {
+        let config = r#"
+            [builds]
+            android_apk = true
+            android_aab = true
+            web = true
+            pc = true
+
+            [renutil]
+            version = "8.3.4"
+
+            [tasks.mobile]
+            type = "resize_images"
+            enabled = true
+            paths = { "game/images" = { scale = 0.5 } }
+            on_builds = ["android_apk", "android_aab"]
+
+            [tasks.web]
+            type = "resize_images"
+            enabled = true
+            paths = { "game/images" = { scale = 0.5 } }
+            on_builds = ["web"]
+            "#;
+        let parse = |config: &str| {
+            toml::Value::Table(toml::from_str(config).unwrap())
+                .try_into::<Config>()
+                .unwrap()
+        };
+
+        assert_eq!(
+            resize_problems(
+                &parse(&config.replacen("scale = 0.5", "scale = 2.0", 1)),
+                Path::new(env!("CARGO_MANIFEST_DIR")),
+            ),
+            vec![
+                "Task 'mobile' resizes images in game/images, which does not exist.",
+                "Task 'mobile' resizes images in game/images with a scale outside of (0, 1].",
+                "Task 'web' resizes images in game/images, which does not exist.",
+            ]
+        );
+
+        let config = parse(config);
+        assert_eq!(
+            build_groups(&config),
+            vec![
+                (
+                    vec!["mobile".to_string()],
+                    vec!["android_aab".to_string(), "android_apk".to_string()]
+                ),
+                (vec![], vec!["pc".to_string()]),
+                (vec!["web".to_string()], vec!["web".to_string()]),
+            ]
+        );
+        assert!(resize_conflicts(&config).is_empty());
+
+        let mut in_place = config;
+        in_place.options.build_in_place = true;
+        assert_eq!(resize_conflicts(&in_place).len(), 1);
+    }
+}
@@ -0,0 +1,185 @@
+use crate::common::canonicalize_normalized;
+use anyhow::{Context, Result, anyhow, bail};
+use itertools::Itertools;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use toml::{Table, Value};
+
+/// Merges `overrides` into `base`. Tables are merged recursively, all other values
+/// including arrays are replaced.
+pub fn merge(base: &mut Table, overrides: Table) {
+    for (key, value) in overrides {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base)), Value::Table(overrides)) => merge(base, overrides),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Renames the `build` alias to `builds`, so configs using either of them can be merged.
+fn normalize_builds(config: &mut Table) {
+    if !config.contains_key("builds")
+        && let Some(builds) = config.remove("build")
+    {
+        config.insert("builds".into(), builds);
+    }
+}
+
+fn read_included(path: &Path, stack: &mut Vec<PathBuf>) -> Result<Table> {
+    let canonical = canonicalize_normalized(path)
+        .map_err(|_| anyhow!("Config file does not exist: {}", path.to_string_lossy()))?;
+    if stack.contains(&canonical) {
+        bail!(
+            "Config files include each other: {} -> {}",
+            stack.iter().map(|p| p.to_string_lossy()).join(" -> "),
+            canonical.to_string_lossy()
+        );
+    }
+
+    let mut config: Table = toml::from_str(&fs::read_to_string(path)?)
+        .with_context(|| format!("Invalid config file {}", path.to_string_lossy()))?;
+    normalize_builds(&mut config);
+
+    let includes = match config.remove("include") {
+        None => vec![],
+        Some(Value::Array(includes)) => includes
+            .into_iter()
+            .map(|include| match include {
+                Value::String(include) => Ok(include),
+                _ => Err(anyhow!("include must be a list of paths")),
+            })
+            .collect::<Result<Vec<_>>>()?,
+        Some(_) => bail!("include must be a list of paths"),
+    };
+
+    stack.push(canonical);
+    let mut merged = Table::new();
+    for include in includes {
+        let path = path.parent().unwrap_or(Path::new(".")).join(include);
+        merge(&mut merged, read_included(&path, stack)?);
+    }
+    merge(&mut merged, config);
+    stack.pop();
+
+    Ok(merged)
+}
+
+/// Reads a config file and merges it over the files it includes, which are read in order.
+/// Includes are relative to the file that includes them and may include other files.
+pub fn read_with_includes(path: &Path) -> Result<Table> {
+    read_included(path, &mut vec![])
+}
+
+/// Removes the profiles of a config and merges the selected one over it.
+pub fn apply_profile(config: &mut Table, profile: Option<&str>) -> Result<()> {
+    let profiles = match config.remove("profiles") {
+        Some(Value::Table(profiles)) => profiles,
+        Some(_) => bail!("profiles must be a table of profiles"),
+        None => Table::new(),
+    };
+
+    let Some(name) = profile else {
+        return Ok(());
+    };
+
+    match profiles.get(name) {
+        Some(Value::Table(overrides)) => {
+            let mut overrides = overrides.clone();
+            normalize_builds(&mut overrides);
+            merge(config, overrides);
+            Ok(())
+        }
+        Some(_) => bail!("Profile {name} must be a table"),
+        None if profiles.is_empty() => bail!("Unknown profile {name}, the config has no profiles"),
+        None => bail!(
+            "Unknown profile {name}, available profiles: {}",
+            profiles.keys().join(", ")
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply_profile, read_with_includes};
+    use std::{env, fs};
+
+    #[test]
+    fn profiles_and_includes() {
+        let dir = env::temp_dir().join(format!("renkit-profiles-{}", rand::random::<u32>()));
+        fs::create_dir_all(dir.join("ci")).unwrap();
+        fs::write(
+            dir.join("ci/base.toml"),
+            r#"
+            [build]
+            pc = true
+            mac = true
+
+            [renutil]
+            version = "8.3.4"
+
+            [tasks.notarize]
+            type = "notarize"
+            enabled = true
+            on_builds = ["mac"]
+
+            [profiles.demo.builds]
+            mac = false
+            "#,
+        )
+        .unwrap();
+        fs::write(
+            dir.join("renconstruct.toml"),
+            r#"
+            include = ["ci/base.toml"]
+
+            [renutil]
+            update_pickle = true
+
+            [profiles.demo.tasks.notarize]
+            enabled = false
+
+            [profiles.release.renutil]
+            version = "8.3.7"
+            "#,
+        )
+        .unwrap();
+
+        let config = read_with_includes(&dir.join("renconstruct.toml")).unwrap();
+        assert_eq!(config["renutil"]["version"].as_str(), Some("8.3.4"));
+        assert_eq!(config["renutil"]["update_pickle"].as_bool(), Some(true));
+        assert!(config.get("include").is_none());
+
+        let mut demo = config.clone();
+        apply_profile(&mut demo, Some("demo")).unwrap();
+        assert_eq!(demo["builds"]["mac"].as_bool(), Some(false));
+        assert_eq!(demo["builds"]["pc"].as_bool(), Some(true));
+        assert_eq!(demo["tasks"]["notarize"]["enabled"].as_bool(), Some(false));
+        assert_eq!(demo["tasks"]["notarize"]["type"].as_str(), Some("notarize"));
+        assert!(demo.get("profiles").is_none());
+
+        assert_eq!(
+            apply_profile(&mut config.clone(), Some("nightly"))
+                .unwrap_err()
+                .to_string(),
+            "Unknown profile nightly, available profiles: demo, release"
+        );
+
+        fs::write(
+            dir.join("ci/base.toml"),
+            "include = [\"../renconstruct.toml\"]",
+        )
+        .unwrap();
+        assert!(
+            read_with_includes(&dir.join("renconstruct.toml"))
+                .unwrap_err()
+                .to_string()
+                .starts_with("Config files include each other")
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,326 @@
+use crate::common::canonicalize_normalized;
+use anyhow::{Context, Result};
+use regex::Regex;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// The directory within the output directory that holds the staging copy of the project.
+pub const STAGING_DIR: &str = ".renconstruct-staging";
+
+/// The directory within the output directory that holds the overlay of the Ren'Py instance.
+pub const OVERLAY_DIR: &str = ".renconstruct-instance";
+
+/// Entries of `rapt` that are linked into the overlay instead of copied, since they are large
+/// and never written to while building. Everything else in `rapt` is copied.
+const LINKED_RAPT_ENTRIES: [&str; 2] = ["Sdk", "renpy"];
+
+/// Paths that are never copied into the staging directory: version control, files Ren'Py
+/// writes while running and the backups `keystore` leaves behind.
+const DEFAULT_IGNORES: [&str; 9] = [
+    ".git",
+    ".hg",
+    ".svn",
+    "/game/cache",
+    "/game/saves",
+    "/log.txt",
+    "/traceback.txt",
+    "/errors.txt",
+    "*.original",
+];
+
+/// Translates a gitignore-style pattern into a regex matching relative paths.
+/// Patterns containing a `/` are relative to the project root, all others match
+/// files and directories with that name anywhere in the project.
+fn pattern_regex(pattern: &str) -> Result<Regex> {
+    let pattern = pattern.trim().trim_end_matches('/');
+    let anchored = pattern.contains('/');
+    let mut rest = pattern.trim_start_matches('/');
+
+    let mut regex = String::from(if anchored { "^" } else { "^(?:.*/)?" });
+    while let Some(c) = rest.chars().next() {
+        if let Some(after) = rest.strip_prefix("**/") {
+            regex.push_str("(?:.*/)?");
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("**") {
+            regex.push_str(".*");
+            rest = after;
+        } else {
+            match c {
+                '*' => regex.push_str("[^/]*"),
+                '?' => regex.push_str("[^/]"),
+                c => regex.push_str(&regex::escape(&c.to_string())),
+            }
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    regex.push('$');
+
+    Regex::new(&regex).with_context(|| format!("Invalid ignore pattern: {pattern}"))
+}
+
+/// Copies a file with a reflink if the file system supports it. Otherwise the file is copied,
+/// since tasks writing to a linked file in place would modify the project.
+fn stage_file(from: &Path, to: &Path) -> io::Result<()> {
+    if reflink_copy::reflink(from, to).is_ok() {
+        return Ok(());
+    }
+    let _ = fs::remove_file(to);
+    fs::copy(from, to).map(|_| ())
+}
+
+fn stage_dir(from: &Path, to: &Path, relative: &Path, ignores: &[Regex]) -> Result<()> {
+    fs::create_dir_all(to)?;
+
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let relative = relative.join(entry.file_name());
+        let relative_str = relative.to_string_lossy().replace('\\', "/");
+        if ignores.iter().any(|ignore| ignore.is_match(&relative_str)) {
+            continue;
+        }
+
+        let from = entry.path();
+        let to = to.join(entry.file_name());
+        if fs::metadata(&from)?.is_dir() {
+            stage_dir(&from, &to, &relative, ignores)?;
+        } else {
+            stage_file(&from, &to)
+                .with_context(|| format!("Unable to stage {}", from.to_string_lossy()))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// A copy of the project that builds run in, so tasks and Ren'Py never modify the project
+/// itself. The copy is removed when this is dropped.
+#[derive(Debug)]
+pub struct Staging {
+    root: PathBuf,
+    /// The project directory within the staging directory, named like the project.
+    pub project: PathBuf,
+}
+
+impl Staging {
+    /// Copies `input_dir` into the staging directory within `output_dir`, leaving out
+    /// the default ignores, `ignore` and the output directory itself.
+    pub fn create(input_dir: &Path, output_dir: &Path, ignore: &[String]) -> Result<Self> {
+        let input_dir = canonicalize_normalized(input_dir)?;
+        let root = canonicalize_normalized(output_dir)?.join(STAGING_DIR);
+
+        let mut ignores = DEFAULT_IGNORES
+            .iter()
+            .copied()
+            .chain(ignore.iter().map(String::as_str))
+            .map(pattern_regex)
+            .collect::<Result<Vec<_>>>()?;
+        // The output directory may be inside of the project, but never belongs to it.
+        if let Ok(output) = root.parent().unwrap().strip_prefix(&input_dir) {
+            ignores.push(pattern_regex(&format!(
+                "/{}",
+                output.to_string_lossy().replace('\\', "/")
+            ))?);
+        }
+
+        if root.exists() {
+            fs::remove_dir_all(&root)?;
+        }
+        let project = root.join(input_dir.file_name().unwrap_or("project".as_ref()));
+        let staging = Self { root, project };
+        stage_dir(&input_dir, &staging.project, Path::new(""), &ignores)?;
+
+        Ok(staging)
+    }
+}
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+#[cfg(target_family = "unix")]
+fn link_entry(from: &Path, to: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(from, to)
+}
+
+#[cfg(target_family = "windows")]
+fn link_entry(from: &Path, to: &Path) -> io::Result<()> {
+    if fs::metadata(from)?.is_dir() {
+        std::os::windows::fs::symlink_dir(from, to)
+    } else {
+        std::os::windows::fs::symlink_file(from, to)
+    }
+}
+
+/// A copy of a Ren'Py instance that Android packages are built with, since building them writes
+/// to RAPT's project and keystores. Only `renpy.py` and `rapt` are copied and everything else links
+/// back to the instance, so instances of shared or read-only registries can be built with as well.
+/// The overlay is a registry of its own, holding just this instance. It is removed when this is dropped.
+#[derive(Debug)]
+pub struct InstanceOverlay {
+    /// The registry to look the instance up in, which falls back to the other registries.
+    pub registry: PathBuf,
+    /// The directory of the instance within the overlay.
+    pub path: PathBuf,
+}
+
+impl InstanceOverlay {
+    /// Creates the overlay of the instance at `instance_path`, named `name`, within `output_dir`.
+    pub fn create(instance_path: &Path, name: &str, output_dir: &Path) -> Result<Self> {
+        let registry = canonicalize_normalized(output_dir)?.join(OVERLAY_DIR);
+        if registry.exists() {
+            fs::remove_dir_all(&registry)?;
+        }
+        let overlay = Self {
+            path: registry.join(name),
+            registry,
+        };
+        fs::create_dir_all(&overlay.path)?;
+
+        for entry in fs::read_dir(instance_path)? {
+            let entry = entry?;
+            let from = entry.path();
+            let to = overlay.path.join(entry.file_name());
+            if entry.file_name() == "rapt" {
+                fs::create_dir_all(&to)?;
+                for entry in fs::read_dir(&from)? {
+                    let entry = entry?;
+                    let from = entry.path();
+                    let to = to.join(entry.file_name());
+                    if LINKED_RAPT_ENTRIES.iter().any(|e| entry.file_name() == *e) {
+                        link_entry(&from, &to)?;
+                    } else if fs::metadata(&from)?.is_dir() {
+                        stage_dir(&from, &to, Path::new(""), &[])?;
+                    } else {
+                        stage_file(&from, &to)?;
+                    }
+                }
+            } else if entry.file_name() == "renpy.py" {
+                // Ren'Py derives its base directory, and with it the location of RAPT, from its entrypoint.
+                stage_file(&from, &to)?;
+            } else {
+                link_entry(&from, &to)?;
+            }
+        }
+
+        Ok(overlay)
+    }
+}
+
+impl Drop for InstanceOverlay {
+    fn drop(&mut self) {
+        // Links are removed without following them, so the instance itself stays untouched.
+        let _ = fs::remove_dir_all(&self.registry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InstanceOverlay, OVERLAY_DIR, STAGING_DIR, Staging};
+    use crate::dedupe::write_unshared;
+    use std::{env, fs, io::Write};
+
+    #[test]
+    fn staged_project() {
+        let dir = env::temp_dir().join(format!("renkit-staging-{}", rand::random::<u32>()));
+        let project = dir.join("my-game");
+        fs::create_dir_all(project.join("game/images/raw")).unwrap();
+        fs::create_dir_all(project.join("game/cache")).unwrap();
+        fs::write(project.join("game/script.rpy"), "label start:").unwrap();
+        fs::write(project.join("game/images/bg.png"), "png").unwrap();
+        fs::write(project.join("game/images/raw/bg.psd"), "psd").unwrap();
+        fs::write(project.join("game/cache/bytecode.rpyb"), "cache").unwrap();
+
+        let output = project.join("out");
+        fs::create_dir_all(&output).unwrap();
+
+        let staging = Staging::create(&project, &output, &["game/images/raw".into()]).unwrap();
+        let staged = output.join(STAGING_DIR).join("my-game");
+        assert_eq!(staging.project.file_name(), staged.file_name());
+        assert!(staged.join("game/script.rpy").exists());
+        assert!(!staged.join("game/images/raw").exists());
+        assert!(!staged.join("game/cache").exists());
+        assert!(!staged.join("out").exists());
+
+        write_unshared(&staged.join("game/images/bg.png"), "webp").unwrap();
+        fs::OpenOptions::new()
+            .append(true)
+            .open(staged.join("game/images/bg.png"))
+            .unwrap()
+            .write_all(b"!")
+            .unwrap();
+        fs::write(staged.join("game/script.rpy"), "label end:").unwrap();
+        assert_eq!(
+            fs::read_to_string(project.join("game/images/bg.png")).unwrap(),
+            "png"
+        );
+        assert_eq!(
+            fs::read_to_string(project.join("game/script.rpy")).unwrap(),
+            "label start:"
+        );
+
+        drop(staging);
+        assert!(!output.join(STAGING_DIR).exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn instance_overlay() {
+        let dir = env::temp_dir().join(format!("renkit-overlay-{}", rand::random::<u32>()));
+        let instance = dir.join("registry/8.3.4");
+        fs::create_dir_all(instance.join("renpy")).unwrap();
+        fs::create_dir_all(instance.join("rapt/Sdk/platforms")).unwrap();
+        fs::create_dir_all(instance.join("rapt/project")).unwrap();
+        fs::write(instance.join("renpy.py"), "import renpy").unwrap();
+        fs::write(
+            instance.join("rapt/project/local.properties"),
+            "key.alias=android",
+        )
+        .unwrap();
+        fs::write(instance.join("rapt/android.keystore"), "keystore").unwrap();
+
+        let output = dir.join("out");
+        fs::create_dir_all(&output).unwrap();
+        let overlay = InstanceOverlay::create(&instance, "8.3.4", &output).unwrap();
+        assert_eq!(overlay.path, overlay.registry.join("8.3.4"));
+
+        let is_link = |path: &str| {
+            fs::symlink_metadata(overlay.path.join(path))
+                .unwrap()
+                .file_type()
+                .is_symlink()
+        };
+        assert!(is_link("renpy"));
+        assert!(is_link("rapt/Sdk"));
+        assert!(!is_link("renpy.py"));
+        assert!(!is_link("rapt"));
+        assert!(!is_link("rapt/project/local.properties"));
+
+        fs::write(
+            overlay.path.join("rapt/project/local.properties"),
+            "key.alias=custom",
+        )
+        .unwrap();
+        fs::write(overlay.path.join("rapt/android.keystore"), "custom").unwrap();
+        fs::write(overlay.path.join("rapt/bundle.keystore"), "custom").unwrap();
+        assert_eq!(
+            fs::read_to_string(instance.join("rapt/project/local.properties")).unwrap(),
+            "key.alias=android"
+        );
+        assert_eq!(
+            fs::read_to_string(instance.join("rapt/android.keystore")).unwrap(),
+            "keystore"
+        );
+        assert!(!instance.join("rapt/bundle.keystore").exists());
+
+        drop(overlay);
+        assert!(!output.join(OVERLAY_DIR).exists());
+        assert!(instance.join("rapt/Sdk/platforms").exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
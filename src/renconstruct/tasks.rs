@@ -1,11 +1,19 @@
-use super::config::{
-    ConvertImagesOptions, GeneralTaskOptions, ImageFormat, KeystoreOptions, LintOptions,
-    NotarizeOptions,
+use super::{
+    config::{
+        ConvertImagesOptions, DependsOn, GeneralTaskOptions, ImageFormat, KeystoreOptions,
+        LintOptions, NotarizeOptions, ResizeFilter, ResizeImagesOptions, TaskOptions,
+    },
+    image_cache::ImageCache,
 };
 use crate::{
-    common::canonicalize_normalized, renotize::full_run, renutil::launch, version::Version,
+    common::canonicalize_normalized,
+    dedupe::{copy_unshared, write_unshared},
+    lock::LockCheck,
+    renotize::full_run,
+    renutil::{InstanceOptions, Sandbox, launch},
+    version::Version,
 };
-use anyhow::{Result, anyhow, bail};
+use anyhow::{Context, Result, anyhow, bail};
 use base64::prelude::*;
 use command_executor::{
     command::Command, shutdown_mode::ShutdownMode, thread_pool_builder::ThreadPoolBuilder,
@@ -14,17 +22,24 @@ use imgref::ImgRef;
 use indicatif::{ProgressBar, ProgressStyle};
 // use jpegxl_rs::encode::{EncoderFrame, EncoderResult, EncoderSpeed};
 // use jpegxl_rs::encoder_builder;
-use image::{EncodableLayout, ImageReader};
+use image::{EncodableLayout, ImageReader, codecs::jpeg::JpegEncoder, imageops::FilterType};
 use jwalk::WalkDir;
 use ravif::Encoder;
 use rgb::FromSlice;
+use rustpython_vm::PyObjectRef;
 use serde_json::Value;
 use std::{
     collections::HashMap,
-    env, fs,
-    io::Read,
+    env,
+    fmt::Display,
+    fs,
+    io::{Cursor, Read},
     path::{Path, PathBuf},
     str::FromStr,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
     thread,
 };
 
@@ -34,14 +49,104 @@ pub struct Task {
     pub kind: GeneralTaskOptions,
 }
 
+/// The stages tasks run in, before and after Ren'Py builds the distributions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    PreBuild,
+    PostBuild,
+}
+
+impl Display for Stage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Stage::PreBuild => write!(f, "pre-build"),
+            Stage::PostBuild => write!(f, "post-build"),
+        }
+    }
+}
+
+impl Task {
+    /// Whether the task does any work in the given stage.
+    /// Custom tasks only run in the stages their class implements a handler for.
+    #[must_use]
+    pub fn runs_in(&self, stage: Stage) -> bool {
+        match (&self.kind.options, stage) {
+            (TaskOptions::Keystore(_), _)
+            | (
+                TaskOptions::Lint(_) | TaskOptions::ConvertImages(_) | TaskOptions::ResizeImages(_),
+                Stage::PreBuild,
+            )
+            | (TaskOptions::Notarize(_), Stage::PostBuild) => true,
+            (TaskOptions::Custom(_), stage) => self.handler(stage).is_some(),
+            _ => false,
+        }
+    }
+
+    /// The Python method a custom task runs in the given stage, if its class implements one.
+    #[must_use]
+    pub fn handler(&self, stage: Stage) -> Option<&PyObjectRef> {
+        match (&self.kind.options, stage) {
+            (TaskOptions::Custom(opts), Stage::PreBuild) => opts.task_handle_pre.as_ref(),
+            (TaskOptions::Custom(opts), Stage::PostBuild) => opts.task_handle_post.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// The names of the tasks this task waits for in the given stage.
+    #[must_use]
+    pub fn dependencies(&self, stage: Stage) -> &[String] {
+        match (&self.kind.depends_on, stage) {
+            (DependsOn::All(tasks), _) => tasks,
+            (DependsOn::Stages(stages), Stage::PreBuild) => &stages.pre_build,
+            (DependsOn::Stages(stages), Stage::PostBuild) => &stages.post_build,
+        }
+    }
+
+    /// Whether the task waits for another one in every stage both of them run in, regardless
+    /// of `depends_on`. Images are resized before they are converted, since converted images
+    /// could not be decoded or would be re-encoded losslessly.
+    #[must_use]
+    pub fn waits_for(&self, other: &Task) -> bool {
+        matches!(
+            (&self.kind.options, &other.kind.options),
+            (TaskOptions::ConvertImages(_), TaskOptions::ResizeImages(_))
+        )
+    }
+
+    /// Whether the task may run alongside other tasks. Only sandboxed tasks do,
+    /// lint and keystore tasks never run in parallel.
+    #[must_use]
+    pub fn runs_in_parallel(&self) -> bool {
+        self.kind.sandboxed
+            && !matches!(
+                self.kind.options,
+                TaskOptions::Lint(_) | TaskOptions::Keystore(_)
+            )
+    }
+
+    #[must_use]
+    pub fn priority(&self, stage: Stage) -> usize {
+        match stage {
+            Stage::PreBuild => self.kind.priorities.pre_build,
+            Stage::PostBuild => self.kind.priorities.post_build,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct TaskContext {
     pub version: Version,
+    pub instance_options: InstanceOptions,
+    pub arch: Option<String>,
     pub input_dir: PathBuf,
     pub output_dir: PathBuf,
     pub renpy_path: PathBuf,
     pub registry: PathBuf,
+    /// Additional directories to search for JDKs in.
+    pub jdk_paths: Vec<PathBuf>,
     pub on_builds: HashMap<String, Option<String>>,
+    /// The cache converted images are reused from, unless it is disabled.
+    pub image_cache: Option<ImageCache>,
 }
 
 pub struct ProcessingCommand {
@@ -50,6 +155,26 @@ pub struct ProcessingCommand {
     pub lossless: bool,
     pub webp_quality: f32,
     pub avif_quality: f32,
+    pub cache: Option<ImageCache>,
+    pub cache_hits: Arc<AtomicUsize>,
+}
+
+/// The encoder an image is converted with, along with the settings that affect its output.
+#[derive(Debug, Clone, Copy)]
+enum Encoding {
+    LosslessWebP,
+    WebP(f32),
+    Avif(f32),
+}
+
+impl Display for Encoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Encoding::LosslessWebP => write!(f, "webp lossless"),
+            Encoding::WebP(quality) => write!(f, "webp quality={quality}"),
+            Encoding::Avif(quality) => write!(f, "avif quality={quality}"),
+        }
+    }
 }
 
 impl ProcessingCommand {
@@ -59,6 +184,8 @@ impl ProcessingCommand {
         lossless: bool,
         webp_quality: f32,
         avif_quality: f32,
+        cache: Option<ImageCache>,
+        cache_hits: Arc<AtomicUsize>,
     ) -> ProcessingCommand {
         ProcessingCommand {
             image_format,
@@ -66,8 +193,21 @@ impl ProcessingCommand {
             lossless,
             webp_quality,
             avif_quality,
+            cache,
+            cache_hits,
         }
     }
+
+    fn encoding(&self) -> Result<Encoding> {
+        Ok(match (&self.image_format, self.lossless) {
+            (ImageFormat::WebP | ImageFormat::HybridWebPAvif, true) => Encoding::LosslessWebP,
+            (ImageFormat::WebP, false) => Encoding::WebP(self.webp_quality),
+            (ImageFormat::Avif, true) => bail!("Lossless AVIF is not supported."),
+            (ImageFormat::Avif | ImageFormat::HybridWebPAvif, false) => {
+                Encoding::Avif(self.avif_quality)
+            }
+        })
+    }
 }
 
 fn encode_avif(path: &PathBuf, quality: f32) -> Result<()> {
@@ -92,7 +232,7 @@ fn encode_avif(path: &PathBuf, quality: f32) -> Result<()> {
         .with_num_threads(Some(2));
     let img = avif_enc.encode_rgba(image)?;
 
-    fs::write(path, &img.avif_file)?;
+    write_unshared(path, &img.avif_file)?;
 
     Ok(())
 }
@@ -135,7 +275,7 @@ fn encode_webp(path: &PathBuf, quality: f32, lossless: bool) -> Result<()> {
             qmin: 0,
             qmax: 100,
         })
-        .map_err(|err| anyhow!("Error encoding WebP image: {:?}", err))?
+        .map_err(|err| anyhow!("Error encoding WebP image: {err:?}"))?
     } else {
         // -q 90 -m 6 -sharp_yuv -pre 4
         enc.encode_advanced(&webp::WebPConfig {
@@ -169,17 +309,32 @@ fn encode_webp(path: &PathBuf, quality: f32, lossless: bool) -> Result<()> {
             qmin: 0,
             qmax: 100,
         })
-        .map_err(|err| anyhow!("Error encoding WebP image: {:?}", err))?
+        .map_err(|err| anyhow!("Error encoding WebP image: {err:?}"))?
     };
 
-    fs::write(path, result.as_bytes())?;
+    write_unshared(path, result.as_bytes())?;
 
     Ok(())
 }
 
 impl Command for ProcessingCommand {
     fn execute(&self) -> Result<()> {
-        match self.image_format {
+        let encoding = self.encoding()?;
+
+        let key = match &self.cache {
+            Some(cache) => {
+                let key = ImageCache::key(&self.path, &encoding.to_string())?;
+                if let Some(entry) = cache.get(&key) {
+                    copy_unshared(&entry, &self.path)?;
+                    self.cache_hits.fetch_add(1, Ordering::Relaxed);
+                    return Ok(());
+                }
+                Some(key)
+            }
+            None => None,
+        };
+
+        match encoding {
             // ImageFormat::JpegXl => {
             //     let image = ImageReader::open(&self.path)?.decode()?.to_rgba8();
             //     // let mut encoder = match self.lossless {
@@ -206,41 +361,140 @@ impl Command for ProcessingCommand {
             //     )?;
             //     fs::write(&self.path, &buffer.data)?;
             // }
-            ImageFormat::HybridWebPAvif => {
-                if self.lossless {
-                    encode_webp(&self.path, self.webp_quality, true)?;
-                } else {
-                    encode_avif(&self.path, self.avif_quality)?;
-                }
+            Encoding::LosslessWebP => encode_webp(&self.path, self.webp_quality, true)?,
+            Encoding::WebP(quality) => encode_webp(&self.path, quality, false)?,
+            Encoding::Avif(quality) => encode_avif(&self.path, quality)?,
+        }
+
+        // The cache only speeds up later builds, so failing to fill it does not fail this one.
+        if let (Some(cache), Some(key)) = (&self.cache, key) {
+            let _ = cache.insert(&key, &self.path);
+        }
+
+        Ok(())
+    }
+}
+
+pub struct ResizeCommand {
+    pub path: PathBuf,
+    pub scale: Option<f32>,
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    pub filter: ResizeFilter,
+    pub jpeg_quality: u8,
+    pub webp_quality: f32,
+}
+
+impl ResizeCommand {
+    /// The size to downscale an image to, or `None` if it already fits. Images are never upscaled.
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss,
+        clippy::cast_precision_loss
+    )]
+    fn target_size(&self, width: u32, height: u32) -> Option<(u32, u32)> {
+        let factor = [
+            self.scale,
+            self.max_width.map(|max| max as f32 / width as f32),
+            self.max_height.map(|max| max as f32 / height as f32),
+        ]
+        .into_iter()
+        .flatten()
+        .fold(1.0, f32::min);
+        if factor >= 1.0 {
+            return None;
+        }
+
+        Some((
+            ((width as f32 * factor).round() as u32).max(1),
+            ((height as f32 * factor).round() as u32).max(1),
+        ))
+    }
+}
+
+/// Whether a WebP file holds a lossy image, i.e. has a `VP8 ` chunk instead of a `VP8L` one.
+fn is_lossy_webp(data: &[u8]) -> bool {
+    let mut offset = 12;
+    while let Some(header) = data.get(offset..offset + 8) {
+        match &header[..4] {
+            b"VP8 " => return true,
+            b"VP8L" => return false,
+            _ => {}
+        }
+        let size = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        offset += 8 + size + size % 2;
+    }
+
+    false
+}
+
+impl Command for ResizeCommand {
+    fn execute(&self) -> Result<()> {
+        // Images are written in the format they are actually in, whatever their extension.
+        let reader = ImageReader::open(&self.path)?.with_guessed_format()?;
+        let format = reader
+            .format()
+            .ok_or(anyhow!("Unknown image format: {}", self.path.display()))?;
+        let image = reader
+            .decode()
+            .with_context(|| format!("Unable to resize {}", self.path.display()))?;
+
+        let Some((width, height)) = self.target_size(image.width(), image.height()) else {
+            return Ok(());
+        };
+        let filter = match self.filter {
+            ResizeFilter::Nearest => FilterType::Nearest,
+            ResizeFilter::Triangle => FilterType::Triangle,
+            ResizeFilter::CatmullRom => FilterType::CatmullRom,
+            ResizeFilter::Gaussian => FilterType::Gaussian,
+            ResizeFilter::Lanczos3 => FilterType::Lanczos3,
+        };
+        // Resizing keeps the color type of the image, including its alpha channel.
+        let resized = image.resize_exact(width, height, filter);
+
+        // Lossy formats are encoded at the configured quality, lossy WebP images stay lossy.
+        let mut buffer = Cursor::new(vec![]);
+        match format {
+            image::ImageFormat::Jpeg => {
+                JpegEncoder::new_with_quality(&mut buffer, self.jpeg_quality)
+                    .encode_image(&resized)?;
             }
-            ImageFormat::Avif => {
-                if self.lossless {
-                    bail!("Lossless AVIF is not supported.");
-                }
-                encode_avif(&self.path, self.avif_quality)?;
+            image::ImageFormat::WebP if is_lossy_webp(&fs::read(&self.path)?) => {
+                let image = resized.to_rgba8();
+                let encoded =
+                    webp::Encoder::from_rgba(&image, width, height).encode(self.webp_quality);
+                buffer.get_mut().extend_from_slice(&encoded);
             }
-            ImageFormat::WebP => encode_webp(&self.path, self.webp_quality, self.lossless)?,
+            format => resized.write_to(&mut buffer, format)?,
         }
+        write_unshared(&self.path, buffer.into_inner())?;
 
         Ok(())
     }
 }
 
-pub async fn task_lint_pre(ctx: &TaskContext, _options: &LintOptions) -> Result<()> {
-    let (status, _stdout, _stderr) = launch(
+pub async fn task_lint_pre(ctx: &TaskContext, options: &LintOptions) -> Result<()> {
+    launch(
         &ctx.registry,
         Some(&ctx.version),
+        &ctx.instance_options,
+        ctx.arch.as_deref(),
+        if options.sandbox {
+            Sandbox::Enabled
+        } else {
+            Sandbox::Disabled
+        },
+        None,
         true,
         true,
         &[ctx.input_dir.to_string_lossy().to_string(), "lint".into()],
+        true,
         false,
-        false,
+        &ctx.jdk_paths,
+        LockCheck::Skip,
     )
-    .await?;
-
-    if !status.success() {
-        bail!("Lint failed with status code: {}", status);
-    }
+    .await
+    .context("Lint failed")?;
 
     Ok(())
 }
@@ -294,13 +548,13 @@ pub fn task_keystore_pre(ctx: &TaskContext, options: &KeystoreOptions) -> Result
         Ok(val) => BASE64_STANDARD.decode(val)?,
         Err(_) => BASE64_STANDARD.decode(options.keystore_apk.clone())?,
     };
-    fs::write(&android_path, android_keystore)?;
+    write_unshared(&android_path, android_keystore)?;
 
     let bundle_keystore = match env::var("RC_KEYSTORE_AAB") {
         Ok(val) => BASE64_STANDARD.decode(val)?,
         Err(_) => BASE64_STANDARD.decode(options.keystore_aab.clone())?,
     };
-    fs::write(&bundle_path, bundle_keystore)?;
+    write_unshared(&bundle_path, bundle_keystore)?;
 
     // We need to disable the update_keystores option in android.json
     // otherwise Ren'Py will overwrite our changes to the property files.
@@ -318,7 +572,7 @@ pub fn task_keystore_pre(ctx: &TaskContext, options: &KeystoreOptions) -> Result
             let mut config: HashMap<String, Value> =
                 serde_json::from_str(&fs::read_to_string(&path)?)?;
             config.insert("update_keystores".to_string(), Value::Bool(false));
-            fs::write(&path, serde_json::to_string(&config)?)?;
+            write_unshared(&path, serde_json::to_string(&config)?)?;
             break;
         }
     }
@@ -355,8 +609,9 @@ pub fn task_keystore_pre(ctx: &TaskContext, options: &KeystoreOptions) -> Result
             .replace('\\', "/"),
         ctx.renpy_path.to_string_lossy()
     );
-    fs::write(&local_properties_path, &property_contents)?;
-    fs::write(
+    // `renpy_path` is the overlay of the instance, whose copies may still share storage with it.
+    write_unshared(&local_properties_path, &property_contents)?;
+    write_unshared(
         &bundle_properties_path,
         property_contents.replace("android.keystore", "bundle.keystore"),
     )?;
@@ -394,28 +649,28 @@ pub fn task_keystore_post(ctx: &TaskContext, _options: &KeystoreOptions) -> Resu
     }
 
     if android_path_backup.exists() {
-        fs::copy(&android_path_backup, &android_path)?;
+        copy_unshared(&android_path_backup, &android_path)?;
         fs::remove_file(&android_path_backup)?;
     } else {
         fs::remove_file(&android_path)?;
     }
 
     if bundle_path_backup.exists() {
-        fs::copy(&bundle_path_backup, &bundle_path)?;
+        copy_unshared(&bundle_path_backup, &bundle_path)?;
         fs::remove_file(&bundle_path_backup)?;
     } else {
         fs::remove_file(&bundle_path)?;
     }
 
     if local_properties_path_backup.exists() {
-        fs::copy(&local_properties_path_backup, &local_properties_path)?;
+        copy_unshared(&local_properties_path_backup, &local_properties_path)?;
         fs::remove_file(&local_properties_path_backup)?;
     } else {
         fs::remove_file(&local_properties_path)?;
     }
 
     if bundle_properties_path_backup.exists() {
-        fs::copy(&bundle_properties_path_backup, &bundle_properties_path)?;
+        copy_unshared(&bundle_properties_path_backup, &bundle_properties_path)?;
         fs::remove_file(&bundle_properties_path_backup)?;
     } else {
         fs::remove_file(&bundle_properties_path)?;
@@ -424,50 +679,45 @@ pub fn task_keystore_post(ctx: &TaskContext, _options: &KeystoreOptions) -> Resu
     Ok(())
 }
 
-pub fn task_convert_images_pre(ctx: &TaskContext, options: &ConvertImagesOptions) -> Result<()> {
-    let mut files = vec![];
-
-    for (path, opts) in &options.paths {
-        let path = ctx.input_dir.join(path);
-        if !path.exists() {
-            println!("Path does not exist: {}", path.display());
-            continue;
-        }
+/// Finds the files with one of the given extensions in a directory of the project.
+fn find_images(
+    ctx: &TaskContext,
+    path: &str,
+    recursive: bool,
+    extensions: &[String],
+) -> Vec<PathBuf> {
+    let path = ctx.input_dir.join(path);
+    if !path.exists() {
+        println!("Path does not exist: {}", path.display());
+        return vec![];
+    }
 
-        for entry in if opts.recursive {
-            WalkDir::new(path)
-        } else {
-            WalkDir::new(path).max_depth(1)
-        } {
-            match entry {
-                Ok(entry) => {
-                    if entry.path().is_dir() {
-                        continue;
-                    }
-                    if let Some(ext) = entry.path().extension() {
-                        if !opts.extensions.contains(&ext.to_string_lossy().to_string()) {
-                            continue;
-                        }
-                        // read first 16 bytes
-                        let mut buf = [0; 12];
-                        let mut file = fs::File::open(entry.path())?;
-                        file.read_exact(&mut buf)?;
-                        drop(file);
-                        if String::from_utf8_lossy(&buf[0..4]).to_string().as_str() == "RIFF" {
-                            continue;
-                        }
-                        if String::from_utf8_lossy(&buf[4..12]).to_string().as_str() == "ftypavif" {
-                            continue;
-                        }
-                        files.push((entry.path(), opts.lossless));
-                    }
+    let mut files = vec![];
+    for entry in if recursive {
+        WalkDir::new(path)
+    } else {
+        WalkDir::new(path).max_depth(1)
+    } {
+        match entry {
+            Ok(entry) => {
+                if entry.path().is_dir() {
+                    continue;
+                }
+                if let Some(ext) = entry.path().extension()
+                    && extensions.contains(&ext.to_string_lossy().to_string())
+                {
+                    files.push(entry.path());
                 }
-                Err(err) => println!("Error: {err}"),
             }
+            Err(err) => println!("Error: {err}"),
         }
     }
+    files
+}
 
-    let bar =ProgressBar::new(files.len() as u64).with_style(
+/// Runs image processing commands on a thread pool with one thread per CPU but one.
+fn process_images(commands: Vec<Box<dyn Command + Send + Sync>>) -> Result<()> {
+    let bar =ProgressBar::new(commands.len() as u64).with_style(
         ProgressStyle::with_template(
             "{bar:48.green/black} {human_pos:>5.green}/{human_len:<5.green} {per_sec:.red} eta {eta:.blue}",
         )
@@ -484,14 +734,8 @@ pub fn task_convert_images_pre(ctx: &TaskContext, options: &ConvertImagesOptions
         .with_shutdown_mode(ShutdownMode::CompletePending)
         .build()?;
 
-    for (path, lossless) in files {
-        pool.submit(Box::new(ProcessingCommand::new(
-            options.format.clone(),
-            path,
-            lossless,
-            options.webp_quality,
-            options.avif_quality,
-        )));
+    for command in commands {
+        pool.submit(command);
         bar.inc(1);
     }
 
@@ -503,6 +747,78 @@ pub fn task_convert_images_pre(ctx: &TaskContext, options: &ConvertImagesOptions
     Ok(())
 }
 
+pub fn task_convert_images_pre(ctx: &TaskContext, options: &ConvertImagesOptions) -> Result<()> {
+    let mut files = vec![];
+
+    for (path, opts) in &options.paths {
+        for path in find_images(ctx, path, opts.recursive, &opts.extensions) {
+            // read first 16 bytes
+            let mut buf = [0; 12];
+            let mut file = fs::File::open(&path)?;
+            file.read_exact(&mut buf)?;
+            drop(file);
+            if String::from_utf8_lossy(&buf[0..4]).to_string().as_str() == "RIFF" {
+                continue;
+            }
+            if String::from_utf8_lossy(&buf[4..12]).to_string().as_str() == "ftypavif" {
+                continue;
+            }
+            files.push((path, opts.lossless));
+        }
+    }
+
+    let total = files.len();
+    let cache_hits = Arc::new(AtomicUsize::new(0));
+    process_images(
+        files
+            .into_iter()
+            .map(|(path, lossless)| -> Box<dyn Command + Send + Sync> {
+                Box::new(ProcessingCommand::new(
+                    options.format.clone(),
+                    path,
+                    lossless,
+                    options.webp_quality,
+                    options.avif_quality,
+                    ctx.image_cache.clone(),
+                    cache_hits.clone(),
+                ))
+            })
+            .collect(),
+    )?;
+
+    if let Some(cache) = &ctx.image_cache {
+        println!(
+            "Reused {} of {total} images from the image cache",
+            cache_hits.load(Ordering::Relaxed)
+        );
+        if let Err(e) = cache.prune() {
+            println!("Unable to prune the image cache: {e}");
+        }
+    }
+
+    Ok(())
+}
+
+pub fn task_resize_images_pre(ctx: &TaskContext, options: &ResizeImagesOptions) -> Result<()> {
+    let mut commands: Vec<Box<dyn Command + Send + Sync>> = vec![];
+
+    for (path, opts) in &options.paths {
+        for path in find_images(ctx, path, opts.recursive, &opts.extensions) {
+            commands.push(Box::new(ResizeCommand {
+                path,
+                scale: opts.scale,
+                max_width: opts.max_width,
+                max_height: opts.max_height,
+                filter: options.filter,
+                jpeg_quality: options.jpeg_quality,
+                webp_quality: options.webp_quality,
+            }));
+        }
+    }
+
+    process_images(commands)
+}
+
 pub fn task_notarize_post(ctx: &TaskContext, options: &NotarizeOptions) -> Result<()> {
     for path in ctx.on_builds.values() {
         let path = Path::new(path.as_ref().expect("Build path not found"));
@@ -566,3 +882,157 @@ pub fn task_notarize_post(ctx: &TaskContext, options: &NotarizeOptions) -> Resul
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ResizeCommand, Stage, Task, TaskContext, task_convert_images_pre, task_resize_images_pre,
+    };
+    use crate::{
+        renconstruct::{
+            config::{GeneralTaskOptions, ResizeFilter, TaskOptions},
+            plan::TaskGraph,
+        },
+        renutil::InstanceOptions,
+        version::Version,
+    };
+    use command_executor::command::Command;
+    use image::{DynamicImage, ImageReader, Rgba, RgbaImage};
+    use std::{collections::HashMap, env, fs, str::FromStr};
+
+    #[test]
+    fn resized_images() {
+        let dir = env::temp_dir().join(format!("renkit-resize-{}", rand::random::<u32>()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("bg.png");
+        RgbaImage::from_pixel(64, 32, Rgba([255, 0, 0, 128]))
+            .save(&path)
+            .unwrap();
+
+        let command = ResizeCommand {
+            path: path.clone(),
+            scale: Some(0.5),
+            max_width: Some(16),
+            max_height: None,
+            filter: ResizeFilter::Lanczos3,
+            jpeg_quality: 90,
+            webp_quality: 90.0,
+        };
+        assert_eq!(command.target_size(8, 4), Some((4, 2)));
+        let fitting = ResizeCommand {
+            scale: None,
+            ..command
+        };
+        assert_eq!(fitting.target_size(8, 4), None);
+        let command = ResizeCommand {
+            scale: Some(0.5),
+            ..fitting
+        };
+        command.execute().unwrap();
+
+        let image = ImageReader::open(&path).unwrap().decode().unwrap();
+        assert_eq!((image.width(), image.height()), (16, 8));
+        assert_eq!(image.to_rgba8().get_pixel(8, 4), &Rgba([255, 0, 0, 128]));
+
+        // Lossy images are encoded at the configured quality and stay lossy.
+        let noise = RgbaImage::from_fn(256, 256, |_, _| Rgba([rand::random(), 0, 0, 255]));
+        let webp_path = dir.join("bg.webp");
+        fs::write(
+            &webp_path,
+            &*webp::Encoder::from_rgba(&noise, 256, 256).encode(90.0),
+        )
+        .unwrap();
+        let jpeg_path = dir.join("bg.jpg");
+        DynamicImage::ImageRgba8(noise)
+            .to_rgb8()
+            .save(&jpeg_path)
+            .unwrap();
+        for path in [&webp_path, &jpeg_path] {
+            let size = fs::metadata(path).unwrap().len();
+            ResizeCommand {
+                path: path.clone(),
+                max_width: None,
+                webp_quality: 50.0,
+                jpeg_quality: 50,
+                ..command
+            }
+            .execute()
+            .unwrap();
+            // Lossless encodings of the noise would be larger than a quarter of the original.
+            assert!(fs::metadata(path).unwrap().len() < size / 4);
+            let image = ImageReader::open(path).unwrap().decode().unwrap();
+            assert_eq!((image.width(), image.height()), (128, 128));
+        }
+        assert_eq!(&fs::read(&webp_path).unwrap()[12..16], b"VP8 ");
+        assert_eq!(&fs::read(&jpeg_path).unwrap()[..2], &[0xFF, 0xD8]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn resized_and_converted_images() {
+        let dir = env::temp_dir().join(format!("renkit-resize-{}", rand::random::<u32>()));
+        fs::create_dir_all(dir.join("game")).unwrap();
+        let path = dir.join("game/bg.png");
+        RgbaImage::from_pixel(64, 32, Rgba([255, 0, 0, 255]))
+            .save(&path)
+            .unwrap();
+
+        let tasks = toml::from_str::<HashMap<String, GeneralTaskOptions>>(
+            r#"
+            [convert]
+            type = "convert_images"
+            enabled = true
+            format = "webp"
+            paths = { "game" = { lossless = false } }
+
+            [resize]
+            type = "resize_images"
+            enabled = true
+            paths = { "game" = { scale = 0.5 } }
+            priorities = { pre_build = 1 }
+            "#,
+        )
+        .unwrap()
+        .into_iter()
+        .map(|(name, kind)| Task { name, kind })
+        .collect::<Vec<_>>();
+        let mut graph = TaskGraph::new(&tasks, Stage::PreBuild).unwrap();
+
+        let ctx = TaskContext {
+            version: Version::from_str("8.3.4").unwrap(),
+            instance_options: InstanceOptions::default(),
+            arch: None,
+            input_dir: dir.clone(),
+            output_dir: dir.join("out"),
+            renpy_path: dir.join("renpy"),
+            registry: dir.join("registry"),
+            jdk_paths: vec![],
+            on_builds: HashMap::new(),
+            image_cache: None,
+        };
+        let mut order = vec![];
+        while let Some((i, task)) = graph.next(1) {
+            match &task.kind.options {
+                TaskOptions::ConvertImages(opts) => task_convert_images_pre(&ctx, opts).unwrap(),
+                TaskOptions::ResizeImages(opts) => task_resize_images_pre(&ctx, opts).unwrap(),
+                _ => unreachable!(),
+            }
+            order.push(task.name.as_str());
+            graph.finish(i);
+        }
+        assert_eq!(order, vec!["resize", "convert"]);
+
+        let webp = fs::read(&path).unwrap();
+        assert_eq!(&webp[12..16], b"VP8 ");
+        let image = ImageReader::open(&path)
+            .unwrap()
+            .with_guessed_format()
+            .unwrap()
+            .decode()
+            .unwrap();
+        assert_eq!((image.width(), image.height()), (32, 16));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
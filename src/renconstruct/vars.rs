@@ -0,0 +1,310 @@
+use crate::common::canonicalize_normalized;
+use anyhow::{Context, Result, anyhow, bail};
+use std::{
+    cell::OnceCell,
+    collections::HashMap,
+    env,
+    path::{Path, PathBuf},
+    process::Command,
+};
+use toml::{Table, Value};
+
+/// Resolves `${NAME}` and `${NAME:-default}` against the `[vars]` of a config, which may
+/// reference each other, the built-in variables of a task and the environment, in that order.
+struct Resolver<'a> {
+    vars: Option<&'a Table>,
+    task: Option<(&'a Builtins, &'a [String])>,
+    resolved: HashMap<String, String>,
+    resolving: Vec<String>,
+}
+
+impl<'a> Resolver<'a> {
+    fn new(vars: Option<&'a Table>, task: Option<(&'a Builtins, &'a [String])>) -> Self {
+        Self {
+            vars,
+            task,
+            resolved: HashMap::new(),
+            resolving: vec![],
+        }
+    }
+
+    fn lookup(&mut self, name: &str) -> Result<Option<String>> {
+        if let Some(value) = self.resolved.get(name) {
+            return Ok(Some(value.clone()));
+        }
+
+        let Some(value) = self.vars.and_then(|vars| vars.get(name)) else {
+            if let Some((builtins, task_builds)) = self.task
+                && let Some(value) = builtins.get(name, task_builds)?
+            {
+                return Ok(Some(value));
+            }
+            return Ok(env::var(name).ok());
+        };
+
+        let raw = match value {
+            Value::String(value) => value.clone(),
+            Value::Integer(_) | Value::Float(_) | Value::Boolean(_) => value.to_string(),
+            _ => bail!("Variable {name} must be a string, number or boolean"),
+        };
+        if self.resolving.iter().any(|n| n == name) {
+            bail!("Variable {name} references itself");
+        }
+
+        self.resolving.push(name.to_string());
+        let value = self.expand(&raw)?;
+        self.resolving.pop();
+
+        self.resolved.insert(name.to_string(), value.clone());
+        Ok(Some(value))
+    }
+
+    /// Expands all variables in a string, `$${` escapes a literal `${`.
+    fn expand(&mut self, value: &str) -> Result<String> {
+        let mut result = String::new();
+        let mut rest = value;
+
+        while let Some(start) = rest.find('$') {
+            result.push_str(&rest[..start]);
+            rest = &rest[start..];
+
+            if let Some(after) = rest.strip_prefix("$${") {
+                result.push_str("${");
+                rest = after;
+                continue;
+            }
+            let Some(after) = rest.strip_prefix("${") else {
+                result.push('$');
+                rest = &rest[1..];
+                continue;
+            };
+
+            let end = after
+                .find('}')
+                .ok_or(anyhow!("Unterminated variable in '{value}'"))?;
+            let (name, default) = match after[..end].split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (&after[..end], None),
+            };
+
+            match (self.lookup(name)?, default) {
+                (Some(value), None) => result.push_str(&value),
+                (Some(value), Some(_)) if !value.is_empty() => result.push_str(&value),
+                (_, Some(default)) => result.push_str(default),
+                (None, None) => bail!("Undefined variable: {name}"),
+            }
+            rest = &after[end + 1..];
+        }
+
+        result.push_str(rest);
+        Ok(result)
+    }
+}
+
+/// Applies `f` to every string in a value, with the dotted path of the string for errors.
+fn map_strings(
+    value: &mut Value,
+    path: &str,
+    f: &mut impl FnMut(&str) -> Result<String>,
+) -> Result<()> {
+    match value {
+        Value::String(s) => {
+            *s = f(s).with_context(|| format!("Invalid value for {path}"))?;
+        }
+        Value::Array(values) => {
+            for (i, value) in values.iter_mut().enumerate() {
+                map_strings(value, &format!("{path}[{i}]"), f)?;
+            }
+        }
+        Value::Table(table) => {
+            for (key, value) in table.iter_mut() {
+                map_strings(value, &format!("{path}.{key}"), f)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// The variables renconstruct provides to task options, resolved when a task uses them.
+struct Builtins {
+    renpy_version: Option<String>,
+    project_name: Option<String>,
+    input_dir: PathBuf,
+    git_sha: OnceCell<Option<String>>,
+    builds: Vec<String>,
+}
+
+impl Builtins {
+    fn git_sha(&self) -> Option<String> {
+        self.git_sha
+            .get_or_init(|| {
+                let output = Command::new("git")
+                    .arg("-C")
+                    .arg(&self.input_dir)
+                    .args(["rev-parse", "HEAD"])
+                    .output()
+                    .ok()?;
+                output
+                    .status
+                    .success()
+                    .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+            })
+            .clone()
+    }
+
+    /// The value of a built-in variable, or `None` if there is no built-in variable of that name.
+    fn get(&self, name: &str, task_builds: &[String]) -> Result<Option<String>> {
+        let value = match name {
+            "renpy_version" => self.renpy_version.clone().ok_or(anyhow!(
+                "${{renpy_version}} requires renutil.version to be set"
+            ))?,
+            "project_name" => self.project_name.clone().ok_or(anyhow!(
+                "${{project_name}} requires the input directory to exist"
+            ))?,
+            "git_sha" => self.git_sha().ok_or(anyhow!(
+                "${{git_sha}} requires the input directory to be a git repository"
+            ))?,
+            "build" => {
+                let [build] = task_builds else {
+                    bail!("${{build}} requires the task to run on exactly one build");
+                };
+                build.clone()
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(value))
+    }
+}
+
+/// Resolves `${NAME}` and `${NAME:-default}` in every string of a config against `[vars]`
+/// and the environment. The options of tasks may additionally use the built-in variables
+/// `${renpy_version}`, `${project_name}`, `${git_sha}` and `${build}`.
+pub fn interpolate(config: &mut Table, input_dir: &Path) -> Result<()> {
+    let vars = config.get("vars").and_then(|v| v.as_table()).cloned();
+    let mut resolver = Resolver::new(vars.as_ref(), None);
+    for (key, value) in config.iter_mut() {
+        if key != "vars" && key != "tasks" {
+            map_strings(value, key, &mut |s| resolver.expand(s))?;
+        }
+    }
+
+    let builds = ["builds", "build"]
+        .iter()
+        .filter_map(|key| config.get(*key).and_then(|v| v.as_table()))
+        .flat_map(|builds| builds.iter())
+        .filter(|(_, enabled)| enabled.as_bool() == Some(true))
+        .map(|(build, _)| build.clone())
+        .collect::<Vec<_>>();
+    let builtins = Builtins {
+        renpy_version: config
+            .get("renutil")
+            .and_then(|renutil| renutil.get("version"))
+            .and_then(|version| version.as_str())
+            .map(ToString::to_string),
+        project_name: canonicalize_normalized(input_dir).ok().and_then(|dir| {
+            dir.file_name()
+                .map(|name| name.to_string_lossy().to_string())
+        }),
+        input_dir: input_dir.to_path_buf(),
+        git_sha: OnceCell::new(),
+        builds,
+    };
+
+    let Some(tasks) = config.get_mut("tasks").and_then(|v| v.as_table_mut()) else {
+        return Ok(());
+    };
+    for (name, task) in tasks.iter_mut() {
+        let task_builds = match task.get("on_builds").and_then(|v| v.as_array()) {
+            Some(on_builds) if !on_builds.is_empty() => on_builds
+                .iter()
+                .filter_map(|build| build.as_str().map(ToString::to_string))
+                .collect(),
+            _ => builtins.builds.clone(),
+        };
+        let mut resolver = Resolver::new(vars.as_ref(), Some((&builtins, &task_builds)));
+        map_strings(task, &format!("tasks.{name}"), &mut |s| resolver.expand(s))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::interpolate;
+    use std::env;
+
+    #[test]
+    fn interpolated_config() {
+        let mut config: toml::Table = toml::from_str(
+            r#"
+            [vars]
+            certs = "${RENKIT_UNDEFINED_VARIABLE:-certificates}"
+            key = "${certs}/private-key.pem"
+            suffix = "${build}"
+
+            [builds]
+            mac = true
+            pc = true
+
+            [renutil]
+            version = "8.3.4"
+
+            [tasks.notarize]
+            type = "notarize"
+            key_file = "${key}"
+            bundle_id = "$${literal}"
+            on_builds = ["mac"]
+
+            [tasks.custom]
+            type = "custom"
+            output = "${project_name}-${renpy_version}-${suffix}"
+            template = "{renpy_version}"
+            "#,
+        )
+        .unwrap();
+        let temp_dir = env::temp_dir();
+
+        assert_eq!(
+            interpolate(&mut config.clone(), &temp_dir)
+                .unwrap_err()
+                .to_string(),
+            "Invalid value for tasks.custom.output"
+        );
+
+        config["tasks"]["custom"]
+            .as_table_mut()
+            .unwrap()
+            .insert("on_builds".into(), vec!["pc"].into());
+
+        let mut undefined = config.clone();
+        undefined["vars"]["certs"] = "${RENKIT_UNDEFINED_VARIABLE}".into();
+        assert_eq!(
+            interpolate(&mut undefined, &temp_dir)
+                .unwrap_err()
+                .root_cause()
+                .to_string(),
+            "Undefined variable: RENKIT_UNDEFINED_VARIABLE"
+        );
+
+        interpolate(&mut config, &temp_dir).unwrap();
+
+        assert_eq!(
+            config["tasks"]["notarize"]["key_file"].as_str(),
+            Some("certificates/private-key.pem")
+        );
+        assert_eq!(
+            config["tasks"]["notarize"]["bundle_id"].as_str(),
+            Some("${literal}")
+        );
+        let project_name = temp_dir.file_name().unwrap().to_string_lossy();
+        assert_eq!(
+            config["tasks"]["custom"]["output"].as_str(),
+            Some(format!("{project_name}-8.3.4-pc").as_str())
+        );
+        assert_eq!(
+            config["tasks"]["custom"]["template"].as_str(),
+            Some("{renpy_version}")
+        );
+    }
+}
@@ -43,7 +43,7 @@ fn notarize_file(
             println!("Notarization UUID: {id}");
             println!("Waiting for notarization to complete");
 
-            let wait_limit = Duration::from_secs(1800);
+            let wait_limit = Duration::from_mins(30);
             let wait_interval = Duration::from_secs(5);
             let start_time = std::time::Instant::now();
 
@@ -190,6 +190,30 @@ pub fn sign_app(input_file: &Path, key_file: &Path, cert_file: &Path) -> Result<
     Ok(())
 }
 
+/// Loads the signing key, certificate and App Store Connect API key without using them,
+/// so broken files are detected before anything is built.
+pub fn validate_signing_files(
+    key_file: &Path,
+    cert_file: &Path,
+    app_store_key_file: &Path,
+) -> Result<()> {
+    let mut sign_config = SignConfig::default();
+    sign_config.signer.pem_path_key = Some(PemSigningKey {
+        paths: vec![key_file.to_path_buf()],
+    });
+    sign_config.signer.certificate_der_key = Some(CertificateDerSigningKey {
+        paths: vec![cert_file.to_path_buf()],
+    });
+
+    let mut settings = SigningSettings::default();
+    let certs = sign_config.signer.resolve_certificates(false)?;
+    certs.load_into_signing_settings(&mut settings)?;
+
+    Notarizer::from_api_key(app_store_key_file)?;
+
+    Ok(())
+}
+
 pub fn notarize_app(input_file: &Path, app_store_key_file: &Path) -> Result<()> {
     notarize_file(input_file, app_store_key_file, None)
 }
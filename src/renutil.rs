@@ -1,24 +1,38 @@
+use crate::cache::ArchiveCache;
 use crate::common::canonicalize_normalized;
+use crate::dedupe::dedupe;
+use crate::jdk::{configure_java_home, find_jdk, required_major};
+use crate::lock::{LockCheck, check_lock};
 use crate::version::Version;
 use anyhow::{Result, anyhow};
 use bzip2::read::BzDecoder;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use lol_html::{HtmlRewriter, Settings, element};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 #[cfg(target_family = "unix")]
 use std::os::unix::fs::PermissionsExt;
 use std::{
     env, fs,
     io::{BufRead, BufReader},
     marker::PhantomData,
-    path::PathBuf,
+    path::{Path, PathBuf},
     process::{Command, ExitStatus, Stdio},
     str::FromStr,
     sync::{Arc, Mutex},
     thread,
+    time::{Duration, SystemTime},
 };
 use tar::Archive;
-use trauma::{download::Download, downloader::DownloaderBuilder};
+use tokio::{io::AsyncWriteExt, sync::oneshot};
 use zip::read::root_dir_common_filter;
 
+/// The file a project uses to pin the version of Ren'Py it should be launched with.
+pub const VERSION_FILE_NAME: &str = ".renpy-version";
+/// The file in the registry that stores the globally configured default version.
+pub const DEFAULT_VERSION_FILE_NAME: &str = ".default-version";
+
 pub trait InstanceState {}
 
 pub struct Local;
@@ -27,24 +41,138 @@ pub struct Remote;
 impl InstanceState for Local {}
 impl InstanceState for Remote {}
 
+/// Options that change the contents of an instance at install time.
+/// Instances installed with non-default options live in their own directory,
+/// suffixed with the name of the variant, i.e. `8.3.4@pickle5`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InstanceOptions {
+    #[serde(default)]
+    pub update_pickle: bool,
+}
+
+impl InstanceOptions {
+    /// Parses the variant suffix of an instance name, i.e. `pickle5` in `8.3.4@pickle5`.
+    pub fn from_variant(variant: &str) -> Result<Self> {
+        match variant {
+            "" => Ok(Self::default()),
+            "pickle5" => Ok(Self {
+                update_pickle: true,
+            }),
+            _ => Err(anyhow!("Unknown instance variant: {variant}")),
+        }
+    }
+
+    #[must_use]
+    pub fn variant(&self) -> Option<&'static str> {
+        if self.update_pickle {
+            Some("pickle5")
+        } else {
+            None
+        }
+    }
+}
+
+/// Splits an instance name like `8.3.4@pickle5` into its version and install options.
+pub fn parse_instance_name(name: &str) -> Result<(Version, InstanceOptions)> {
+    match name.split_once('@') {
+        Some((version, variant)) => Ok((
+            Version::from_str(version)?,
+            InstanceOptions::from_variant(variant)?,
+        )),
+        None => Ok((Version::from_str(name)?, InstanceOptions::default())),
+    }
+}
+
+/// The Android SDK build tools installed into every instance.
+pub const ANDROID_BUILD_TOOLS: &str = "29.0.2";
+
+/// A downloaded archive an instance was installed from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArchiveRecord {
+    pub component: String,
+    pub url: String,
+    pub sha256: String,
+}
+
+/// Records how an instance was installed, stored inside of the instance directory.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InstanceManifest {
+    pub version: String,
+    pub options: InstanceOptions,
+    /// Empty for instances installed before archives were recorded.
+    #[serde(default)]
+    pub archives: Vec<ArchiveRecord>,
+    /// The modifications renutil applied to the SDK after extracting it.
+    #[serde(default)]
+    pub patches: Vec<String>,
+    #[serde(default)]
+    pub android_build_tools: Option<String>,
+}
+
+impl InstanceManifest {
+    pub const FILE_NAME: &'static str = ".renutil.json";
+
+    pub fn read(instance_path: &Path) -> Result<Option<Self>> {
+        let path = instance_path.join(Self::FILE_NAME);
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_str(&fs::read_to_string(path)?)?))
+    }
+
+    pub fn write(&self, instance_path: &Path) -> Result<()> {
+        fs::write(
+            instance_path.join(Self::FILE_NAME),
+            serde_json::to_string_pretty(self)?,
+        )?;
+        Ok(())
+    }
+}
+
 pub struct Instance<S: InstanceState> {
     pub version: Version,
+    pub options: InstanceOptions,
     _marker: PhantomData<S>,
 }
 
 impl<S: InstanceState> Instance<S> {
     #[must_use]
-    pub fn new(version: Version) -> Self {
+    pub fn new(version: Version, options: InstanceOptions) -> Self {
         Self {
             version,
+            options,
             _marker: PhantomData,
         }
     }
 
+    /// The name of the instance directory, i.e. `8.3.4` or `8.3.4@pickle5`.
+    #[must_use]
+    pub fn name(&self) -> String {
+        match self.options.variant() {
+            Some(variant) => format!("{}@{variant}", self.version),
+            None => self.version.to_string(),
+        }
+    }
+
+    /// The directory of this instance. Installed instances are looked up in all registries,
+    /// all others are placed in the given registry.
     #[must_use]
-    pub fn path(&self, registry: &PathBuf) -> PathBuf {
-        let base_path = canonicalize_normalized(registry).expect("Unable to canonicalize path.");
-        base_path.join(self.version.to_string())
+    pub fn path(&self, registry: &Path) -> PathBuf {
+        let name = self.name();
+        let registry =
+            find_instance_registry(registry, &name).unwrap_or_else(|| registry.to_path_buf());
+        let base_path = canonicalize_normalized(&registry).expect("Unable to canonicalize path.");
+        base_path.join(name)
+    }
+
+    /// The directory this instance points to if it was registered via `renutil link`.
+    #[must_use]
+    pub fn link_target(&self, registry: &Path) -> Option<PathBuf> {
+        let path = self.path(registry);
+        match fs::symlink_metadata(&path) {
+            Ok(meta) if meta.file_type().is_symlink() => fs::read_link(path).ok(),
+            _ => None,
+        }
     }
 }
 
@@ -73,7 +201,7 @@ impl Instance<Local> {
                         Ok("py3-windows-i686")
                     }
                 }
-                _ => Err(anyhow!("Unsupported architecture: {}", architecture)),
+                _ => Err(anyhow!("Unsupported architecture: {architecture}")),
             },
             "linux" => match architecture {
                 "x86_64" => {
@@ -96,7 +224,7 @@ impl Instance<Local> {
                 }
                 "arm" => {
                     if self.version < Version::from_str("7.5.0").unwrap() {
-                        Err(anyhow!("Unsupported architecture: {}", architecture))
+                        Err(anyhow!("Unsupported architecture: {architecture}"))
                     } else if self.version < Version::from_str("8.0.0").unwrap() {
                         Ok("py2-linux-armv7l")
                     } else {
@@ -105,14 +233,14 @@ impl Instance<Local> {
                 }
                 "aarch64" => {
                     if self.version < Version::from_str("7.5.0").unwrap() {
-                        Err(anyhow!("Unsupported architecture: {}", architecture))
+                        Err(anyhow!("Unsupported architecture: {architecture}"))
                     } else if self.version < Version::from_str("8.0.0").unwrap() {
                         Ok("py2-linux-aarch64")
                     } else {
                         Ok("py3-linux-aarch64")
                     }
                 }
-                _ => Err(anyhow!("Unsupported architecture: {}", architecture)),
+                _ => Err(anyhow!("Unsupported architecture: {architecture}")),
             },
             "macos" => {
                 if self.version < Version::from_str("7.4.0").unwrap() {
@@ -127,24 +255,58 @@ impl Instance<Local> {
                     Ok("py3-mac-universal")
                 }
             }
-            _ => Err(anyhow!("Unsupported OS: {}", host_os)),
+            _ => Err(anyhow!("Unsupported OS: {host_os}")),
         }
     }
 
-    pub fn python(&self, registry: &PathBuf) -> Result<PathBuf> {
-        let exe = match std::env::consts::OS {
-            "windows" => "python.exe",
-            _ => "python",
-        };
+    /// Lists all architectures this instance ships a Python runtime for, based on its `lib` directory.
+    pub fn architectures(&self, registry: &Path) -> Result<Vec<String>> {
+        let mut architectures = vec![];
 
-        match self.architecture() {
-            Ok(arch) => Ok(self.path(registry).join("lib").join(arch).join(exe)),
-            Err(e) => Err(e),
+        for entry in fs::read_dir(self.path(registry).join("lib"))? {
+            let path = entry?.path();
+            if !path.join("python").is_file() && !path.join("python.exe").is_file() {
+                continue;
+            }
+            if let Some(name) = path.file_name() {
+                architectures.push(name.to_string_lossy().to_string());
+            }
         }
+
+        architectures.sort();
+
+        Ok(architectures)
+    }
+
+    /// Returns the Python executable for the given architecture,
+    /// or for the architecture matching the host if none is given.
+    pub fn python(&self, registry: &Path, arch: Option<&str>) -> Result<PathBuf> {
+        let arch = match arch {
+            Some(arch) => {
+                let available = self.architectures(registry)?;
+                if !available.iter().any(|a| a == arch) {
+                    anyhow::bail!(
+                        "Architecture {arch} is not available for {}. Available architectures: {}",
+                        self.name(),
+                        available.join(", ")
+                    );
+                }
+                arch
+            }
+            None => self.architecture()?,
+        };
+
+        let exe = if arch.contains("windows") {
+            "python.exe"
+        } else {
+            "python"
+        };
+
+        Ok(self.path(registry).join("lib").join(arch).join(exe))
     }
 
     #[must_use]
-    pub fn entrypoint(&self, registry: &PathBuf) -> PathBuf {
+    pub fn entrypoint(&self, registry: &Path) -> PathBuf {
         self.path(registry).join("renpy.py")
     }
 }
@@ -157,6 +319,7 @@ impl std::fmt::Display for Version {
             patch,
             hotfix,
             nightly,
+            label,
         } = self;
         {
             write!(f, "{major}.{minor}.{patch}")?;
@@ -166,14 +329,41 @@ impl std::fmt::Display for Version {
             if *nightly {
                 write!(f, "+nightly")?;
             }
+            if let Some(label) = label {
+                write!(f, "+{label}")?;
+            }
             Ok(())
         }
     }
 }
 
+/// The registries configured via `RENUTIL_REGISTRY_PATH`, separated like `PATH`.
+/// Defaults to `~/.renutil` if it is not set.
+#[must_use]
+pub fn get_registry_path() -> Vec<PathBuf> {
+    let paths = env::var_os("RENUTIL_REGISTRY_PATH")
+        .map(|paths| {
+            env::split_paths(&paths)
+                .filter(|p| !p.as_os_str().is_empty())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    if paths.is_empty() {
+        home::home_dir()
+            .map(|home| vec![home.join(".renutil")])
+            .unwrap_or_default()
+    } else {
+        paths
+    }
+}
+
+/// Resolves the registry new instances are installed into, which is either the given one
+/// or the first writable registry of the registry path.
 #[must_use]
 pub fn get_registry(registry: Option<PathBuf>) -> PathBuf {
     let registry = registry
+        .or_else(|| get_registry_path().into_iter().find(|p| is_writable(p)))
         .or_else(|| match home::home_dir() {
             Some(mut path) => {
                 path.push(".renutil");
@@ -190,6 +380,134 @@ pub fn get_registry(registry: Option<PathBuf>) -> PathBuf {
     registry
 }
 
+fn is_writable(dir: &Path) -> bool {
+    if fs::create_dir_all(dir).is_err() {
+        return false;
+    }
+    let probe = dir.join(format!(".renutil-write-test-{}", std::process::id()));
+    let writable = fs::write(&probe, b"").is_ok();
+    let _ = fs::remove_file(&probe);
+    writable
+}
+
+/// All registries instances are looked up in, in order of precedence.
+/// The given registry is searched first, unless it is part of the registry path itself.
+#[must_use]
+pub fn get_registries(registry: &Path) -> Vec<PathBuf> {
+    layer_registries(registry, get_registry_path())
+}
+
+fn layer_registries(registry: &Path, mut registry_path: Vec<PathBuf>) -> Vec<PathBuf> {
+    if !registry_path.iter().any(|p| p == registry) {
+        registry_path.insert(0, registry.to_path_buf());
+    }
+    registry_path.dedup();
+    registry_path
+}
+
+/// Finds the registry the instance with the given name is installed in, if any.
+#[must_use]
+pub fn find_instance_registry(registry: &Path, name: &str) -> Option<PathBuf> {
+    get_registries(registry)
+        .into_iter()
+        .find(|r| r.join(name).exists())
+}
+
+/// Fails if the given instance is provided by a registry other than the given one.
+/// Instances in shared registries are never modified, as those are typically read-only.
+fn ensure_owned(registry: &PathBuf, instance: &Instance<Local>) -> Result<()> {
+    let path = instance.path(registry);
+    let owned = canonicalize_normalized(registry)?.join(instance.name());
+    if path != owned {
+        anyhow::bail!(
+            "{} is provided by the shared registry {}, which can not be modified.",
+            instance.name(),
+            path.parent().unwrap_or(&path).to_string_lossy()
+        );
+    }
+    Ok(())
+}
+
+/// Walks up from `start` through all of its parent directories
+/// and returns the path to the closest `.renpy-version` file, if any.
+#[must_use]
+pub fn find_version_file(start: &Path) -> Option<PathBuf> {
+    let start = canonicalize_normalized(start).ok()?;
+    start
+        .ancestors()
+        .map(|dir| dir.join(VERSION_FILE_NAME))
+        .find(|path| path.is_file())
+}
+
+pub fn read_version_file(path: &Path) -> Result<(Version, InstanceOptions)> {
+    let content = fs::read_to_string(path)?;
+    parse_instance_name(content.trim())
+        .map_err(|e| anyhow!("Invalid version in {}: {e}", path.to_string_lossy()))
+}
+
+pub fn get_default_version(registry: &Path) -> Result<Option<(Version, InstanceOptions)>> {
+    let path = get_registries(registry)
+        .into_iter()
+        .map(|r| r.join(DEFAULT_VERSION_FILE_NAME))
+        .find(|p| p.exists());
+    match path {
+        Some(path) => Ok(Some(read_version_file(&path)?)),
+        None => Ok(None),
+    }
+}
+
+pub fn default(
+    registry: &Path,
+    version: Option<&Version>,
+    options: &InstanceOptions,
+) -> Result<()> {
+    let Some(version) = version else {
+        match get_default_version(registry)? {
+            Some((version, options)) => {
+                println!("{}", Instance::<Local>::new(version, options).name());
+            }
+            None => println!("No default version set."),
+        }
+        return Ok(());
+    };
+
+    let instance = Instance::<Local>::new(version.clone(), options.clone());
+
+    if !version.is_installed(registry, options) {
+        println!(
+            "Version {} is not installed yet, it will be installed on first launch.",
+            instance.name()
+        );
+    }
+
+    fs::write(
+        registry.join(DEFAULT_VERSION_FILE_NAME),
+        format!("{}\n", instance.name()),
+    )?;
+
+    println!("Default version set to {}.", instance.name());
+
+    Ok(())
+}
+
+pub fn pin(project: &Path, version: &Version, options: &InstanceOptions) -> Result<()> {
+    if !project.is_dir() {
+        anyhow::bail!(
+            "Project directory does not exist: {}",
+            project.to_string_lossy()
+        );
+    }
+
+    let instance = Instance::<Local>::new(version.clone(), options.clone());
+    let path = project.join(VERSION_FILE_NAME);
+
+    fs::write(&path, format!("{}\n", instance.name()))?;
+
+    println!("Pinned {} to {}.", path.to_string_lossy(), instance.name());
+
+    Ok(())
+}
+
 pub async fn get_available_versions(registry: &PathBuf, online: bool) -> Result<Vec<Version>> {
     let mut versions = vec![];
 
@@ -213,7 +531,9 @@ pub async fn get_available_versions(registry: &PathBuf, online: bool) -> Result<
                                 .ok_or(anyhow!("Unable to strip suffix."))?,
                         };
 
-                        if let Ok(version) = Version::from_str(href) {
+                        if let Ok(version) = Version::from_str(href)
+                            && version.label.is_none()
+                        {
                             versions.push(version);
                         }
 
@@ -227,7 +547,28 @@ pub async fn get_available_versions(registry: &PathBuf, online: bool) -> Result<
             rewriter.end()?;
         }
     } else {
-        for entry in fs::read_dir(registry)? {
+        for instance in get_installed_instances(registry)? {
+            if !versions.contains(&instance.version) {
+                versions.push(instance.version);
+            }
+        }
+    }
+
+    Ok(versions)
+}
+
+pub fn get_installed_instances(registry: &PathBuf) -> Result<Vec<Instance<Local>>> {
+    let mut instances: Vec<Instance<Local>> = vec![];
+
+    for layer in get_registries(registry) {
+        // Shared registries may not exist on every machine.
+        let entries = match fs::read_dir(&layer) {
+            Ok(entries) => entries,
+            Err(e) if layer == *registry => return Err(e.into()),
+            Err(_) => continue,
+        };
+
+        for entry in entries {
             let entry = entry?;
             let path = entry.path();
             let path = path
@@ -236,16 +577,30 @@ pub async fn get_available_versions(registry: &PathBuf, online: bool) -> Result<
                 .to_str()
                 .ok_or(anyhow!("Unable to get file name."))?;
 
-            if let Ok(version) = Version::from_str(path) {
-                versions.push(version);
+            if let Ok((version, options)) = parse_instance_name(path) {
+                let instance = Instance::new(version, options);
+                if !instances.iter().any(|i| i.name() == instance.name()) {
+                    instances.push(instance);
+                }
             }
         }
     }
 
-    Ok(versions)
+    Ok(instances)
 }
 
 pub async fn list(registry: &PathBuf, online: bool, num: usize, nightly: bool) -> Result<()> {
+    if !online {
+        let mut instances = get_installed_instances(registry)?;
+        instances.sort_by(|a, b| b.version.cmp(&a.version).then(a.name().cmp(&b.name())));
+
+        for instance in instances.iter().take(num) {
+            println!("{}", instance.name());
+        }
+
+        return Ok(());
+    }
+
     let versions = get_available_versions(registry, online).await?;
 
     let mut versions = if online {
@@ -266,20 +621,27 @@ pub async fn list(registry: &PathBuf, online: bool, num: usize, nightly: bool) -
     Ok(())
 }
 
-pub async fn show(registry: &PathBuf, version: &Version) -> Result<()> {
-    if version.is_installed(registry) {
+pub async fn show(
+    registry: &PathBuf,
+    version: &Version,
+    options: &InstanceOptions,
+    jdk_paths: &[PathBuf],
+) -> Result<()> {
+    if version.is_installed(registry, options) {
         println!("Version: {version}");
     } else {
         let versions = get_available_versions(registry, true).await?;
         if !versions.contains(version) {
-            anyhow::bail!("{} is not a valid version of Ren'Py.", version);
+            anyhow::bail!("{version} is not a valid version of Ren'Py.");
         }
     }
 
-    if version.is_installed(registry) {
-        let instance = version
-            .to_local(registry)
-            .expect("Unable to get local instance.");
+    if let Some(variant) = options.variant() {
+        println!("Variant: {variant}");
+    }
+
+    if version.is_installed(registry, options) {
+        let instance = version.to_local(registry, options)?;
 
         println!("Installed: Yes");
 
@@ -288,28 +650,280 @@ pub async fn show(registry: &PathBuf, version: &Version) -> Result<()> {
 
         println!("Location: {location}");
 
+        if let Some(target) = instance.link_target(registry) {
+            println!("Linked to: {}", target.to_string_lossy());
+        }
+
         let architecture = instance
             .architecture()
             .expect("Unable to get architecture.");
 
         println!("Architecture: {architecture}");
+
+        let architectures = instance.architectures(registry)?;
+
+        println!("Available Architectures: {}", architectures.join(", "));
     } else {
         println!("Installed: No");
     }
-    println!("SDK URL: {}", version.sdk_url()?);
-    println!("RAPT URL: {}", version.rapt_url()?);
+
+    match find_jdk(version, jdk_paths) {
+        Some(jdk) => println!(
+            "Java: OpenJDK {} at {}",
+            jdk.version,
+            jdk.path.to_string_lossy()
+        ),
+        None => println!("Java: OpenJDK {} not found", required_major(version)),
+    }
+
+    // Custom labels only exist locally, so there is nothing to download.
+    if version.label.is_none() {
+        println!("SDK URL: {}", version.sdk_url()?);
+        println!("RAPT URL: {}", version.rapt_url()?);
+    }
+
+    Ok(())
+}
+
+/// Returned by `launch` when Ren'Py exits unsuccessfully and its status is checked.
+/// Holds the crash reports Ren'Py wrote during the failed launch.
+#[derive(Debug)]
+pub struct LaunchError {
+    pub status: i32,
+    pub artifacts: Vec<PathBuf>,
+}
+
+impl std::fmt::Display for LaunchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Unable to launch Ren'Py: Status {}", self.status)?;
+        if !self.artifacts.is_empty() {
+            let artifacts = self
+                .artifacts
+                .iter()
+                .map(|p| p.to_string_lossy())
+                .collect::<Vec<_>>();
+            write!(f, "\nCrash artifacts: {}", artifacts.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for LaunchError {}
+
+/// The files Ren'Py writes into its base directory when it crashes.
+pub const CRASH_ARTIFACTS: [&str; 3] = ["traceback.txt", "errors.txt", "log.txt"];
+
+/// Finds all crash artifacts in the given directories that were modified after `since`.
+#[must_use]
+pub fn find_crash_artifacts(dirs: &[PathBuf], since: SystemTime) -> Vec<PathBuf> {
+    // File systems update modification times from a coarser clock than `SystemTime::now`,
+    // so files written right after the launch may appear to be slightly older than it.
+    let since = since.checked_sub(Duration::from_secs(1)).unwrap_or(since);
+
+    let mut artifacts = vec![];
+
+    for dir in dirs {
+        for name in CRASH_ARTIFACTS {
+            let path = dir.join(name);
+            let Ok(modified) = fs::metadata(&path).and_then(|m| m.modified()) else {
+                continue;
+            };
+            if modified >= since && !artifacts.contains(&path) {
+                artifacts.push(path);
+            }
+        }
+    }
+
+    artifacts
+}
+
+/// Extracts the part of a crash artifact that is relevant for the user.
+/// For tracebacks, this is the short traceback of the script before the full traceback of the engine.
+#[must_use]
+pub fn summarize_crash_artifact(path: &Path) -> Option<String> {
+    let name = path.file_name()?.to_str()?;
+    let content = fs::read_to_string(path).ok()?;
+
+    let summary = match name {
+        "traceback.txt" => content
+            .split("-- Full Traceback")
+            .next()
+            .unwrap_or(&content)
+            .trim_end()
+            .to_string(),
+        "errors.txt" => content.trim_end().to_string(),
+        _ => return None,
+    };
+
+    Some(format!("--- {} ---\n{summary}", path.to_string_lossy()))
+}
+
+/// Screen variants of other devices that can be emulated on desktop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ScreenVariant {
+    Phone,
+    Tablet,
+    Touch,
+    Web,
+}
+
+impl ScreenVariant {
+    /// The value of `RENPY_VARIANT`, mirroring the emulation modes of the Ren'Py launcher.
+    #[must_use]
+    pub fn renpy_variant(&self) -> &'static str {
+        match self {
+            ScreenVariant::Phone => "touch small phone android mobile",
+            ScreenVariant::Tablet => "touch medium tablet android mobile",
+            ScreenVariant::Touch => "touch",
+            ScreenVariant::Web => "web",
+        }
+    }
+}
+
+/// Checks that a warp target of the form `file.rpy:line` exists within the given project.
+/// The file name is matched against the trailing path components of the scripts in `game`,
+/// so `script.rpy` and `chapter1/script.rpy` both match `game/chapter1/script.rpy`.
+pub fn validate_warp(project: &Path, warp: &str) -> Result<()> {
+    let Some((file, line)) = warp.rsplit_once(':') else {
+        anyhow::bail!("Invalid warp target '{warp}', expected 'file.rpy:line'.");
+    };
+
+    let line = match line.parse::<usize>() {
+        Ok(line) if line > 0 => line,
+        _ => anyhow::bail!("Invalid line number in warp target '{warp}'."),
+    };
+
+    let game_dir = project.join("game");
+    if !game_dir.is_dir() {
+        anyhow::bail!(
+            "{} is not a Ren'Py project, the game directory is missing.",
+            project.to_string_lossy()
+        );
+    }
+
+    let file = PathBuf::from(file.replace('\\', "/"));
+    let mut matches = vec![];
+    for entry in jwalk::WalkDir::new(&game_dir) {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "rpy") {
+            continue;
+        }
+        if path.strip_prefix(&game_dir)?.ends_with(&file) {
+            matches.push(path);
+        }
+    }
+
+    let path = match matches.as_slice() {
+        [] => anyhow::bail!(
+            "Unable to find {} in {}.",
+            file.to_string_lossy(),
+            game_dir.to_string_lossy()
+        ),
+        [path] => path,
+        _ => {
+            let mut candidates = matches
+                .iter()
+                .map(|path| {
+                    format!(
+                        "  {}:{line}",
+                        path.strip_prefix(&game_dir).unwrap().to_string_lossy()
+                    )
+                })
+                .collect::<Vec<_>>();
+            candidates.sort();
+            anyhow::bail!(
+                "Warp target '{warp}' is ambiguous, use one of:\n{}",
+                candidates.join("\n")
+            );
+        }
+    };
+
+    let num_lines = fs::read_to_string(path)?.lines().count();
+    if line > num_lines {
+        anyhow::bail!(
+            "{} only has {num_lines} lines, unable to warp to line {line}.",
+            path.to_string_lossy()
+        );
+    }
 
     Ok(())
 }
 
+/// Whether to isolate a launched Ren'Py process from the user's persistent data and saves.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Sandbox {
+    #[default]
+    Disabled,
+    /// Launch with a throwaway home and save directory, which is removed afterwards.
+    Enabled,
+    /// Same as `Enabled`, but the sandbox directory is kept for inspection.
+    Keep,
+}
+
+struct SandboxDir {
+    path: PathBuf,
+    keep: bool,
+}
+
+impl SandboxDir {
+    /// Creates a temporary directory and points the home, save and persistent data
+    /// directories of the given command into it.
+    fn create(cmd: &mut Command, keep: bool) -> Result<Self> {
+        let path = env::temp_dir().join(format!(
+            "renutil-sandbox-{}-{}",
+            std::process::id(),
+            rand::random::<u32>()
+        ));
+
+        let home = path.join("home");
+        let saves = path.join("saves");
+        let multipersistent = path.join("multipersistent");
+
+        for dir in [&home, &saves, &multipersistent] {
+            fs::create_dir_all(dir)?;
+        }
+
+        cmd.env("HOME", &home)
+            .env("USERPROFILE", &home)
+            .env("APPDATA", home.join("AppData").join("Roaming"))
+            .env("XDG_CONFIG_HOME", home.join(".config"))
+            .env("XDG_DATA_HOME", home.join(".local").join("share"))
+            .env("RENPY_PATH_TO_SAVES", &saves)
+            .env("RENPY_MULTIPERSISTENT", &multipersistent);
+
+        println!("Sandbox: {}", path.to_string_lossy());
+
+        Ok(Self { path, keep })
+    }
+}
+
+impl Drop for SandboxDir {
+    fn drop(&mut self) {
+        if self.keep {
+            println!("Keeping sandbox at {}", self.path.to_string_lossy());
+        } else if let Err(e) = fs::remove_dir_all(&self.path) {
+            println!(
+                "Unable to remove sandbox at {}: {e}",
+                self.path.to_string_lossy()
+            );
+        }
+    }
+}
+
 pub async fn launch(
     registry: &PathBuf,
     version: Option<&Version>,
+    options: &InstanceOptions,
+    arch: Option<&str>,
+    sandbox: Sandbox,
+    variant: Option<ScreenVariant>,
     headless: bool,
     direct: bool,
     args: &[String],
     check_status: bool,
     auto_install: bool,
+    jdk_paths: &[PathBuf],
+    lock: LockCheck,
 ) -> Result<(ExitStatus, String, String)> {
     let auto_install = match std::env::var("RENUTIL_AUTOINSTALL") {
         Ok(val) => {
@@ -323,47 +937,56 @@ pub async fn launch(
         Err(_) => auto_install,
     };
 
-    if !direct && version.is_none() {
-        anyhow::bail!("Launcher mode requires a version to be specified via '-v <version>'.");
-    }
-
-    let version = match version {
-        Some(version) => Some(version.clone()),
-        None => {
-            if args.is_empty() {
-                None
-            } else {
-                let path = PathBuf::from(&args[0]);
-                if path.exists() {
-                    let renpy_version_path = path.join(".renpy-version");
-                    if renpy_version_path.exists() {
-                        let file_content = fs::read_to_string(renpy_version_path)?;
-                        Some(Version::from_str(file_content.trim())?)
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                }
-            }
+    let version = if let Some(version) = version {
+        Some((version.clone(), options.clone()))
+    } else {
+        // In direct mode, the first argument is the project directory,
+        // so a version pinned by the project takes precedence over the global default.
+        let pinned = match args.first().map(PathBuf::from) {
+            Some(path) if direct && path.exists() => match find_version_file(&path) {
+                Some(version_file) => Some(read_version_file(&version_file)?),
+                None => None,
+            },
+            _ => None,
+        };
+        match pinned {
+            Some(version) => Some(version),
+            None => get_default_version(registry)?,
         }
     };
 
-    let Some(version) = version else {
+    let Some((version, options)) = version else {
         anyhow::bail!(
-            "Could not determine Ren'Py version to launch with, supply it via '-v <version>'."
+            "Could not determine Ren'Py version to launch with, supply it via '-v <version>' or set a default via 'renutil default <version>'."
         );
     };
 
-    println!("Ren'Py Version: {version}");
+    println!(
+        "Ren'Py Version: {}",
+        Instance::<Local>::new(version.clone(), options.clone()).name()
+    );
+
+    if !version.is_installed(registry, &options) && auto_install {
+        install(registry, &version, false, false, &options, jdk_paths).await?;
+    }
 
-    if !version.is_installed(registry) && auto_install {
-        install(registry, &version, false, false, false).await?;
+    if lock != LockCheck::Skip
+        && direct
+        && let Some(project) = args.first().map(PathBuf::from)
+        && project.exists()
+    {
+        check_lock(
+            registry,
+            &project,
+            &version,
+            &options,
+            lock == LockCheck::Enforce,
+        )?;
     }
 
-    let instance = version.to_local(registry)?;
+    let instance = version.to_local(registry, &options)?;
 
-    let python = instance.python(registry)?;
+    let python = instance.python(registry, arch)?;
     let python = python.to_str().unwrap();
 
     let entrypoint = instance.entrypoint(registry);
@@ -386,6 +1009,10 @@ pub async fn launch(
         cmd.arg(launcher_path).args(args);
     }
 
+    if let Some(variant) = variant {
+        cmd.env("RENPY_VARIANT", variant.renpy_variant());
+    }
+
     if headless {
         unsafe {
             std::env::set_var("SDL_AUDIODRIVER", "dummy");
@@ -393,6 +1020,16 @@ pub async fn launch(
         };
     }
 
+    // Removed again once it goes out of scope, unless it should be kept.
+    let _sandbox_dir = match sandbox {
+        Sandbox::Disabled => None,
+        Sandbox::Enabled | Sandbox::Keep => {
+            Some(SandboxDir::create(cmd, sandbox == Sandbox::Keep)?)
+        }
+    };
+
+    let launch_start = SystemTime::now();
+
     let mut child = cmd.spawn()?;
 
     let child_stdout = child.stdout.take().unwrap();
@@ -430,13 +1067,6 @@ pub async fn launch(
 
     let status = child.wait()?;
 
-    if check_status && !status.success() {
-        anyhow::bail!(
-            "Unable to launch Ren'Py: Status {}",
-            status.code().unwrap_or(1)
-        );
-    }
-
     unsafe {
         match rpy_log_val_orig {
             Some(val) => std::env::set_var("RENPY_LOG_TO_STDOUT", val),
@@ -444,152 +1074,382 @@ pub async fn launch(
         }
     }
 
+    if check_status && !status.success() {
+        // Ren'Py writes its crash reports into the base directory of whatever it was running,
+        // which is the project in direct mode and the launcher otherwise.
+        let mut artifact_dirs = vec![];
+        if direct {
+            artifact_dirs.extend(args.first().map(PathBuf::from));
+        } else {
+            artifact_dirs.push(instance.path(registry).join("launcher"));
+            artifact_dirs.extend(args.iter().map(PathBuf::from).filter(|p| p.is_dir()));
+        }
+
+        let artifacts = find_crash_artifacts(&artifact_dirs, launch_start);
+        for artifact in &artifacts {
+            if let Some(summary) = summarize_crash_artifact(artifact) {
+                eprintln!("{summary}");
+            }
+        }
+
+        return Err(LaunchError {
+            status: status.code().unwrap_or(1),
+            artifacts,
+        }
+        .into());
+    }
+
     let out_stdout = result_stdout.lock().unwrap().join("\n");
     let out_stderr = result_stderr.lock().unwrap().join("\n");
 
     Ok((status, out_stdout, out_stderr))
 }
 
-pub async fn install(
-    registry: &PathBuf,
-    version: &Version,
-    no_cleanup: bool,
-    force: bool,
-    update_pickle: bool,
-) -> Result<()> {
-    let versions = get_available_versions(registry, true).await?;
-    if !versions.contains(version) {
-        anyhow::bail!("{} is not a valid version of Ren'Py.", version);
-    }
+/// Set to make `install` report the progress of its components as lines on stdout,
+/// for interfaces that run it in a child process like `renutil tui`.
+pub const PROGRESS_ENV: &str = "RENUTIL_PROGRESS";
 
-    let java_home = if let Ok(val) = env::var("JAVA_HOME") {
-        PathBuf::from(val)
-    } else {
-        let jdk_version = if version >= &Version::from_str("8.2.0").unwrap() {
-            "21"
-        } else {
-            "8"
-        };
-        anyhow::bail!(
-            "JAVA_HOME is not set. Please check if you need to install OpenJDK {jdk_version}"
-        );
-    };
+const PROGRESS_PREFIX: &str = "renutil-progress\t";
 
-    if version.is_installed(registry) {
-        if force {
-            println!("Forcing uninstallation of existing version {version}.");
-            uninstall(registry, version)?;
-        } else {
-            return Err(anyhow!("Version {} is already installed.", version));
+/// The progress of a component while it is being installed, i.e. the bytes downloaded so far.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstallProgress {
+    pub component: String,
+    pub state: String,
+    pub position: u64,
+    pub length: Option<u64>,
+}
+
+impl InstallProgress {
+    fn of(bar: &ProgressBar) -> Self {
+        Self {
+            component: bar.prefix(),
+            state: bar.message(),
+            position: bar.position(),
+            length: bar.length(),
         }
     }
 
-    let instance = version
-        .to_remote(registry)
-        .expect("Unable to get remote instance.");
+    /// Reads a progress line written by `install`, returns `None` for all other lines.
+    #[must_use]
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.strip_prefix(PROGRESS_PREFIX)?.splitn(4, '\t');
+        Some(Self {
+            component: fields.next()?.to_string(),
+            position: fields.next()?.parse().ok()?,
+            length: match fields.next()? {
+                "" => None,
+                length => Some(length.parse().ok()?),
+            },
+            state: fields.next()?.to_string(),
+        })
+    }
+}
 
-    let base_path = instance.path(registry);
+impl std::fmt::Display for InstallProgress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{PROGRESS_PREFIX}{}\t{}\t{}\t{}",
+            self.component,
+            self.position,
+            self.length
+                .map(|length| length.to_string())
+                .unwrap_or_default(),
+            self.state
+        )
+    }
+}
 
-    fs::create_dir_all(&base_path).expect("Unable to create directory.");
+/// A downloadable part of an SDK, extracted into `target` once it has been downloaded.
+struct Component {
+    name: &'static str,
+    url: Url,
+    target: PathBuf,
+    /// Optional components are not available for every version, i.e. Steam and Web support.
+    required: bool,
+}
 
-    let sdk_url = version.sdk_url()?;
-    let rapt_url = version.rapt_url()?;
-    let steam_url = version.steam_url()?;
-    let web_url = version.web_url()?;
+impl Component {
+    fn file_name(&self) -> &str {
+        self.url
+            .path_segments()
+            .and_then(Iterator::last)
+            .unwrap_or(self.name)
+    }
 
-    println!("Downloading Ren'Py {version}...");
-    let downloads = vec![
-        Download::new(
-            &sdk_url,
-            sdk_url.path_segments().unwrap().next_back().unwrap(),
-        ),
-        Download::new(
-            &rapt_url,
-            rapt_url.path_segments().unwrap().next_back().unwrap(),
-        ),
-        Download::new(
-            &steam_url,
-            steam_url.path_segments().unwrap().next_back().unwrap(),
-        ),
-        Download::new(
-            &web_url,
-            web_url.path_segments().unwrap().next_back().unwrap(),
-        ),
-    ];
-    let downloader = DownloaderBuilder::new().directory(registry.clone()).build();
-    downloader.download(&downloads).await;
+    /// Downloads and extracts the component, waiting for `after` before extracting if given.
+    /// Archives are taken from the cache if possible, otherwise they are added to it.
+    /// Returns the cached archive and its hash, or nothing if an optional component is not available for this version.
+    async fn install(
+        self,
+        cache: ArchiveCache,
+        bar: ProgressBar,
+        after: Option<oneshot::Receiver<()>>,
+    ) -> Result<Option<(PathBuf, String)>> {
+        bar.set_style(
+            ProgressStyle::with_template(
+                "{prefix:>6.bold} {bar:32.green/black} {bytes:>10}/{total_bytes:<10} {msg}",
+            )
+            .unwrap()
+            .progress_chars("━╾╴─"),
+        );
+        bar.set_message("verifying");
+
+        let file_name = self.file_name().to_string();
+        let cached = {
+            let (cache, url, file_name) = (cache.clone(), self.url.clone(), file_name.clone());
+            tokio::task::spawn_blocking(move || cache.get(&url, &file_name)).await??
+        };
+
+        let (archive, sha256) = if let Some(cached) = cached {
+            bar.set_message("cached");
+            cached
+        } else {
+            let partial = cache.partial_path()?;
+            let downloaded = download_file(&self.url, &partial, &bar).await;
+            if !matches!(downloaded, Ok(Some(_))) {
+                let _ = fs::remove_file(&partial);
+            }
+            let sha256 = match downloaded {
+                Ok(Some(sha256)) => Some(sha256),
+                Ok(None) if !self.required => {
+                    cache.insert_unavailable(&self.url)?;
+                    None
+                }
+                // Offline, optional components known to be unavailable are skipped.
+                Err(_) if !self.required && cache.is_unavailable(&self.url) => None,
+                Ok(None) => {
+                    bar.abandon_with_message("failed");
+                    anyhow::bail!("Unable to download {} from {}.", self.name, self.url);
+                }
+                Err(e) => {
+                    bar.abandon_with_message("failed");
+                    return Err(e);
+                }
+            };
+            let Some(sha256) = sha256 else {
+                bar.finish_with_message("not available");
+                return Ok(None);
+            };
+            (
+                cache.insert(&self.url, &file_name, &partial, &sha256)?,
+                sha256,
+            )
+        };
 
-    println!("Extracting SDK");
+        if let Some(after) = after {
+            bar.set_message("waiting");
+            after
+                .await
+                .map_err(|_| anyhow!("Unable to extract {}.", self.name))?;
+        }
 
-    let sdk_zip_path = registry.join(sdk_url.path_segments().unwrap().next_back().unwrap());
+        // Extraction progress is measured in bytes of the archive read so far.
+        bar.set_message("extracting");
+        bar.set_length(fs::metadata(&archive)?.len());
+        bar.set_position(0);
+        let target = self.target;
+        let source = archive.clone();
+        let extract_bar = bar.clone();
+        tokio::task::spawn_blocking(move || extract_archive(&source, &target, &extract_bar))
+            .await??;
+        bar.finish_with_message("done");
+
+        Ok(Some((archive, sha256)))
+    }
+}
 
-    if sdk_zip_path.extension().unwrap() == "bz2" {
-        let compressed_file = fs::File::open(&sdk_zip_path)?;
-        let tar_path = sdk_zip_path.with_extension("");
-        let mut tar_file = fs::File::create(&tar_path)?;
+/// Streams the response body into `path` while hashing it.
+/// Returns the SHA-256 hash of the file, or nothing if the server does not have it.
+async fn download_file(url: &Url, path: &Path, bar: &ProgressBar) -> Result<Option<String>> {
+    let mut response = reqwest::get(url.clone()).await?;
+    if !response.status().is_success() {
+        return Ok(None);
+    }
+    if let Some(length) = response.content_length() {
+        bar.set_length(length);
+    }
+    bar.set_message("downloading");
+
+    let mut hasher = Sha256::new();
+    let mut file = tokio::fs::File::create(path).await?;
+    while let Some(chunk) = response.chunk().await? {
+        file.write_all(&chunk).await?;
+        hasher.update(&chunk);
+        bar.inc(chunk.len() as u64);
+    }
+    file.flush().await?;
 
-        let mut decompressor = BzDecoder::new(compressed_file);
-        std::io::copy(&mut decompressor, &mut tar_file)?;
+    Ok(Some(format!("{:x}", hasher.finalize())))
+}
 
-        let tar_file = fs::File::open(&tar_path).unwrap();
-        let mut tar_archive = Archive::new(tar_file);
-        for file in tar_archive.entries().unwrap() {
+/// Unpacks an SDK archive into `target`, dropping its root directory.
+/// Tarballs are decompressed while they are unpacked, without writing the tar to disk.
+fn extract_archive(archive: &Path, target: &Path, bar: &ProgressBar) -> Result<()> {
+    let file = bar.wrap_read(fs::File::open(archive)?);
+    if archive.extension().is_some_and(|ext| ext == "bz2") {
+        let decoder = BzDecoder::new(BufReader::new(file));
+        let mut tar = Archive::new(decoder);
+        for file in tar.entries()? {
             let mut file = file?;
             let path = file.path()?.components().skip(1).collect::<PathBuf>();
             if path.as_os_str().is_empty() {
                 continue;
             }
-            file.unpack(base_path.join(path))?;
+            file.unpack(target.join(path))?;
         }
-
-        fs::remove_file(tar_path)?;
     } else {
-        let zip_data = fs::File::open(&sdk_zip_path)?;
-        let mut zip = zip::ZipArchive::new(zip_data)?;
-        zip.extract_unwrapped_root_dir(&base_path, root_dir_common_filter)?;
+        let mut zip = zip::ZipArchive::new(file)?;
+        zip.extract_unwrapped_root_dir(target, root_dir_common_filter)?;
+    }
+    Ok(())
+}
+
+pub async fn install(
+    registry: &PathBuf,
+    version: &Version,
+    no_cleanup: bool,
+    force: bool,
+    options: &InstanceOptions,
+    jdk_paths: &[PathBuf],
+) -> Result<()> {
+    // Versions whose SDK is cached are known to exist, so they can be installed offline.
+    let cache = ArchiveCache::open();
+    if !cache.contains(&version.sdk_url()?) {
+        let versions = get_available_versions(registry, true).await?;
+        if !versions.contains(version) {
+            anyhow::bail!("{version} is not a valid version of Ren'Py.");
+        }
     }
 
-    println!("Extracting RAPT");
+    let java_home = configure_java_home(version, jdk_paths)?;
 
-    let rapt_zip_path = registry.join(rapt_url.path_segments().unwrap().next_back().unwrap());
+    if version.is_installed(registry, options) {
+        if force {
+            println!("Forcing uninstallation of existing version {version}.");
+            uninstall(registry, version, options)?;
+        } else {
+            return Err(anyhow!("Version {version} is already installed."));
+        }
+    }
 
-    let zip_data = fs::File::open(&rapt_zip_path)?;
-    let mut zip = zip::ZipArchive::new(zip_data)?;
-    zip.extract_unwrapped_root_dir(&base_path.join("rapt"), root_dir_common_filter)?;
+    let instance = version
+        .to_remote(registry, options)
+        .expect("Unable to get remote instance.");
 
-    let steam_zip_path = registry.join(steam_url.path_segments().unwrap().next_back().unwrap());
-    if steam_zip_path.exists() {
-        println!("Extracting Steam support");
+    let base_path = instance.path(registry);
 
-        let zip_data = fs::File::open(&steam_zip_path)?;
-        let mut zip = zip::ZipArchive::new(zip_data)?;
-        zip.extract_unwrapped_root_dir(&base_path.join("lib"), root_dir_common_filter)?;
-    }
+    fs::create_dir_all(&base_path).expect("Unable to create directory.");
+
+    println!("Downloading Ren'Py {version}...");
 
-    let web_zip_path = registry.join(web_url.path_segments().unwrap().next_back().unwrap());
-    if web_zip_path.exists() {
-        println!("Extracting Web support");
+    let components = [
+        Component {
+            name: "SDK",
+            url: version.sdk_url()?,
+            target: base_path.clone(),
+            required: true,
+        },
+        Component {
+            name: "RAPT",
+            url: version.rapt_url()?,
+            target: base_path.join("rapt"),
+            required: true,
+        },
+        Component {
+            name: "Steam",
+            url: version.steam_url()?,
+            target: base_path.join("lib"),
+            required: false,
+        },
+        Component {
+            name: "Web",
+            url: version.web_url()?,
+            target: base_path.join("web"),
+            required: false,
+        },
+    ];
 
-        let zip_data = fs::File::open(&web_zip_path)?;
-        let mut zip = zip::ZipArchive::new(zip_data)?;
-        zip.extract_unwrapped_root_dir(&base_path.join("web"), root_dir_common_filter)?;
+    // Every component is extracted as soon as its download completes, while the others are still
+    // downloading. Steam support is extracted into the lib directory of the SDK, so it waits for
+    // the SDK to be extracted first.
+    let progress = MultiProgress::new();
+    let (sdk_extracted, sdk_done) = oneshot::channel();
+    let (mut sdk_extracted, mut sdk_done) = (Some(sdk_extracted), Some(sdk_done));
+    let mut handles = vec![];
+    let mut bars = vec![];
+    for component in components {
+        let bar = progress.add(ProgressBar::new(0).with_prefix(component.name));
+        bars.push(bar.clone());
+        let (signal, after) = match component.name {
+            "SDK" => (sdk_extracted.take(), None),
+            "Steam" => (None, sdk_done.take()),
+            _ => (None, None),
+        };
+        let cache = cache.clone();
+        let record = (component.name, component.url.to_string());
+        handles.push((
+            record,
+            tokio::spawn(async move {
+                let installed = component.install(cache, bar, after).await;
+                if let (Ok(Some(_)), Some(signal)) = (&installed, signal) {
+                    let _ = signal.send(());
+                }
+                installed
+            }),
+        ));
     }
 
-    if !no_cleanup {
-        println!("Cleaning up temporary files");
-        fs::remove_file(sdk_zip_path).expect("Unable to remove SDK archive.");
-        fs::remove_file(rapt_zip_path).expect("Unable to remove RAPT archive.");
-        if steam_zip_path.exists() {
-            fs::remove_file(steam_zip_path).expect("Unable to remove Steam archive.");
+    let reporter = env::var_os(PROGRESS_ENV).is_some().then(|| {
+        let bars = bars.clone();
+        tokio::spawn(async move {
+            loop {
+                for bar in &bars {
+                    println!("{}", InstallProgress::of(bar));
+                }
+                tokio::time::sleep(Duration::from_millis(200)).await;
+            }
+        })
+    });
+
+    let mut archives = vec![];
+    let mut records = vec![];
+    let mut result = Ok(());
+    for ((component, url), handle) in handles {
+        match handle.await? {
+            Ok(Some((archive, sha256))) => {
+                archives.push(archive);
+                records.push(ArchiveRecord {
+                    component: component.to_string(),
+                    url,
+                    sha256,
+                });
+            }
+            Ok(None) => {}
+            Err(e) => result = result.and(Err(e)),
+        }
+    }
+    if let Some(reporter) = reporter {
+        reporter.abort();
+        for bar in &bars {
+            println!("{}", InstallProgress::of(bar));
         }
-        if web_zip_path.exists() {
-            fs::remove_file(web_zip_path).expect("Unable to remove Web archive.");
+    }
+    result?;
+
+    // Archives live in the cache, but are kept next to the registry on request like before.
+    if no_cleanup {
+        for archive in archives {
+            if let Some(file_name) = archive.file_name() {
+                fs::copy(&archive, registry.join(file_name))?;
+            }
         }
     }
 
-    let instance = version.to_local(registry)?;
+    let instance = version.to_local(registry, options)?;
 
-    let python = instance.python(registry)?;
+    let python = instance.python(registry, None)?;
 
     #[cfg(target_family = "unix")]
     {
@@ -675,6 +1535,9 @@ pub async fn install(
         fs::copy(android_keystore, bundle_keystore)?;
     }
 
+    // Every modification of the SDK is recorded under a stable id, so `renkit.lock` can tell instances apart.
+    let mut patches = vec![];
+
     println!("Patching SSL issue in RAPT");
     let interface_path = base_path.join("rapt/buildlib/rapt/interface.py");
     let content = fs::read_to_string(&interface_path)?;
@@ -685,6 +1548,7 @@ pub async fn install(
         "import ssl; ssl._create_default_https_context = ssl._create_unverified_context",
     );
     fs::write(&interface_path, lines.join("\n"))?;
+    patches.push("rapt-unverified-ssl".to_string());
 
     #[cfg(target_family = "windows")]
     {
@@ -699,6 +1563,7 @@ pub async fn install(
             &plat_path,
             content.replace("__file__", r"__file__.replace('\\\\?\\', '')"),
         )?;
+        patches.push("rapt-windows-extended-paths".to_string());
     }
 
     println!("Installing RAPT");
@@ -710,6 +1575,7 @@ pub async fn install(
         std::os::windows::fs::symlink_dir(base_path.join("renpy"), base_path.join("rapt/renpy"))?;
         #[cfg(target_family = "unix")]
         std::os::unix::fs::symlink(base_path.join("renpy"), base_path.join("rapt/renpy"))?;
+        patches.push("rapt-renpy-link".to_string());
     }
 
     println!("Patching import issue in android.py");
@@ -721,6 +1587,7 @@ pub async fn install(
         .filter(|line| !line.contains("import pygame_sdl2"))
         .collect();
     fs::write(&interface_path, lines.join("\n"))?;
+    patches.push("android-no-pygame-import".to_string());
 
     unsafe { env::set_var("RAPT_NO_TERMS", "1") };
 
@@ -748,6 +1615,7 @@ pub async fn install(
             .to_string();
         fs::write(path, content)?;
     }
+    patches.push("gradle-xmx8g".to_string());
 
     println!("Installing Android SDK");
     #[cfg(target_family = "windows")]
@@ -773,13 +1641,13 @@ pub async fn install(
     }
 
     let mut cmd = Command::new(&sdkmanager);
-    cmd.arg("build-tools;29.0.2");
+    cmd.arg(format!("build-tools;{ANDROID_BUILD_TOOLS}"));
     let status = cmd.status()?;
     if !status.success() {
         anyhow::bail!("Unable to install Android SDK build tools.");
     }
 
-    if update_pickle {
+    if options.update_pickle {
         println!("Increasing default pickle protocol from 2 to 5");
         let pickle_path = base_path.join("renpy/compat/pickle.py");
         let content = fs::read_to_string(&pickle_path)?;
@@ -787,13 +1655,31 @@ pub async fn install(
             &pickle_path,
             content.replace("PROTOCOL = 2", "PROTOCOL = 5"),
         )?;
+        patches.push("pickle-protocol-5".to_string());
+    }
+
+    InstanceManifest {
+        version: version.to_string(),
+        options: options.clone(),
+        archives: records,
+        patches,
+        android_build_tools: Some(ANDROID_BUILD_TOOLS.into()),
+    }
+    .write(&base_path)?;
+
+    let auto_dedupe = std::env::var("RENUTIL_DEDUPE")
+        .is_ok_and(|val| matches!(val.to_lowercase().as_str(), "true" | "1"));
+    if auto_dedupe {
+        dedupe(registry, false)?;
     }
 
     Ok(())
 }
 
-pub fn cleanup(registry: &PathBuf, version: &Version) -> Result<()> {
-    let instance = version.to_local(registry)?;
+pub fn cleanup(registry: &PathBuf, version: &Version, options: &InstanceOptions) -> Result<()> {
+    let instance = version.to_local(registry, options)?;
+
+    ensure_owned(registry, &instance)?;
 
     let path = instance.path(registry);
 
@@ -813,8 +1699,17 @@ pub fn cleanup(registry: &PathBuf, version: &Version) -> Result<()> {
     Ok(())
 }
 
-pub fn uninstall(registry: &PathBuf, version: &Version) -> Result<()> {
-    let instance = version.to_local(registry)?;
+pub fn uninstall(registry: &PathBuf, version: &Version, options: &InstanceOptions) -> Result<()> {
+    let instance = version.to_local(registry, options)?;
+
+    ensure_owned(registry, &instance)?;
+
+    if instance.link_target(registry).is_some() {
+        anyhow::bail!(
+            "{} is a linked instance, use 'renutil unlink' to remove it.",
+            instance.name()
+        );
+    }
 
     let path = instance.path(registry);
 
@@ -822,3 +1717,157 @@ pub fn uninstall(registry: &PathBuf, version: &Version) -> Result<()> {
 
     Ok(())
 }
+
+pub fn link(
+    registry: &Path,
+    path: &Path,
+    version: &Version,
+    options: &InstanceOptions,
+) -> Result<()> {
+    let target = canonicalize_normalized(path)
+        .map_err(|e| anyhow!("Unable to resolve {}: {e}", path.to_string_lossy()))?;
+
+    if !target.join("renpy.py").exists() {
+        anyhow::bail!(
+            "{} does not look like a Ren'Py SDK, renpy.py is missing.",
+            target.to_string_lossy()
+        );
+    }
+
+    let instance = version.to_remote(registry, options)?;
+    let link_path = instance.path(registry);
+
+    // The link may be dangling, in which case `exists` would report it as missing.
+    if fs::symlink_metadata(&link_path).is_ok() {
+        anyhow::bail!("{} already exists in the registry.", instance.name());
+    }
+
+    #[cfg(target_family = "windows")]
+    std::os::windows::fs::symlink_dir(&target, &link_path)?;
+    #[cfg(target_family = "unix")]
+    std::os::unix::fs::symlink(&target, &link_path)?;
+
+    println!(
+        "Linked {} as {}.",
+        target.to_string_lossy(),
+        instance.name()
+    );
+
+    Ok(())
+}
+
+pub fn unlink(registry: &PathBuf, version: &Version, options: &InstanceOptions) -> Result<()> {
+    let instance = Instance::<Local>::new(version.clone(), options.clone());
+
+    if instance.link_target(registry).is_none() {
+        anyhow::bail!("{} is not a linked instance.", instance.name());
+    }
+
+    ensure_owned(registry, &instance)?;
+
+    let link_path = instance.path(registry);
+
+    // Directory symlinks on Windows have to be removed as directories.
+    #[cfg(target_family = "windows")]
+    fs::remove_dir(&link_path)?;
+    #[cfg(target_family = "unix")]
+    fs::remove_file(&link_path)?;
+
+    println!("Unlinked {}.", instance.name());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        InstallProgress, Instance, InstanceOptions, Local, layer_registries, parse_instance_name,
+        validate_warp,
+    };
+    use std::{env, fs, path::PathBuf};
+
+    #[test]
+    fn instance_names() {
+        let (version, options) = parse_instance_name("8.3.4").unwrap();
+        assert_eq!(version.to_string(), "8.3.4");
+        assert_eq!(options, InstanceOptions::default());
+        assert_eq!(Instance::<Local>::new(version, options).name(), "8.3.4");
+
+        let (version, options) = parse_instance_name("8.3.4@pickle5").unwrap();
+        assert_eq!(version.to_string(), "8.3.4");
+        assert!(options.update_pickle);
+        assert_eq!(
+            Instance::<Local>::new(version, options).name(),
+            "8.3.4@pickle5"
+        );
+
+        assert!(parse_instance_name("8.3.4@unknown").is_err());
+        assert!(parse_instance_name("bad-version-string@pickle5").is_err());
+    }
+
+    #[test]
+    fn registry_layering() {
+        let user = PathBuf::from("/home/user/.renutil");
+        let shared = PathBuf::from("/opt/renkit/registry");
+
+        assert_eq!(
+            layer_registries(&user, vec![shared.clone()]),
+            vec![user.clone(), shared.clone()]
+        );
+        assert_eq!(
+            layer_registries(&user, vec![shared.clone(), user.clone()]),
+            vec![shared.clone(), user.clone()]
+        );
+        assert_eq!(layer_registries(&user, vec![user.clone()]), vec![user]);
+    }
+
+    #[test]
+    fn install_progress() {
+        let progress = InstallProgress {
+            component: "SDK".into(),
+            state: "not available".into(),
+            position: 512,
+            length: None,
+        };
+        assert_eq!(
+            InstallProgress::parse(&progress.to_string()),
+            Some(progress.clone())
+        );
+        let progress = InstallProgress {
+            length: Some(1024),
+            ..progress
+        };
+        assert_eq!(
+            InstallProgress::parse(&progress.to_string()),
+            Some(progress)
+        );
+        assert_eq!(InstallProgress::parse("Downloading Ren'Py 8.3.4..."), None);
+    }
+
+    #[test]
+    fn warp_targets() {
+        let project = env::temp_dir().join(format!("renkit-warp-{}", rand::random::<u32>()));
+        fs::create_dir_all(project.join("game/chapter1")).unwrap();
+        fs::create_dir_all(project.join("game/chapter2")).unwrap();
+        fs::write(
+            project.join("game/script.rpy"),
+            "label start:\n    return\n",
+        )
+        .unwrap();
+        fs::write(project.join("game/chapter1/intro.rpy"), "label one:\n").unwrap();
+        fs::write(project.join("game/chapter2/intro.rpy"), "label two:\n").unwrap();
+
+        assert!(validate_warp(&project, "script.rpy:2").is_ok());
+        assert!(validate_warp(&project, "script.rpy:3").is_err());
+        // Only whole path components match.
+        assert!(validate_warp(&project, "ript.rpy:1").is_err());
+        assert!(validate_warp(&project, "chapter1/intro.rpy:1").is_ok());
+        let ambiguous = validate_warp(&project, "intro.rpy:1")
+            .unwrap_err()
+            .to_string();
+        assert!(ambiguous.contains("chapter1/intro.rpy:1"));
+        assert!(ambiguous.contains("chapter2/intro.rpy:1"));
+
+        fs::remove_dir_all(project).unwrap();
+    }
+}
@@ -0,0 +1,678 @@
+use crate::jdk::find_jdk;
+use crate::renutil::{
+    InstallProgress, PROGRESS_ENV, get_available_versions, get_installed_instances,
+    parse_instance_name,
+};
+use anyhow::{Result, anyhow};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::{
+    collections::HashMap,
+    env,
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::Arc,
+};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    process::Command,
+    sync::mpsc,
+};
+
+/// Requests with larger bodies are rejected, none of the endpoints need more than a few arguments.
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+/// Limits the request line and every header line, so clients can not exhaust memory before sending a body.
+const MAX_LINE_LENGTH: usize = 8 * 1024;
+const MAX_HEADERS: usize = 64;
+
+struct Request {
+    method: String,
+    path: String,
+    query: HashMap<String, String>,
+    /// Header values by their lowercased names.
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+}
+
+/// Guards the API against other local processes and web pages, which can reach `127.0.0.1` as well.
+/// Requests need the token printed at startup and a `Host` of the server itself, which rules out
+/// DNS rebinding. Requests with a body must be JSON, which browsers can not send cross-origin
+/// without a preflight request.
+struct Session {
+    token: String,
+    port: u16,
+}
+
+impl Session {
+    fn authorize(&self, request: &Request) -> Result<(), Response> {
+        let host = request.header("host").unwrap_or_default();
+        if host != format!("127.0.0.1:{}", self.port) && host != format!("localhost:{}", self.port)
+        {
+            return Err(Response::error(403, format!("Invalid host: {host}")));
+        }
+
+        let token = request
+            .header("authorization")
+            .and_then(|value| value.strip_prefix("Bearer "));
+        if token != Some(self.token.as_str()) {
+            return Err(Response::error(401, "Missing or invalid token."));
+        }
+
+        if request.method == "POST" {
+            let content_type = request.header("content-type").unwrap_or_default();
+            let mime = content_type.split(';').next().unwrap_or_default().trim();
+            if !mime.eq_ignore_ascii_case("application/json") {
+                return Err(Response::error(
+                    415,
+                    "Requests must have the content type application/json.",
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+struct Response {
+    status: u16,
+    body: Value,
+}
+
+impl Response {
+    fn ok(body: Value) -> Self {
+        Self { status: 200, body }
+    }
+
+    fn error(status: u16, message: impl std::fmt::Display) -> Self {
+        Self {
+            status,
+            body: json!({ "error": message.to_string() }),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct InstallRequest {
+    version: String,
+    #[serde(default)]
+    update_pickle: bool,
+    #[serde(default)]
+    force: bool,
+    #[serde(default)]
+    no_cleanup: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(clippy::struct_excessive_bools)]
+struct LaunchRequest {
+    version: Option<String>,
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(default)]
+    direct: bool,
+    #[serde(default)]
+    headless: bool,
+    #[serde(default)]
+    check_status: bool,
+    #[serde(default)]
+    sandbox: bool,
+    arch: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LintRequest {
+    project: PathBuf,
+    version: Option<String>,
+    #[serde(default = "default_as_true")]
+    sandbox: bool,
+}
+
+fn default_as_true() -> bool {
+    true
+}
+
+/// Serves the registry over a local HTTP/JSON interface until the process is terminated.
+///
+/// Read-only endpoints answer with a single JSON document. Installing, launching and linting
+/// stream newline-delimited JSON events instead, one per line of output, followed by an `exit` event.
+/// These run in a separate `renutil` process, since launching Ren'Py modifies the process environment.
+///
+/// Every request has to send the token printed at startup as `Authorization: Bearer <token>`.
+/// The JDK paths are searched in addition to the common install locations.
+pub async fn serve(registry: PathBuf, jdk_paths: Vec<PathBuf>, port: u16) -> Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port)).await?;
+    let session = Arc::new(Session {
+        token: format!("{:032x}", rand::random::<u128>()),
+        port: listener.local_addr()?.port(),
+    });
+    let jdk_paths = Arc::new(jdk_paths);
+
+    println!("Listening on http://{}", listener.local_addr()?);
+    println!("Token: {}", session.token);
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let registry = registry.clone();
+        let session = session.clone();
+        let jdk_paths = jdk_paths.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, &registry, &jdk_paths, &session).await {
+                eprintln!("Unable to handle request: {e}");
+            }
+        });
+    }
+}
+
+async fn handle_connection(
+    mut stream: TcpStream,
+    registry: &PathBuf,
+    jdk_paths: &[PathBuf],
+    session: &Session,
+) -> Result<()> {
+    let request = match read_request(&mut BufReader::new(&mut stream)).await {
+        Ok(request) => request,
+        Err(e) => return write_response(&mut stream, Response::error(400, e)).await,
+    };
+
+    if let Err(response) = session.authorize(&request) {
+        println!("{} {} - {}", request.method, request.path, response.status);
+        return write_response(&mut stream, response).await;
+    }
+
+    println!("{} {}", request.method, request.path);
+
+    let segments = request
+        .path
+        .trim_matches('/')
+        .split('/')
+        .map(percent_decode)
+        .collect::<Vec<_>>();
+    let segments = segments.iter().map(String::as_str).collect::<Vec<_>>();
+
+    let response = match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["instances"]) => instances(registry),
+        ("GET", ["instances", name]) => instance(registry, jdk_paths, name),
+        ("GET", ["versions"]) => versions(registry, &request.query).await,
+        ("POST", ["install"]) => match serde_json::from_slice::<InstallRequest>(&request.body) {
+            Ok(body) => {
+                return stream_renutil(&mut stream, registry, jdk_paths, install_args(&body)).await;
+            }
+            Err(e) => Response::error(400, e),
+        },
+        ("POST", ["launch"]) => match serde_json::from_slice::<LaunchRequest>(&request.body) {
+            Ok(body) => {
+                return stream_renutil(&mut stream, registry, jdk_paths, launch_args(&body)).await;
+            }
+            Err(e) => Response::error(400, e),
+        },
+        ("POST", ["lint"]) => match serde_json::from_slice::<LintRequest>(&request.body) {
+            Ok(body) => {
+                return stream_renutil(&mut stream, registry, jdk_paths, lint_args(&body)).await;
+            }
+            Err(e) => Response::error(400, e),
+        },
+        _ => Response::error(
+            404,
+            format!("No such endpoint: {} {}", request.method, request.path),
+        ),
+    };
+
+    write_response(&mut stream, response).await
+}
+
+/// Reads a line of at most `MAX_LINE_LENGTH` bytes, returns an empty string at the end of the stream.
+async fn read_line(reader: &mut (impl AsyncBufRead + Unpin)) -> Result<String> {
+    let mut line = String::new();
+    (&mut *reader)
+        .take(MAX_LINE_LENGTH as u64 + 1)
+        .read_line(&mut line)
+        .await?;
+    if line.len() > MAX_LINE_LENGTH {
+        anyhow::bail!("Request line or header exceeds {MAX_LINE_LENGTH} bytes.");
+    }
+    Ok(line)
+}
+
+async fn read_request(reader: &mut (impl AsyncBufRead + Unpin)) -> Result<Request> {
+    let line = read_line(reader).await?;
+    let mut parts = line.split_whitespace();
+    let method = parts
+        .next()
+        .ok_or(anyhow!("Missing request method."))?
+        .to_string();
+    let target = parts.next().ok_or(anyhow!("Missing request target."))?;
+
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path.to_string(), parse_query(query)),
+        None => (target.to_string(), HashMap::new()),
+    };
+
+    let mut headers = HashMap::new();
+    for count in 0.. {
+        let header = read_line(reader).await?;
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if count == MAX_HEADERS {
+            anyhow::bail!("Request has more than {MAX_HEADERS} headers.");
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+        }
+    }
+
+    let content_length = match headers.get("content-length") {
+        Some(value) => value.parse::<usize>()?,
+        None => 0,
+    };
+
+    if content_length > MAX_BODY_SIZE {
+        anyhow::bail!("Request body exceeds {MAX_BODY_SIZE} bytes.");
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).await?;
+
+    Ok(Request {
+        method,
+        path,
+        query,
+        headers,
+        body,
+    })
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        415 => "Unsupported Media Type",
+        _ => "Internal Server Error",
+    }
+}
+
+async fn write_response(stream: &mut TcpStream, response: Response) -> Result<()> {
+    let body = serde_json::to_string(&response.body)?;
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason_phrase(response.status),
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Decodes `%XX` escapes, i.e. `8.3.4%40pickle5` to `8.3.4@pickle5`.
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%'
+            && let Some(byte) = value
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        {
+            decoded.push(byte);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn parse_query(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((key, value)) => (percent_decode(key), percent_decode(value)),
+            None => (percent_decode(pair), String::new()),
+        })
+        .collect()
+}
+
+fn query_flag(query: &HashMap<String, String>, key: &str) -> bool {
+    query
+        .get(key)
+        .is_some_and(|value| matches!(value.as_str(), "" | "1" | "true"))
+}
+
+fn instances(registry: &PathBuf) -> Response {
+    let mut instances = match get_installed_instances(registry) {
+        Ok(instances) => instances,
+        Err(e) => return Response::error(500, e),
+    };
+    instances.sort_by(|a, b| b.version.cmp(&a.version).then(a.name().cmp(&b.name())));
+
+    Response::ok(
+        instances
+            .iter()
+            .map(|instance| {
+                json!({
+                    "name": instance.name(),
+                    "version": instance.version.to_string(),
+                    "variant": instance.options.variant(),
+                    "path": instance.path(registry),
+                    "linked_to": instance.link_target(registry),
+                })
+            })
+            .collect(),
+    )
+}
+
+fn instance(registry: &Path, jdk_paths: &[PathBuf], name: &str) -> Response {
+    let (version, options) = match parse_instance_name(name) {
+        Ok(instance) => instance,
+        Err(e) => return Response::error(400, format!("Invalid version: {name} - {e}")),
+    };
+
+    if !version.is_installed(registry, &options) {
+        return Response::error(404, format!("{name} is not installed."));
+    }
+
+    let instance = match version.to_local(registry, &options) {
+        Ok(instance) => instance,
+        Err(e) => return Response::error(500, e),
+    };
+
+    let java = find_jdk(&version, jdk_paths).map(|jdk| {
+        json!({
+            "version": jdk.version,
+            "path": jdk.path,
+        })
+    });
+
+    Response::ok(json!({
+        "name": instance.name(),
+        "version": version.to_string(),
+        "variant": options.variant(),
+        "path": instance.path(registry),
+        "linked_to": instance.link_target(registry),
+        "architecture": instance.architecture().ok(),
+        "architectures": instance.architectures(registry).unwrap_or_default(),
+        "java": java,
+    }))
+}
+
+async fn versions(registry: &PathBuf, query: &HashMap<String, String>) -> Response {
+    let online = query_flag(query, "online");
+    let nightly = query_flag(query, "nightly");
+
+    let mut versions = match get_available_versions(registry, online).await {
+        Ok(versions) => versions,
+        Err(e) => return Response::error(500, e),
+    };
+    versions.retain(|v| !v.nightly || nightly);
+    versions.sort_by(|a, b| b.cmp(a));
+
+    Response::ok(versions.iter().map(ToString::to_string).collect())
+}
+
+fn install_args(request: &InstallRequest) -> Vec<String> {
+    let mut args = vec!["install".to_string(), request.version.clone()];
+    if request.update_pickle {
+        args.push("--update-pickle".into());
+    }
+    if request.force {
+        args.push("--force".into());
+    }
+    if request.no_cleanup {
+        args.push("--no-cleanup".into());
+    }
+    args
+}
+
+fn launch_args(request: &LaunchRequest) -> Vec<String> {
+    let mut args = vec!["launch".to_string()];
+    if let Some(version) = &request.version {
+        args.extend(["--version".into(), version.clone()]);
+    }
+    if let Some(arch) = &request.arch {
+        args.extend(["--arch".into(), arch.clone()]);
+    }
+    if request.direct {
+        args.push("--direct".into());
+    }
+    if request.headless {
+        args.push("--headless".into());
+    }
+    if request.check_status {
+        args.push("--check-status".into());
+    }
+    if request.sandbox {
+        args.push("--sandbox".into());
+    }
+    args.push("--".into());
+    args.extend(request.args.iter().cloned());
+    args
+}
+
+fn lint_args(request: &LintRequest) -> Vec<String> {
+    launch_args(&LaunchRequest {
+        version: request.version.clone(),
+        args: vec![request.project.to_string_lossy().to_string(), "lint".into()],
+        direct: true,
+        headless: true,
+        check_status: true,
+        sandbox: request.sandbox,
+        arch: None,
+    })
+}
+
+/// Runs `renutil` with the given arguments and streams its output as newline-delimited JSON events.
+async fn stream_renutil(
+    stream: &mut TcpStream,
+    registry: &PathBuf,
+    jdk_paths: &[PathBuf],
+    args: Vec<String>,
+) -> Result<()> {
+    let exe = env::current_exe()?;
+
+    let mut child = match Command::new(exe)
+        .arg("--registry")
+        .arg(registry)
+        .args(
+            jdk_paths
+                .iter()
+                .flat_map(|path| [OsStr::new("--jdk-path"), path.as_os_str()]),
+        )
+        .args(&args)
+        .env(PROGRESS_ENV, "1")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => return write_response(stream, Response::error(500, e)).await,
+    };
+
+    stream
+        .write_all(
+            b"HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n",
+        )
+        .await?;
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    if let Some(stdout) = child.stdout.take() {
+        tokio::spawn(forward_lines(stdout, "stdout", tx.clone()));
+    }
+    if let Some(stderr) = child.stderr.take() {
+        tokio::spawn(forward_lines(stderr, "stderr", tx.clone()));
+    }
+    drop(tx);
+
+    // If the client disconnects, writing fails and the child is killed once it is dropped.
+    while let Some(event) = rx.recv().await {
+        write_chunk(stream, &event).await?;
+    }
+
+    let status = child.wait().await?;
+    write_chunk(stream, &json!({ "event": "exit", "status": status.code() })).await?;
+
+    stream.write_all(b"0\r\n\r\n").await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
+
+async fn forward_lines(
+    pipe: impl AsyncRead + Unpin,
+    name: &'static str,
+    tx: mpsc::UnboundedSender<Value>,
+) {
+    let mut lines = BufReader::new(pipe).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if tx.send(line_event(name, &line)).is_err() {
+            break;
+        }
+    }
+}
+
+/// Turns the progress lines of `install` into `progress` events and all other lines into output events.
+fn line_event(name: &str, line: &str) -> Value {
+    match InstallProgress::parse(line) {
+        Some(progress) => json!({
+            "event": "progress",
+            "component": progress.component,
+            "state": progress.state,
+            "position": progress.position,
+            "length": progress.length,
+        }),
+        None => json!({ "event": name, "line": line }),
+    }
+}
+
+async fn write_chunk(stream: &mut TcpStream, event: &Value) -> Result<()> {
+    let line = format!("{event}\n");
+    stream
+        .write_all(format!("{:x}\r\n{line}\r\n", line.len()).as_bytes())
+        .await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        MAX_LINE_LENGTH, Session, line_event, parse_query, percent_decode, query_flag, read_request,
+    };
+    use crate::renutil::InstallProgress;
+    use serde_json::json;
+
+    #[test]
+    fn query_parsing() {
+        assert_eq!(percent_decode("8.3.4%40pickle5"), "8.3.4@pickle5");
+        assert_eq!(percent_decode("100%"), "100%");
+
+        let query = parse_query("online=true&nightly&num=5");
+        assert!(query_flag(&query, "online"));
+        assert!(query_flag(&query, "nightly"));
+        assert!(!query_flag(&query, "missing"));
+        assert_eq!(query.get("num").map(String::as_str), Some("5"));
+    }
+
+    #[test]
+    fn progress_events() {
+        let progress = InstallProgress {
+            component: "SDK".into(),
+            state: "downloading".into(),
+            position: 512,
+            length: Some(1024),
+        };
+        assert_eq!(
+            line_event("stdout", &progress.to_string()),
+            json!({
+                "event": "progress",
+                "component": "SDK",
+                "state": "downloading",
+                "position": 512,
+                "length": 1024,
+            })
+        );
+        assert_eq!(
+            line_event("stderr", "Installing..."),
+            json!({ "event": "stderr", "line": "Installing..." })
+        );
+    }
+
+    #[test]
+    fn authorization() {
+        let session = Session {
+            token: "secret".into(),
+            port: 8780,
+        };
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let check = |request: &str| {
+            let request = runtime
+                .block_on(read_request(&mut request.as_bytes()))
+                .unwrap();
+            session
+                .authorize(&request)
+                .map_err(|response| response.status)
+        };
+
+        let auth = "Authorization: Bearer secret\r\n";
+        let json = "Content-Type: application/json; charset=utf-8\r\n";
+        assert_eq!(
+            check(&format!(
+                "GET /instances HTTP/1.1\r\nHost: localhost:8780\r\n{auth}\r\n"
+            )),
+            Ok(())
+        );
+        assert_eq!(
+            check(&format!(
+                "POST /lint HTTP/1.1\r\nhost: 127.0.0.1:8780\r\n{auth}{json}Content-Length: 2\r\n\r\n{{}}"
+            )),
+            Ok(())
+        );
+        assert_eq!(
+            check("GET /instances HTTP/1.1\r\nHost: localhost:8780\r\n\r\n"),
+            Err(401)
+        );
+        assert_eq!(
+            check(&format!(
+                "GET /instances HTTP/1.1\r\nHost: evil.example:8780\r\n{auth}\r\n"
+            )),
+            Err(403)
+        );
+        assert_eq!(
+            check(&format!(
+                "POST /lint HTTP/1.1\r\nHost: localhost:8780\r\n{auth}Content-Type: text/plain\r\nContent-Length: 2\r\n\r\n{{}}"
+            )),
+            Err(415)
+        );
+
+        let long = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_LINE_LENGTH));
+        assert!(
+            runtime
+                .block_on(read_request(&mut long.as_bytes()))
+                .is_err()
+        );
+        let many = format!("GET / HTTP/1.1\r\n{}\r\n", "X-A: b\r\n".repeat(100));
+        assert!(
+            runtime
+                .block_on(read_request(&mut many.as_bytes()))
+                .is_err()
+        );
+    }
+}
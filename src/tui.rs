@@ -0,0 +1,571 @@
+use crate::renutil::{
+    InstallProgress, PROGRESS_ENV, get_available_versions, get_installed_instances,
+};
+use crate::version::Version;
+use anyhow::Result;
+use indicatif::HumanBytes;
+use ratatui::{
+    DefaultTerminal, Frame,
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout, Rect},
+    style::{Style, Stylize},
+    text::Line,
+    widgets::{Block, Gauge, List, ListItem, ListState, Paragraph},
+};
+use std::{
+    collections::HashSet, env, ffi::OsStr, fs, path::PathBuf, process::Stdio, time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, BufReader},
+    process::Command,
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+};
+
+/// The number of output lines that are kept around for the output pane.
+const MAX_LOG_LINES: usize = 500;
+
+enum Message {
+    Remote(Result<Vec<Version>, String>),
+    DiskUsage(String, u64),
+    Progress(InstallProgress),
+    Output(String),
+    Finished(Option<i32>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Pane {
+    Local,
+    Remote,
+}
+
+struct LocalEntry {
+    name: String,
+    linked: bool,
+    size: Option<u64>,
+}
+
+struct Confirmation {
+    prompt: String,
+    description: String,
+    args: Vec<String>,
+}
+
+struct App {
+    registry: PathBuf,
+    jdk_paths: Vec<PathBuf>,
+    tx: UnboundedSender<Message>,
+    pane: Pane,
+    local: Vec<LocalEntry>,
+    local_state: ListState,
+    /// The names of the installed instances, to mark installed versions in the remote list.
+    installed: HashSet<String>,
+    remote: Vec<Version>,
+    remote_state: ListState,
+    remote_status: String,
+    nightly: bool,
+    job: Option<String>,
+    progress: Vec<InstallProgress>,
+    log: Vec<String>,
+    confirmation: Option<Confirmation>,
+    quit: bool,
+}
+
+/// Runs a full-screen terminal interface to manage the instances in the registry.
+///
+/// Actions run in a separate `renutil` process, so that their output ends up in the output pane
+/// instead of being written across the interface.
+/// Has to be called from within a multi-threaded Tokio runtime, which runs the background tasks.
+pub fn tui(registry: PathBuf, jdk_paths: Vec<PathBuf>) -> Result<()> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let mut app = App::new(registry, jdk_paths, tx);
+
+    let mut terminal = ratatui::init();
+    let result = app.run(&mut terminal, rx);
+    ratatui::restore();
+
+    result
+}
+
+impl App {
+    fn new(registry: PathBuf, jdk_paths: Vec<PathBuf>, tx: UnboundedSender<Message>) -> Self {
+        Self {
+            registry,
+            jdk_paths,
+            tx,
+            pane: Pane::Local,
+            local: vec![],
+            local_state: ListState::default(),
+            installed: HashSet::new(),
+            remote: vec![],
+            remote_state: ListState::default(),
+            remote_status: "Loading...".into(),
+            nightly: false,
+            job: None,
+            progress: vec![],
+            log: vec![],
+            confirmation: None,
+            quit: false,
+        }
+    }
+
+    fn run(
+        &mut self,
+        terminal: &mut DefaultTerminal,
+        mut rx: UnboundedReceiver<Message>,
+    ) -> Result<()> {
+        self.refresh_local();
+        self.refresh_remote();
+
+        while !self.quit {
+            while let Ok(message) = rx.try_recv() {
+                self.handle_message(message);
+            }
+
+            terminal.draw(|frame| self.render(frame))?;
+
+            // Polling blocks, so move it off the runtime to keep background tasks progressing.
+            let key = tokio::task::block_in_place(|| -> Result<Option<KeyCode>> {
+                if event::poll(Duration::from_millis(100))?
+                    && let Event::Key(key) = event::read()?
+                    && key.kind == KeyEventKind::Press
+                {
+                    return Ok(Some(key.code));
+                }
+                Ok(None)
+            })?;
+
+            if let Some(key) = key {
+                self.handle_key(key);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn push_log(&mut self, line: impl Into<String>) {
+        self.log.push(line.into());
+        if self.log.len() > MAX_LOG_LINES {
+            self.log.drain(..self.log.len() - MAX_LOG_LINES);
+        }
+    }
+
+    fn refresh_local(&mut self) {
+        let mut instances = match get_installed_instances(&self.registry) {
+            Ok(instances) => instances,
+            Err(e) => {
+                self.push_log(format!("Unable to list installed instances: {e}"));
+                vec![]
+            }
+        };
+        instances.sort_by(|a, b| b.version.cmp(&a.version).then(a.name().cmp(&b.name())));
+
+        self.local = instances
+            .iter()
+            .map(|instance| LocalEntry {
+                name: instance.name(),
+                linked: instance.link_target(&self.registry).is_some(),
+                size: None,
+            })
+            .collect();
+        self.installed = self.local.iter().map(|entry| entry.name.clone()).collect();
+
+        for instance in instances {
+            let tx = self.tx.clone();
+            let name = instance.name();
+            let path = instance.path(&self.registry);
+            tokio::task::spawn_blocking(move || {
+                let size = jwalk::WalkDir::new(path)
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|entry| entry.metadata().ok())
+                    .filter(fs::Metadata::is_file)
+                    .map(|metadata| metadata.len())
+                    .sum();
+                let _ = tx.send(Message::DiskUsage(name, size));
+            });
+        }
+
+        clamp_selection(&mut self.local_state, self.local.len());
+    }
+
+    fn refresh_remote(&mut self) {
+        self.remote_status = "Loading...".into();
+
+        let tx = self.tx.clone();
+        let registry = self.registry.clone();
+        tokio::spawn(async move {
+            let versions = get_available_versions(&registry, true)
+                .await
+                .map_err(|e| e.to_string());
+            let _ = tx.send(Message::Remote(versions));
+        });
+    }
+
+    fn visible_remote(&self) -> Vec<&Version> {
+        let mut versions = self
+            .remote
+            .iter()
+            .filter(|v| !v.nightly || self.nightly)
+            .collect::<Vec<_>>();
+        versions.sort_by(|a, b| b.cmp(a));
+        versions
+    }
+
+    fn handle_message(&mut self, message: Message) {
+        match message {
+            Message::Remote(Ok(versions)) => {
+                self.remote = versions;
+                self.remote_status.clear();
+                let len = self.visible_remote().len();
+                clamp_selection(&mut self.remote_state, len);
+            }
+            Message::Remote(Err(e)) => {
+                self.remote_status = format!("Unable to fetch remote versions: {e}");
+            }
+            Message::DiskUsage(name, size) => {
+                if let Some(entry) = self.local.iter_mut().find(|e| e.name == name) {
+                    entry.size = Some(size);
+                }
+            }
+            Message::Progress(progress) => {
+                match self
+                    .progress
+                    .iter_mut()
+                    .find(|p| p.component == progress.component)
+                {
+                    Some(existing) => *existing = progress,
+                    None => self.progress.push(progress),
+                }
+            }
+            Message::Output(line) => self.push_log(line),
+            Message::Finished(status) => {
+                if let Some(job) = self.job.take() {
+                    match status {
+                        Some(0) => self.push_log(format!("{job}: Done")),
+                        Some(code) => self.push_log(format!("{job}: Failed with status {code}")),
+                        None => self.push_log(format!("{job}: Terminated")),
+                    }
+                }
+                self.progress.clear();
+                self.refresh_local();
+            }
+        }
+    }
+
+    fn handle_key(&mut self, key: KeyCode) {
+        if let Some(confirmation) = self.confirmation.take() {
+            if matches!(key, KeyCode::Char('y' | 'Y')) {
+                self.run_renutil(confirmation.description, &confirmation.args);
+            }
+            return;
+        }
+
+        match key {
+            KeyCode::Char('q') | KeyCode::Esc => self.quit = true,
+            KeyCode::Tab | KeyCode::Left | KeyCode::Right | KeyCode::Char('h' | 'l') => {
+                self.pane = match self.pane {
+                    Pane::Local => Pane::Remote,
+                    Pane::Remote => Pane::Local,
+                };
+            }
+            KeyCode::Up | KeyCode::Char('k') => self.move_selection(-1),
+            KeyCode::Down | KeyCode::Char('j') => self.move_selection(1),
+            KeyCode::Char('r') => {
+                self.refresh_local();
+                self.refresh_remote();
+            }
+            KeyCode::Char('n') => {
+                self.nightly = !self.nightly;
+                let len = self.visible_remote().len();
+                clamp_selection(&mut self.remote_state, len);
+            }
+            KeyCode::Enter | KeyCode::Char('i') if self.pane == Pane::Remote => self.install(),
+            KeyCode::Enter | KeyCode::Char('o') if self.pane == Pane::Local => {
+                if let Some(name) = self.selected_local() {
+                    self.run_renutil(
+                        format!("Launching {name}"),
+                        &["launch".into(), "--version".into(), name],
+                    );
+                }
+            }
+            KeyCode::Char('c') if self.pane == Pane::Local => {
+                if let Some(name) = self.selected_local() {
+                    self.run_renutil(format!("Cleaning {name}"), &["clean".into(), name]);
+                }
+            }
+            KeyCode::Char('u') if self.pane == Pane::Local => {
+                if let Some(name) = self.selected_local() {
+                    self.confirmation = Some(Confirmation {
+                        prompt: format!("Uninstall {name}? [y/n]"),
+                        description: format!("Uninstalling {name}"),
+                        args: vec!["uninstall".into(), name],
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn move_selection(&mut self, offset: isize) {
+        let (state, len) = match self.pane {
+            Pane::Local => (&mut self.local_state, self.local.len()),
+            Pane::Remote => {
+                let len = self.visible_remote().len();
+                (&mut self.remote_state, len)
+            }
+        };
+        if len == 0 {
+            return;
+        }
+        let selected = state.selected().unwrap_or(0).saturating_add_signed(offset);
+        state.select(Some(selected.min(len - 1)));
+    }
+
+    fn selected_local(&self) -> Option<String> {
+        self.local_state
+            .selected()
+            .and_then(|i| self.local.get(i))
+            .map(|entry| entry.name.clone())
+    }
+
+    fn install(&mut self) {
+        let Some(version) = self
+            .remote_state
+            .selected()
+            .and_then(|i| self.visible_remote().get(i).map(|v| (*v).clone()))
+        else {
+            return;
+        };
+
+        if self.installed.contains(&version.to_string()) {
+            self.push_log(format!("{version} is already installed."));
+            return;
+        }
+
+        self.run_renutil(
+            format!("Installing {version}"),
+            &["install".into(), version.to_string()],
+        );
+    }
+
+    /// Runs `renutil` with the given arguments in the background and forwards its output.
+    /// Only one action runs at a time, returns whether the action was started.
+    fn run_renutil(&mut self, description: String, args: &[String]) -> bool {
+        if let Some(job) = &self.job {
+            self.push_log(format!("Busy: {job}"));
+            return false;
+        }
+
+        let child = env::current_exe().and_then(|exe| {
+            Command::new(exe)
+                .arg("--registry")
+                .arg(&self.registry)
+                .args(
+                    self.jdk_paths
+                        .iter()
+                        .flat_map(|path| [OsStr::new("--jdk-path"), path.as_os_str()]),
+                )
+                .args(args)
+                .env(PROGRESS_ENV, "1")
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .kill_on_drop(true)
+                .spawn()
+        });
+        let mut child = match child {
+            Ok(child) => child,
+            Err(e) => {
+                self.push_log(format!("{description}: Unable to start: {e}"));
+                return false;
+            }
+        };
+
+        self.push_log(format!("{description}..."));
+        self.job = Some(description);
+
+        let tx = self.tx.clone();
+        tokio::spawn(async move {
+            let stdout = child
+                .stdout
+                .take()
+                .map(|p| tokio::spawn(forward_lines(p, tx.clone())));
+            let stderr = child
+                .stderr
+                .take()
+                .map(|p| tokio::spawn(forward_lines(p, tx.clone())));
+            for handle in [stdout, stderr].into_iter().flatten() {
+                let _ = handle.await;
+            }
+            let status = child.wait().await.ok().and_then(|status| status.code());
+            let _ = tx.send(Message::Finished(status));
+        });
+
+        true
+    }
+
+    fn render(&mut self, frame: &mut Frame) {
+        let progress_height = if self.progress.is_empty() {
+            0
+        } else {
+            u16::try_from(self.progress.len()).unwrap_or(u16::MAX) + 2
+        };
+
+        let [lists, progress, output, footer] = Layout::vertical([
+            Constraint::Min(6),
+            Constraint::Length(progress_height),
+            Constraint::Length(12),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [local, remote] =
+            Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)])
+                .areas(lists);
+
+        self.render_local(frame, local);
+        self.render_remote(frame, remote);
+        if !self.progress.is_empty() {
+            self.render_progress(frame, progress);
+        }
+        self.render_output(frame, output);
+
+        let footer_text = match &self.confirmation {
+            Some(confirmation) => confirmation.prompt.clone(),
+            None => match self.pane {
+                Pane::Local => "q: quit | tab: switch pane | enter/o: launch | c: clean | u: uninstall | r: refresh",
+                Pane::Remote => "q: quit | tab: switch pane | enter/i: install | n: toggle nightly | r: refresh",
+            }
+            .to_string(),
+        };
+        frame.render_widget(Paragraph::new(footer_text).dim(), footer);
+    }
+
+    fn pane_block(&self, pane: Pane, title: String) -> Block<'static> {
+        let block = Block::bordered().title(title);
+        if self.pane == pane {
+            block.border_style(Style::new().cyan())
+        } else {
+            block
+        }
+    }
+
+    fn render_local(&mut self, frame: &mut Frame, area: Rect) {
+        let items = self
+            .local
+            .iter()
+            .map(|entry| {
+                let size = entry
+                    .size
+                    .map_or_else(|| "...".to_string(), |size| HumanBytes(size).to_string());
+                let linked = if entry.linked { " (linked)" } else { "" };
+                ListItem::new(format!("{:<20} {size:>10}{linked}", entry.name))
+            })
+            .collect::<Vec<_>>();
+
+        let total = self.local.iter().filter_map(|e| e.size).sum::<u64>();
+        let block = self.pane_block(Pane::Local, format!(" Installed ({}) ", HumanBytes(total)));
+
+        let list = List::new(items)
+            .block(block)
+            .highlight_style(Style::new().reversed())
+            .highlight_symbol("> ");
+        frame.render_stateful_widget(list, area, &mut self.local_state);
+    }
+
+    fn render_remote(&mut self, frame: &mut Frame, area: Rect) {
+        let items = self
+            .visible_remote()
+            .into_iter()
+            .map(|version| {
+                if self.installed.contains(&version.to_string()) {
+                    ListItem::new(format!("{version} (installed)")).dim()
+                } else {
+                    ListItem::new(version.to_string())
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let title = if self.remote_status.is_empty() {
+            " Available ".to_string()
+        } else {
+            format!(" Available - {} ", self.remote_status)
+        };
+        let block = self.pane_block(Pane::Remote, title);
+
+        let list = List::new(items)
+            .block(block)
+            .highlight_style(Style::new().reversed())
+            .highlight_symbol("> ");
+        frame.render_stateful_widget(list, area, &mut self.remote_state);
+    }
+
+    fn render_progress(&self, frame: &mut Frame, area: Rect) {
+        let block = Block::bordered().title(" Progress ");
+        let inner = block.inner(area);
+        frame.render_widget(block, area);
+
+        let rows = Layout::vertical(vec![Constraint::Length(1); self.progress.len()]).split(inner);
+        for (progress, row) in self.progress.iter().zip(rows.iter()) {
+            let InstallProgress {
+                component,
+                state,
+                position,
+                length,
+            } = progress;
+
+            #[allow(clippy::cast_precision_loss)]
+            let (ratio, label) = match length {
+                Some(length) if *length > 0 => (
+                    (*position as f64 / *length as f64).clamp(0.0, 1.0),
+                    format!(
+                        "{component}: {state} {} / {}",
+                        HumanBytes(*position),
+                        HumanBytes(*length)
+                    ),
+                ),
+                _ => (0.0, format!("{component}: {state}")),
+            };
+
+            frame.render_widget(Gauge::default().ratio(ratio).label(label), *row);
+        }
+    }
+
+    fn render_output(&self, frame: &mut Frame, area: Rect) {
+        let title = match &self.job {
+            Some(job) => format!(" Output - {job}... "),
+            None => " Output ".to_string(),
+        };
+        let block = Block::bordered().title(title);
+
+        let height = usize::from(block.inner(area).height);
+        let lines = self
+            .log
+            .iter()
+            .skip(self.log.len().saturating_sub(height))
+            .map(|line| Line::from(line.as_str()))
+            .collect::<Vec<_>>();
+
+        frame.render_widget(Paragraph::new(lines).block(block), area);
+    }
+}
+
+fn clamp_selection(state: &mut ListState, len: usize) {
+    match (state.selected(), len) {
+        (_, 0) => state.select(None),
+        (None, _) => state.select(Some(0)),
+        (Some(selected), _) => state.select(Some(selected.min(len - 1))),
+    }
+}
+
+async fn forward_lines(pipe: impl AsyncRead + Unpin, tx: UnboundedSender<Message>) {
+    let mut lines = BufReader::new(pipe).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let message = match InstallProgress::parse(&line) {
+            Some(progress) => Message::Progress(progress),
+            None => Message::Output(line),
+        };
+        if tx.send(message).is_err() {
+            break;
+        }
+    }
+}
@@ -1,4 +1,6 @@
-use crate::renutil::{Instance, Local, Remote};
+use crate::renutil::{
+    Instance, InstanceManifest, InstanceOptions, Local, Remote, find_instance_registry,
+};
 use anyhow::Result;
 use reqwest::Url;
 use std::{path::Path, str::FromStr};
@@ -10,13 +12,18 @@ pub struct Version {
     pub patch: u32,
     pub hotfix: u32,
     pub nightly: bool,
+    /// A custom label for linked SDKs, i.e. `studio` in `8.3.4+studio`.
+    pub label: Option<String>,
 }
 
 impl FromStr for Version {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let reg = regex::Regex::new(r"^(\d+)\.(\d+)(?:\.(\d+))?(?:\.(\d+))?(\+nightly)?$").unwrap();
+        let reg = regex::Regex::new(
+            r"^(\d+)\.(\d+)(?:\.(\d+))?(?:\.(\d+))?(?:\+([0-9A-Za-z][0-9A-Za-z_.-]*))?$",
+        )
+        .unwrap();
         match reg.captures(s) {
             Some(caps) => {
                 let major = caps.get(1).unwrap().as_str().parse::<u32>()?;
@@ -29,13 +36,18 @@ impl FromStr for Version {
                     Some(m) => m.as_str().parse::<u32>()?,
                     None => 0,
                 };
-                let nightly = caps.get(5).is_some();
+                let (nightly, label) = match caps.get(5).map(|m| m.as_str()) {
+                    Some("nightly") => (true, None),
+                    Some(label) => (false, Some(label.to_string())),
+                    None => (false, None),
+                };
                 Ok(Self {
                     major,
                     minor,
                     patch,
                     hotfix,
                     nightly,
+                    label,
                 })
             }
             None => Err(anyhow::anyhow!("Invalid version string.")),
@@ -44,29 +56,64 @@ impl FromStr for Version {
 }
 impl Version {
     #[must_use]
-    pub fn is_installed(&self, registry: &Path) -> bool {
-        registry.join(self.to_string()).exists()
+    pub fn is_installed(&self, registry: &Path, options: &InstanceOptions) -> bool {
+        let name = Instance::<Local>::new(self.clone(), options.clone()).name();
+        find_instance_registry(registry, &name).is_some()
     }
 
-    pub fn to_local(&self, registry: &Path) -> Result<Instance<Local>, std::io::Error> {
-        if self.is_installed(registry) {
-            Ok(Instance::new(self.clone()))
-        } else {
-            Err(std::io::Error::new(
+    pub fn to_local(
+        &self,
+        registry: &Path,
+        options: &InstanceOptions,
+    ) -> Result<Instance<Local>, std::io::Error> {
+        let instance = Instance::new(self.clone(), options.clone());
+        let Some(registry) = find_instance_registry(registry, &instance.name()) else {
+            return Err(std::io::Error::new(
                 std::io::ErrorKind::NotFound,
-                format!("Version {self} is not installed."),
-            ))
+                format!("Version {} is not installed.", instance.name()),
+            ));
+        };
+        let path = registry.join(instance.name());
+
+        // Instances installed before manifests were introduced have nothing to compare against.
+        let manifest = InstanceManifest::read(&path).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "Unable to read the manifest of {}: {e}",
+                    path.to_string_lossy()
+                ),
+            )
+        })?;
+        if let Some(manifest) = manifest
+            && manifest.options != *options
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "Version {} was installed with different options ({:?}), refusing to reuse it.",
+                    instance.name(),
+                    manifest.options
+                ),
+            ));
         }
+
+        Ok(instance)
     }
 
-    pub fn to_remote(&self, registry: &Path) -> Result<Instance<Remote>, std::io::Error> {
-        if self.is_installed(registry) {
+    pub fn to_remote(
+        &self,
+        registry: &Path,
+        options: &InstanceOptions,
+    ) -> Result<Instance<Remote>, std::io::Error> {
+        let instance = Instance::new(self.clone(), options.clone());
+        if self.is_installed(registry, options) {
             Err(std::io::Error::new(
                 std::io::ErrorKind::AlreadyExists,
-                format!("Version {self} is installed."),
+                format!("Version {} is installed.", instance.name()),
             ))
         } else {
-            Ok(Instance::new(self.clone()))
+            Ok(instance)
         }
     }
 
@@ -150,7 +197,7 @@ mod tests {
         let v = super::Version::from_str("8.3.0").unwrap();
         assert_eq!(
             v.sdk_url().unwrap(),
-            "https://www.renpy.org/dl/8.3.0/renpy-8.3.0-sdk.zip"
+            "https://www.renpy.org/dl/8.3.0/renpy-8.3.0-sdkarm.tar.bz2"
                 .parse()
                 .unwrap()
         );
@@ -176,7 +223,7 @@ mod tests {
         let v = super::Version::from_str("8.3.0.24041601+nightly").unwrap();
         assert_eq!(
             v.sdk_url().unwrap(),
-            "https://nightly.renpy.org/8.3.0.24041601+nightly/renpy-8.3.0.24041601+nightly-sdk.zip"
+            "https://nightly.renpy.org/8.3.0.24041601+nightly/renpy-8.3.0.24041601+nightly-sdkarm.tar.bz2"
                 .parse()
                 .unwrap()
         );
@@ -229,6 +276,51 @@ mod tests {
                 .is_err()
         );
 
+        let v = super::Version::from_str("8.3.4+studio").unwrap();
+        assert_eq!(v.major, 8);
+        assert_eq!(v.minor, 3);
+        assert_eq!(v.patch, 4);
+        assert!(!v.nightly);
+        assert_eq!(v.label.as_deref(), Some("studio"));
+        assert_eq!(v.to_string(), "8.3.4+studio");
+
+        assert!(super::Version::from_str("8.3.4+").is_err());
         assert!(super::Version::from_str("bad-version-string").is_err());
     }
+
+    #[test]
+    fn local_manifests() {
+        use crate::renutil::{InstanceManifest, InstanceOptions};
+        use std::{env, fs};
+
+        let registry = env::temp_dir().join(format!("renkit-manifest-{}", rand::random::<u32>()));
+        let path = registry.join("8.3.4");
+        fs::create_dir_all(&path).unwrap();
+        let version = super::Version::from_str("8.3.4").unwrap();
+        let options = InstanceOptions::default();
+
+        // Instances without a manifest are reused as they are.
+        assert!(version.to_local(&registry, &options).is_ok());
+
+        let mut manifest = InstanceManifest {
+            version: "8.3.4".into(),
+            options: InstanceOptions {
+                update_pickle: true,
+            },
+            archives: vec![],
+            patches: vec![],
+            android_build_tools: None,
+        };
+        manifest.write(&path).unwrap();
+        assert!(version.to_local(&registry, &options).is_err());
+        manifest.options = options.clone();
+        manifest.write(&path).unwrap();
+        assert!(version.to_local(&registry, &options).is_ok());
+
+        fs::write(path.join(InstanceManifest::FILE_NAME), "{").unwrap();
+        let error = version.to_local(&registry, &options).err().unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+        fs::remove_dir_all(registry).unwrap();
+    }
 }